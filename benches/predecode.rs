@@ -0,0 +1,61 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+use tam_rs::{TamEmulator, asm, tam};
+
+/// Calls a routine that decrements a global 30000 times.
+fn countdown_program() -> Vec<u32> {
+    tam! {
+        LOADL 30000;
+    next:
+        CALL(SB) decrement[CB];
+        LOAD(1) 0[SB];
+        JUMPIF(0) done[CB];
+        JUMP next[CB];
+    done:
+        HALT;
+    decrement:
+        LOAD(1) 0[SB];
+        CALL(LB) pred[PB];
+        STORE(1) 0[SB];
+        RETURN(0) 0;
+    }
+}
+
+// boxed, as the emulator is too large to move around by value cheaply
+fn emulator() -> Box<TamEmulator> {
+    let mut emu = Box::new(TamEmulator::new(false));
+    emu.set_program(&asm::to_bytes(&countdown_program()))
+        .unwrap();
+    emu
+}
+
+fn countdown(c: &mut Criterion) {
+    let mut group = c.benchmark_group("countdown");
+    group.bench_function("execute", |b| {
+        b.iter_batched_ref(
+            emulator,
+            |emu| {
+                while emu
+                    .fetch_decode()
+                    .and_then(|instr| emu.execute(instr))
+                    .unwrap()
+                {}
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("predecoded", |b| {
+        b.iter_batched_ref(
+            emulator,
+            |emu| {
+                let program = emu.predecode();
+                emu.run_predecoded(black_box(&program)).unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, countdown);
+criterion_main!(benches);
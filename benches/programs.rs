@@ -0,0 +1,136 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use tam_rs::{TamEmulator, asm, io::MemoryIo, tam, triangle};
+
+/// Builds a list of 3000 nodes on the heap, then sums and disposes of it node by node.
+fn heap_list() -> Vec<u32> {
+    tam! {
+        PUSH 3;
+    build:
+        LOAD(1) 1[SB];
+        LOADL 3000;
+        CALL(SB) lt[PB];
+        JUMPIF(0) sum[CB];
+        LOAD(1) 1[SB];
+        LOAD(1) 0[SB];
+        LOADL 2;
+        CALL(SB) new[PB];
+        LOAD(1) -1[ST];
+        STORE(1) 0[SB];
+        STOREI(2);
+        LOAD(1) 1[SB];
+        CALL(SB) succ[PB];
+        STORE(1) 1[SB];
+        JUMP build[CB];
+    sum:
+        LOAD(1) 0[SB];
+        JUMPIF(0) done[CB];
+        LOAD(1) 2[SB];
+        LOAD(1) 0[SB];
+        LOADI(1);
+        CALL(SB) add[PB];
+        STORE(1) 2[SB];
+        LOADL 2;
+        LOAD(1) 0[SB];
+        LOAD(1) 0[SB];
+        CALL(SB) succ[PB];
+        LOADI(1);
+        STORE(1) 0[SB];
+        CALL(SB) dispose[PB];
+        JUMP sum[CB];
+    done:
+        LOAD(1) 2[SB];
+        CALL(SB) putint[PB];
+        HALT
+    }
+}
+
+/// A program to run, with its input and the output it must produce.
+struct Program {
+    name: &'static str,
+    code: Vec<u8>,
+    input: String,
+    output: String,
+}
+
+impl Program {
+    fn triangle(name: &'static str, source: &str, input: String, output: String) -> Program {
+        let compiled = triangle::compile(source, name).expect("benchmark program compiles");
+        Program {
+            name,
+            code: compiled.to_bytes(),
+            input,
+            output,
+        }
+    }
+
+    // boxed, as the emulator is too large to move around by value cheaply
+    fn emulator(&self) -> (Box<TamEmulator>, MemoryIo) {
+        let io = MemoryIo::new(self.input.as_str());
+        let mut emu = Box::new(TamEmulator::new(false));
+        emu.set_program(&self.code).unwrap();
+        emu.set_io(io.clone());
+        (emu, io)
+    }
+}
+
+fn programs() -> Vec<Program> {
+    let text = "The quick brown fox jumps over the lazy dog.\n".repeat(40);
+    let sum = (0..3000).fold(0i16, |sum: i16, i| sum.wrapping_add(i));
+
+    vec![
+        Program::triangle(
+            "fib",
+            include_str!("programs/fib.tri"),
+            String::new(),
+            "2584".into(),
+        ),
+        Program::triangle(
+            "sieve",
+            include_str!("programs/sieve.tri"),
+            String::new(),
+            "550".into(),
+        ),
+        Program::triangle(
+            "sort",
+            include_str!("programs/sort.tri"),
+            String::new(),
+            "9 999\n".into(),
+        ),
+        Program::triangle(
+            "upcase",
+            include_str!("programs/upcase.tri"),
+            text.clone(),
+            format!("{}40\n", text.to_uppercase()),
+        ),
+        Program {
+            name: "heap_list",
+            code: asm::to_bytes(&heap_list()),
+            input: String::new(),
+            output: sum.to_string(),
+        },
+    ]
+}
+
+fn run_programs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("programs");
+    for program in programs() {
+        group.bench_function(program.name, |b| {
+            b.iter_batched_ref(
+                || program.emulator(),
+                |(emu, io)| {
+                    while emu
+                        .fetch_decode()
+                        .and_then(|instr| emu.execute(instr))
+                        .unwrap()
+                    {}
+                    assert_eq!(program.output, io.output_string());
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, run_programs);
+criterion_main!(benches);
@@ -0,0 +1,409 @@
+//! Basic blocks, control-flow graphs and call graphs.
+
+use super::procedure_name;
+use crate::{
+    CALL, CALLI, CB, HALT, JUMP, JUMPI, JUMPIF, LOADA, RETURN, TamInstruction,
+    debug_info::DebugInfo, frames::escape,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+/// The way control passes from one basic block to another.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EdgeKind {
+    /// Straight-line execution into the next block
+    Next,
+    /// An unconditional `JUMP`
+    Jump,
+    /// A `JUMPIF(n)` whose condition held
+    Taken(u8),
+    /// A `JUMPIF(n)` whose condition failed
+    NotTaken(u8),
+    /// Resumption after a routine called at the end of the block returns
+    Return,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Edge {
+    /// Start address of the successor block
+    pub target: u16,
+    pub kind: EdgeKind,
+}
+
+/// A maximal run of instructions that is only entered at its first instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub instructions: Vec<TamInstruction>,
+    pub successors: Vec<Edge>,
+}
+
+impl BasicBlock {
+    /// Gets the address one past the last instruction of the block.
+    pub fn end(&self) -> u16 {
+        self.start + self.instructions.len() as u16
+    }
+
+    /// Gets the entry address of the routine called at the end of the block, if any.
+    pub fn callee(&self) -> Option<u16> {
+        self.instructions
+            .last()
+            .filter(|i| i.op == CALL && i.r as usize == CB)
+            .map(|i| i.d as u16)
+    }
+}
+
+/// The blocks reachable from a routine's entry point without following calls.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcedureCfg {
+    pub entry: u16,
+    /// Start addresses of the procedure's blocks, in address order
+    pub blocks: Vec<u16>,
+}
+
+/// Control-flow graphs of every routine in a program, and the calls between them.
+///
+/// Routines are found from the targets of `CALL d[CB]` and `LOADA d[CB]`, with the
+/// main program at address 0. Blocks end at jumps, returns, `HALT`, and calls to
+/// routines in the code store; calls to primitives do not end a block. Targets
+/// outside the code store are ignored, as are the targets of `JUMPI` and `CALLI`,
+/// which can only be known at runtime.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ControlFlowGraph {
+    blocks: BTreeMap<u16, BasicBlock>,
+    procedures: Vec<ProcedureCfg>,
+    calls: BTreeSet<(u16, u16)>,
+}
+
+impl ControlFlowGraph {
+    /// Builds the graphs of a program which occupies the whole of `code`.
+    pub fn build(code: &[u32]) -> ControlFlowGraph {
+        let instrs: Vec<TamInstruction> = code.iter().map(|&w| TamInstruction::from(w)).collect();
+        let code_top = instrs.len();
+        if code_top == 0 {
+            return ControlFlowGraph::default();
+        }
+
+        let in_code =
+            |instr: &TamInstruction| instr.r as usize == CB && (instr.d as u16 as usize) < code_top;
+
+        // find routine entry points and the first instruction of every block
+        let mut entries = BTreeSet::from([0]);
+        let mut leaders = BTreeSet::from([0]);
+        for (addr, instr) in instrs.iter().enumerate() {
+            let addr = addr as u16;
+            match instr.op {
+                CALL | LOADA if in_code(instr) => {
+                    entries.insert(instr.d as u16);
+                    leaders.insert(instr.d as u16);
+                }
+                JUMP | JUMPIF if in_code(instr) => {
+                    leaders.insert(instr.d as u16);
+                }
+                _ => {}
+            }
+            if ends_block(instr) && ((addr + 1) as usize) < code_top {
+                leaders.insert(addr + 1);
+            }
+        }
+
+        // split the code at the leaders and link the blocks
+        let mut blocks = BTreeMap::new();
+        let bounds: Vec<u16> = leaders.iter().copied().collect();
+        for (i, &start) in bounds.iter().enumerate() {
+            let end = bounds.get(i + 1).map_or(code_top, |&e| e as usize);
+            let instructions = instrs[start as usize..end].to_vec();
+            let last = instructions[instructions.len() - 1];
+            let next = (end < code_top).then_some(end as u16);
+
+            let mut successors = Vec::new();
+            let mut push = |target: Option<u16>, kind| {
+                if let Some(target) = target {
+                    successors.push(Edge { target, kind });
+                }
+            };
+            let target = in_code(&last).then_some(last.d as u16);
+            match last.op {
+                JUMP => push(target, EdgeKind::Jump),
+                JUMPIF => {
+                    push(target, EdgeKind::Taken(last.n));
+                    push(next, EdgeKind::NotTaken(last.n));
+                }
+                CALL if last.r as usize == CB => push(next, EdgeKind::Return),
+                CALLI => push(next, EdgeKind::Return),
+                _ if falls_through(&last) => push(next, EdgeKind::Next),
+                _ => {}
+            }
+
+            blocks.insert(
+                start,
+                BasicBlock {
+                    start,
+                    instructions,
+                    successors,
+                },
+            );
+        }
+
+        // collect each routine's blocks and the calls made from them
+        let mut procedures = Vec::new();
+        let mut calls = BTreeSet::new();
+        for &entry in &entries {
+            let mut seen = BTreeSet::new();
+            let mut stack = vec![entry];
+            while let Some(start) = stack.pop() {
+                if seen.insert(start) {
+                    let block: &BasicBlock = &blocks[&start];
+                    if let Some(callee) = block.callee().filter(|&c| (c as usize) < code_top) {
+                        calls.insert((entry, callee));
+                    }
+                    stack.extend(block.successors.iter().map(|e| e.target));
+                }
+            }
+            procedures.push(ProcedureCfg {
+                entry,
+                blocks: seen.into_iter().collect(),
+            });
+        }
+
+        ControlFlowGraph {
+            blocks,
+            procedures,
+            calls,
+        }
+    }
+
+    /// Gets the block starting at `start`.
+    pub fn block(&self, start: u16) -> Option<&BasicBlock> {
+        self.blocks.get(&start)
+    }
+
+    /// Gets every block, in address order.
+    pub fn blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values()
+    }
+
+    /// Gets every routine, in address order, beginning with the main program.
+    pub fn procedures(&self) -> &[ProcedureCfg] {
+        &self.procedures
+    }
+
+    /// Gets the `(caller, callee)` entry addresses of every call in the program.
+    pub fn calls(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.calls.iter().copied()
+    }
+
+    /// Writes the control-flow graphs in Graphviz DOT format, with one cluster per routine.
+    ///
+    /// Routines are named from `debug_info` where it describes them.
+    pub fn to_dot(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut dot =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for proc in &self.procedures {
+            let name = escape(&procedure_name(proc.entry, debug_info));
+            let _ = writeln!(dot, "    subgraph cluster_{} {{", proc.entry);
+            let _ = writeln!(dot, "        label=\"{name}\";");
+            for start in &proc.blocks {
+                let block = &self.blocks[start];
+                let mut label = String::new();
+                for (i, instr) in block.instructions.iter().enumerate() {
+                    let _ = write!(label, "{:#06x}: {instr}\\l", block.start as usize + i);
+                }
+                let _ = writeln!(dot, "        b{start} [label=\"{label}\"];");
+            }
+            for start in &proc.blocks {
+                for edge in &self.blocks[start].successors {
+                    let attrs = match edge.kind {
+                        EdgeKind::Next | EdgeKind::Jump => String::new(),
+                        EdgeKind::Taken(n) => format!(" [label=\"= {n}\"]"),
+                        EdgeKind::NotTaken(n) => format!(" [label=\"!= {n}\"]"),
+                        EdgeKind::Return => " [style=dashed]".to_string(),
+                    };
+                    let _ = writeln!(dot, "        b{start} -> b{}{attrs};", edge.target);
+                }
+            }
+            dot.push_str("    }\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Writes the call graph in Graphviz DOT format.
+    ///
+    /// Routines are named from `debug_info` where it describes them.
+    pub fn call_graph_dot(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut dot = String::from("digraph calls {\n    node [shape=box];\n");
+        for proc in &self.procedures {
+            let name = escape(&procedure_name(proc.entry, debug_info));
+            let _ = writeln!(dot, "    p{} [label=\"{name}\"];", proc.entry);
+        }
+        for (caller, callee) in &self.calls {
+            let _ = writeln!(dot, "    p{caller} -> p{callee};");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Whether control can leave `instr` other than by moving to the next instruction.
+fn ends_block(instr: &TamInstruction) -> bool {
+    match instr.op {
+        JUMP | JUMPIF | JUMPI | RETURN | HALT | CALLI => true,
+        CALL => instr.r as usize == CB,
+        _ => false,
+    }
+}
+
+/// Checks whether control can pass from `instr` to the instruction after it, either
+/// directly, when a `JUMPIF` condition fails, or when a called routine returns.
+pub(crate) fn falls_through(instr: &TamInstruction) -> bool {
+    !matches!(instr.op, HALT | JUMP | JUMPI | RETURN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{debug_info::Procedure, tam};
+    use rstest::*;
+
+    /// A `while` loop as the compiler lowers it, followed by a call to a routine.
+    fn program() -> Vec<u32> {
+        tam! {
+            JUMP condition[CB];
+        body:
+            LOAD(1) 0[SB];
+            STORE(1) 0[SB];
+        condition:
+            LOAD(1) 0[SB];
+            CALL(SB) not[PB];
+            // loop while false
+            JUMPIF(0) body[CB];
+            CALL(SB) routine[CB];
+            HALT;
+        routine:
+            LOADL 1;
+            RETURN(1) 0;
+        }
+    }
+
+    #[rstest]
+    fn test_build_splits_blocks() {
+        let cfg = ControlFlowGraph::build(&program());
+        let bounds: Vec<(u16, u16)> = cfg.blocks().map(|b| (b.start, b.end())).collect();
+        assert_eq!(
+            vec![(0, 1), (1, 3), (3, 6), (6, 7), (7, 8), (8, 10)],
+            bounds
+        );
+    }
+
+    #[rstest]
+    fn test_build_links_loop() {
+        let cfg = ControlFlowGraph::build(&program());
+        assert_eq!(
+            vec![Edge {
+                target: 3,
+                kind: EdgeKind::Jump
+            }],
+            cfg.block(0).unwrap().successors
+        );
+        assert_eq!(
+            vec![
+                Edge {
+                    target: 1,
+                    kind: EdgeKind::Taken(0)
+                },
+                Edge {
+                    target: 6,
+                    kind: EdgeKind::NotTaken(0)
+                },
+            ],
+            cfg.block(3).unwrap().successors
+        );
+        assert_eq!(
+            vec![Edge {
+                target: 7,
+                kind: EdgeKind::Return
+            }],
+            cfg.block(6).unwrap().successors
+        );
+        assert!(cfg.block(8).unwrap().successors.is_empty());
+    }
+
+    #[rstest]
+    fn test_build_procedures_and_calls() {
+        let cfg = ControlFlowGraph::build(&program());
+        assert_eq!(
+            vec![
+                ProcedureCfg {
+                    entry: 0,
+                    blocks: vec![0, 1, 3, 6, 7]
+                },
+                ProcedureCfg {
+                    entry: 8,
+                    blocks: vec![8]
+                },
+            ],
+            cfg.procedures()
+        );
+        assert_eq!(vec![(0, 8)], cfg.calls().collect::<Vec<_>>());
+    }
+
+    #[rstest]
+    fn test_build_ignores_out_of_range_targets() {
+        let cfg = ControlFlowGraph::build(&tam! { JUMP 40[CB] });
+        assert_eq!(1, cfg.blocks().count());
+        assert!(cfg.block(0).unwrap().successors.is_empty());
+    }
+
+    #[rstest]
+    fn test_build_empty_program() {
+        let cfg = ControlFlowGraph::build(&[]);
+        assert_eq!(0, cfg.blocks().count());
+        assert!(cfg.procedures().is_empty());
+    }
+
+    #[rstest]
+    fn test_to_dot() {
+        let mut info = DebugInfo::default();
+        info.add_procedure(Procedure {
+            name: "one".into(),
+            start: 8,
+            end: 10,
+        });
+        let dot = ControlFlowGraph::build(&program()).to_dot(Some(&info));
+
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("subgraph cluster_0 {\n        label=\"main\";"));
+        assert!(dot.contains("label=\"one\";"));
+        assert!(dot.contains("b8 [label=\"0x0008: LOADL 1\\l0x0009: RETURN(1) 0\\l\"];"));
+        assert!(dot.contains("b3 -> b1 [label=\"= 0\"];"));
+        assert!(dot.contains("b6 -> b7 [style=dashed];"));
+    }
+
+    #[rstest]
+    fn test_dot_escapes_procedure_names() {
+        let mut info = DebugInfo::default();
+        info.add_procedure(Procedure {
+            name: "say \"hi\" \\".into(),
+            start: 8,
+            end: 10,
+        });
+        let cfg = ControlFlowGraph::build(&program());
+
+        let escaped = r#"label="say \"hi\" \\""#;
+        assert!(cfg.to_dot(Some(&info)).contains(escaped));
+        assert!(cfg.call_graph_dot(Some(&info)).contains(escaped));
+    }
+
+    #[rstest]
+    fn test_call_graph_dot() {
+        let dot = ControlFlowGraph::build(&program()).call_graph_dot(None);
+        assert_eq!(
+            "digraph calls {\n    node [shape=box];\n    p0 [label=\"main\"];\n    \
+             p8 [label=\"proc_0x0008\"];\n    p0 -> p8;\n}\n",
+            dot
+        );
+    }
+}
@@ -0,0 +1,23 @@
+//! Static analyses of TAM bytecode.
+//!
+//! [`cfg`] splits a program into basic blocks and builds per-procedure control-flow
+//! graphs and a call graph, which the other analyses work over. [`stack`] bounds the
+//! stack used by each routine and checks that the code keeps the stack balanced.
+
+pub mod cfg;
+pub mod stack;
+
+use crate::debug_info::DebugInfo;
+
+/// Names the routine entered at `entry`, from `debug_info` where it describes it.
+///
+/// The main program at address 0 is always named `main`, and routines without debug
+/// info are named by their address, as in `proc_0x001a`.
+pub fn procedure_name(entry: u16, debug_info: Option<&DebugInfo>) -> String {
+    if entry == 0 {
+        return "main".to_string();
+    }
+    debug_info
+        .and_then(|info| info.procedure_at(entry))
+        .map_or_else(|| format!("proc_{entry:#06x}"), |p| p.name.clone())
+}
@@ -0,0 +1,545 @@
+//! Static bounds on stack usage, by abstract interpretation over the control-flow graph.
+//!
+//! Depths are measured in words from the base of the active frame: `SB` for the main
+//! program, or `LB` for a routine, whose depth on entry is 3 for its link data.
+
+use super::cfg::{BasicBlock, ControlFlowGraph, ProcedureCfg};
+use crate::{
+    CALL, CALLI, CB, HALT, JUMPI, JUMPIF, LOAD, LOADA, LOADI, LOADL, PB, POP, PUSH, RETURN, STORE,
+    STOREI, TamInstruction,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+};
+
+/// Words taken from and left on the stack by each primitive, indexed by displacement.
+///
+/// `eq` and `ne` also take two operands of the size given by the word on top of the
+/// stack, which is not counted here.
+const PRIMITIVE_EFFECTS: [(i32, i32); 29] = [
+    (0, 0),
+    (1, 1), // id
+    (2, 1), // and
+    (2, 1), // or
+    (1, 1), // not
+    (1, 1), // succ
+    (1, 1), // pred
+    (1, 1), // neg
+    (2, 1), // add
+    (2, 1), // sub
+    (2, 1), // mult
+    (2, 1), // div
+    (2, 1), // mod
+    (2, 1), // lt
+    (2, 1), // le
+    (2, 1), // ge
+    (2, 1), // gt
+    (1, 1), // eq
+    (1, 1), // ne
+    (0, 1), // eol
+    (0, 1), // eof
+    (1, 0), // get
+    (1, 0), // put
+    (0, 0), // geteol
+    (0, 0), // puteol
+    (1, 0), // getint
+    (1, 0), // putint
+    (1, 1), // new
+    (2, 0), // dispose
+];
+
+const EQ: i16 = 17;
+const NE: i16 = 18;
+
+/// Words of link data at the base of a routine's frame
+const LINK_DATA: i32 = 3;
+
+/// A fault in the code's use of the stack, which in compiled code is a compiler bug.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StackProblem {
+    /// Code address of the offending instruction or block
+    pub address: u16,
+    pub kind: StackProblemKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StackProblemKind {
+    /// Two paths reach the block at `address` with different stack depths
+    Unbalanced { expected: i32, found: i32 },
+    /// The instruction takes more words than the frame holds above its base
+    Underflow { depth: i32, taken: i32 },
+    /// The `RETURN` differs in result or argument size from the routine's first one
+    InconsistentReturn,
+    /// An `eq` or `ne` is not immediately preceded by a `LOADL` giving the operand size
+    UnknownOperandSize,
+}
+
+impl Display for StackProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}: ", self.address)?;
+        match self.kind {
+            StackProblemKind::Unbalanced { expected, found } => write!(
+                f,
+                "block reached with stack depth {found}, but {expected} on another path"
+            ),
+            StackProblemKind::Underflow { depth, taken } => {
+                write!(f, "takes {taken} words with only {depth} in the frame")
+            }
+            StackProblemKind::InconsistentReturn => {
+                write!(f, "return does not match the routine's other returns")
+            }
+            StackProblemKind::UnknownOperandSize => write!(f, "operand size is not known"),
+        }
+    }
+}
+
+/// Stack usage of a single routine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProcedureStack {
+    pub entry: u16,
+    /// Net number of words a call leaves on the caller's stack, or `None` if the
+    /// routine never returns
+    pub effect: Option<i32>,
+    /// Worst-case depth reached by the routine and the routines it calls, or `None` if
+    /// it may recurse or transfer control through `CALLI` or `JUMPI`
+    pub max_depth: Option<i32>,
+}
+
+/// The stack usage of every routine in a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackAnalysis {
+    /// Usage of each routine, in the order of [`ControlFlowGraph::procedures`]
+    pub procedures: Vec<ProcedureStack>,
+    /// Problems found, in address order
+    pub problems: Vec<StackProblem>,
+}
+
+impl StackAnalysis {
+    /// Analyses a program which occupies the whole of `code`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tam_rs::analysis::stack::StackAnalysis;
+    ///
+    /// // LOADL 1; LOADL 2; CALL add; POP(0) 1; HALT
+    /// let analysis = StackAnalysis::analyse(&[
+    ///     0x30000001, 0x30000002, 0x62040008, 0xb0000001, 0xf0000000,
+    /// ]);
+    /// assert_eq!(Some(2), analysis.max_depth());
+    /// assert!(analysis.problems.is_empty());
+    /// ```
+    pub fn analyse(code: &[u32]) -> StackAnalysis {
+        let cfg = ControlFlowGraph::build(code);
+        let mut problems = Vec::new();
+
+        let returns: HashMap<u16, Option<(i32, i32)>> = cfg
+            .procedures()
+            .iter()
+            .map(|proc| (proc.entry, find_return(&cfg, proc, &mut problems)))
+            .collect();
+
+        let frames: BTreeMap<u16, FrameUsage> = cfg
+            .procedures()
+            .iter()
+            .map(|proc| {
+                (
+                    proc.entry,
+                    analyse_frame(&cfg, proc, &returns, &mut problems),
+                )
+            })
+            .collect();
+
+        let mut totals = HashMap::new();
+        let procedures = cfg
+            .procedures()
+            .iter()
+            .map(|proc| ProcedureStack {
+                entry: proc.entry,
+                effect: returns[&proc.entry].map(|(n, d)| n - d),
+                max_depth: total_depth(proc.entry, &frames, &mut totals, &mut Vec::new()),
+            })
+            .collect();
+
+        problems.sort_by_key(|p| p.address);
+        problems.dedup();
+        StackAnalysis {
+            procedures,
+            problems,
+        }
+    }
+
+    /// Gets the worst-case stack depth of the whole program, in words above `SB`.
+    pub fn max_depth(&self) -> Option<i32> {
+        self.procedures
+            .iter()
+            .find(|p| p.entry == 0)
+            .and_then(|p| p.max_depth)
+    }
+}
+
+/// Stack usage within one routine's frame, before the routines it calls are included.
+#[derive(Debug, Default)]
+struct FrameUsage {
+    max_depth: i32,
+    /// Depth at each call to a routine in the code store, and the routine called
+    calls: Vec<(i32, u16)>,
+    /// Whether control may pass to code that cannot be analysed
+    indirect: bool,
+}
+
+/// Gets the result and argument sizes of a routine's first `RETURN`, reporting any
+/// other `RETURN` which differs from it.
+fn find_return(
+    cfg: &ControlFlowGraph,
+    proc: &ProcedureCfg,
+    problems: &mut Vec<StackProblem>,
+) -> Option<(i32, i32)> {
+    let mut found = None;
+    for block in proc.blocks.iter().filter_map(|&start| cfg.block(start)) {
+        for (i, instr) in block.instructions.iter().enumerate() {
+            if instr.op != RETURN {
+                continue;
+            }
+            let sizes = (instr.n as i32, instr.d as i32);
+            match found {
+                None => found = Some(sizes),
+                Some(first) if first != sizes => problems.push(StackProblem {
+                    address: block.start + i as u16,
+                    kind: StackProblemKind::InconsistentReturn,
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+    found
+}
+
+/// Follows every path through a routine, recording the deepest point its frame reaches.
+fn analyse_frame(
+    cfg: &ControlFlowGraph,
+    proc: &ProcedureCfg,
+    returns: &HashMap<u16, Option<(i32, i32)>>,
+    problems: &mut Vec<StackProblem>,
+) -> FrameUsage {
+    let base = if proc.entry == 0 { 0 } else { LINK_DATA };
+    let mut usage = FrameUsage {
+        max_depth: base,
+        ..Default::default()
+    };
+    let mut entry_depths = HashMap::from([(proc.entry, base)]);
+    let mut worklist = vec![proc.entry];
+
+    while let Some(start) = worklist.pop() {
+        let block = cfg.block(start).expect("procedure blocks are in the graph");
+        let Some(depth) = run_block(
+            block,
+            entry_depths[&start],
+            base,
+            returns,
+            &mut usage,
+            problems,
+        ) else {
+            continue;
+        };
+
+        for edge in &block.successors {
+            match entry_depths.get(&edge.target) {
+                None => {
+                    entry_depths.insert(edge.target, depth);
+                    worklist.push(edge.target);
+                }
+                Some(&expected) if expected != depth => problems.push(StackProblem {
+                    address: edge.target,
+                    kind: StackProblemKind::Unbalanced {
+                        expected,
+                        found: depth,
+                    },
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+
+    usage
+}
+
+/// Interprets a block from the given entry depth, and gets the depth at its end, or
+/// `None` if control cannot continue to its successors.
+fn run_block(
+    block: &BasicBlock,
+    mut depth: i32,
+    base: i32,
+    returns: &HashMap<u16, Option<(i32, i32)>>,
+    usage: &mut FrameUsage,
+    problems: &mut Vec<StackProblem>,
+) -> Option<i32> {
+    // value of the word on top of the stack, if pushed by a LOADL in this block
+    let mut top = None;
+
+    for (i, instr) in block.instructions.iter().enumerate() {
+        let address = block.start + i as u16;
+        let (taken, left) = match effect(instr, top, returns) {
+            Ok(Some(effect)) => effect,
+            Ok(None) => return None,
+            Err(kind) => {
+                problems.push(StackProblem { address, kind });
+                return None;
+            }
+        };
+
+        if depth - taken < base {
+            problems.push(StackProblem {
+                address,
+                kind: StackProblemKind::Underflow {
+                    depth: depth - base,
+                    taken,
+                },
+            });
+            return None;
+        }
+
+        match instr.op {
+            CALL if instr.r as usize == CB => usage.calls.push((depth, instr.d as u16)),
+            CALLI | JUMPI => {
+                usage.indirect = true;
+                return None;
+            }
+            RETURN | HALT => return None,
+            _ => {}
+        }
+
+        depth += left - taken;
+        usage.max_depth = usage.max_depth.max(depth);
+        top = (instr.op == LOADL).then_some(instr.d);
+    }
+
+    Some(depth)
+}
+
+/// Gets the words an instruction takes from and leaves on the stack, or `None` if it
+/// calls a routine that never returns.
+fn effect(
+    instr: &TamInstruction,
+    top: Option<i16>,
+    returns: &HashMap<u16, Option<(i32, i32)>>,
+) -> Result<Option<(i32, i32)>, StackProblemKind> {
+    let n = instr.n as i32;
+    let d = instr.d as i32;
+    Ok(Some(match instr.op {
+        LOAD => (0, n),
+        LOADA | LOADL => (0, 1),
+        LOADI => (1, n),
+        STORE => (n, 0),
+        STOREI => (n + 1, 0),
+        CALL if instr.r as usize == PB => match instr.d {
+            EQ | NE => {
+                let size = top.ok_or(StackProblemKind::UnknownOperandSize)? as i32;
+                (1 + 2 * size, 1)
+            }
+            d => PRIMITIVE_EFFECTS.get(d as usize).copied().unwrap_or((0, 0)),
+        },
+        CALL => match returns.get(&(instr.d as u16)) {
+            Some(Some((n, d))) => (*d, *n),
+            Some(None) => return Ok(None),
+            None => (0, 0),
+        },
+        CALLI => (2, 0),
+        RETURN => (n, 0),
+        // a negative PUSH pops
+        PUSH => (-d.min(0), d.max(0)),
+        POP => (n + d, n),
+        JUMPI | JUMPIF => (1, 0),
+        _ => (0, 0),
+    }))
+}
+
+/// Gets the worst-case depth of a routine including its callees, relative to its own
+/// frame base, or `None` if it may recurse or cannot be analysed.
+fn total_depth(
+    entry: u16,
+    frames: &BTreeMap<u16, FrameUsage>,
+    totals: &mut HashMap<u16, Option<i32>>,
+    active: &mut Vec<u16>,
+) -> Option<i32> {
+    if let Some(&total) = totals.get(&entry) {
+        return total;
+    }
+    if active.contains(&entry) {
+        return None;
+    }
+
+    let frame = &frames[&entry];
+    active.push(entry);
+    let mut total = (!frame.indirect).then_some(frame.max_depth);
+    for &(depth, callee) in &frame.calls {
+        let callee_depth = total_depth(callee, frames, totals, active);
+        total = total.zip(callee_depth).map(|(t, c)| t.max(depth + c));
+    }
+    active.pop();
+
+    totals.insert(entry, total);
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tam, triangle};
+    use rstest::*;
+
+    #[rstest]
+    fn test_analyse_straight_line() {
+        let code = tam! {
+            PUSH 2;
+            LOADL 4;
+            LOADL 5;
+            CALL(SB) add[PB];
+            STORE(1) 0[SB];
+            HALT;
+        };
+        let analysis = StackAnalysis::analyse(&code);
+        assert!(analysis.problems.is_empty());
+        assert_eq!(Some(4), analysis.max_depth());
+    }
+
+    #[rstest]
+    fn test_analyse_includes_callees() {
+        let code = tam! {
+            LOADL 1;
+            LOADL 2;
+            CALL(SB) 4[CB];
+            HALT;
+            // routine taking two arguments and returning one word
+            LOAD(2) -2[LB];
+            LOADL 1;
+            CALL(SB) add[PB];
+            RETURN(1) 2;
+        };
+        let analysis = StackAnalysis::analyse(&code);
+        assert!(analysis.problems.is_empty());
+        assert_eq!(
+            vec![
+                ProcedureStack {
+                    entry: 0,
+                    effect: None,
+                    max_depth: Some(8)
+                },
+                ProcedureStack {
+                    entry: 4,
+                    effect: Some(-1),
+                    max_depth: Some(6)
+                },
+            ],
+            analysis.procedures
+        );
+    }
+
+    #[rstest]
+    fn test_analyse_recursion_unbounded() {
+        let code = tam! {
+            CALL(SB) 2[CB];
+            HALT;
+            CALL(LB) 2[CB];
+            RETURN(0) 0;
+        };
+        let analysis = StackAnalysis::analyse(&code);
+        assert!(analysis.problems.is_empty());
+        assert_eq!(None, analysis.max_depth());
+    }
+
+    #[rstest]
+    fn test_analyse_unbalanced_loop() {
+        let code = tam! {
+            // loop pushes a word on every iteration
+            LOADL 1;
+            LOADL 0;
+            JUMPIF(0) 0[CB];
+            HALT;
+        };
+        assert_eq!(
+            vec![StackProblem {
+                address: 0,
+                kind: StackProblemKind::Unbalanced {
+                    expected: 0,
+                    found: 1
+                }
+            }],
+            StackAnalysis::analyse(&code).problems
+        );
+    }
+
+    #[rstest]
+    #[case(
+        tam! { LOADL 1; POP(0) 2; HALT },
+        StackProblemKind::Underflow { depth: 1, taken: 2 }
+    )]
+    #[case(
+        tam! { LOADL 1; PUSH -2; HALT },
+        StackProblemKind::Underflow { depth: 1, taken: 2 }
+    )]
+    #[case(
+        tam! { LOADL 1; CALL(SB) eq[PB]; HALT },
+        StackProblemKind::Underflow { depth: 1, taken: 3 }
+    )]
+    #[case(
+        tam! { LOAD(2) 0[SB]; CALL(SB) eq[PB]; HALT },
+        StackProblemKind::UnknownOperandSize
+    )]
+    fn test_analyse_reports_problem(#[case] code: Vec<u32>, #[case] kind: StackProblemKind) {
+        assert_eq!(
+            vec![StackProblem { address: 1, kind }],
+            StackAnalysis::analyse(&code).problems
+        );
+    }
+
+    #[rstest]
+    fn test_analyse_inconsistent_return() {
+        let code = tam! {
+            CALL(SB) 2[CB];
+            HALT;
+            LOADL 0;
+            JUMPIF(0) 5[CB];
+            RETURN(0) 0;
+            RETURN(0) 1;
+        };
+        assert_eq!(
+            vec![StackProblem {
+                address: 5,
+                kind: StackProblemKind::InconsistentReturn
+            }],
+            StackAnalysis::analyse(&code).problems
+        );
+    }
+
+    #[rstest]
+    #[case("let var n: Integer in begin getint(var n); putint(n * n) end")]
+    #[case("let var n: Integer in while n < 10 do n := n + 1")]
+    #[case("let var b: Boolean in if 1 = 2 then b := true else b := \\b")]
+    #[case(
+        "let type R ~ record a: Integer, b: Char end; var r: R; var s: R \
+         in if r = s then putint(1) else putint(0)"
+    )]
+    #[case(
+        "let func f(x: Integer): Integer ~ x + 1; \
+         proc p(var x: Integer) ~ x := f(x) \
+         in let var y: Integer in p(var y)"
+    )]
+    fn test_analyse_compiled_programs_balanced(#[case] source: &str) {
+        let compiled = triangle::compile(source, "test.tri").unwrap();
+        let analysis = StackAnalysis::analyse(&compiled.code);
+        assert_eq!(Vec::<StackProblem>::new(), analysis.problems);
+        assert!(analysis.max_depth().is_some());
+    }
+
+    #[rstest]
+    fn test_analyse_skips_unreachable_code() {
+        let code = tam! {
+            JUMP 2[CB];
+            RETURN(0) 0;
+            HALT;
+        };
+        assert_eq!(Some(0), StackAnalysis::analyse(&code).max_depth());
+    }
+}
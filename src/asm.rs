@@ -0,0 +1,274 @@
+//! Writing TAM code inline in Rust.
+//!
+//! The [`tam!`](crate::tam) macro assembles instructions written in the usual TAM
+//! notation into a `Vec<u32>` of instruction words, which [`to_bytes`] turns into the
+//! program format read by `TamEmulator::set_program`. Each instruction is one of:
+//!
+//! ```text
+//! OP                  HALT, CALLI, JUMPI
+//! OP(n)               LOADI(1), STOREI(2)
+//! OP d                LOADL 5, LOADL -1, LOADL 'a', PUSH 3
+//! OP(n) d             POP(1) 2, RETURN(1) 2
+//! OP(n) d[r]          LOAD(1) -1[LB], CALL(SB) 26[PB]
+//! OP(n) label[r]      JUMPIF(0) done[CB], CALL(SB) f[CB]
+//! OP(n) name[PB]      CALL(SB) putint[PB]
+//! ```
+//!
+//! where `n` is a number or a register name, which `CALL` uses for its static link.
+//! Instructions are separated by `;`, and `label:` before an instruction names its
+//! address. Labels and primitive names are resolved at compile time, so an unknown
+//! one is a compile error, as is a displacement outside the range of an `i16`.
+//!
+//! Each instruction takes a step of macro recursion, so a program of more than about
+//! a hundred instructions needs a higher `#![recursion_limit]`.
+
+use crate::PRIMITIVE_NAMES;
+
+/// Assembles TAM instructions into a `Vec<u32>` of instruction words.
+///
+/// See the [module documentation](crate::asm) for the syntax.
+///
+/// # Example
+///
+/// ```
+/// use tam_rs::{TamEmulator, asm, io::MemoryIo, tam};
+///
+/// // prints 3 2 1
+/// let code = tam! {
+///     LOADL 3;
+/// next:
+///     LOAD(1) 0[SB];
+///     CALL(SB) putint[PB];
+///     LOADL ' ';
+///     CALL(SB) put[PB];
+///     LOAD(1) 0[SB];
+///     CALL(SB) pred[PB];
+///     STORE(1) 0[SB];
+///     LOAD(1) 0[SB];
+///     JUMPIF(0) done[CB];
+///     JUMP next[CB];
+/// done:
+///     HALT
+/// };
+///
+/// let io = MemoryIo::new("");
+/// let mut emu = TamEmulator::new(false);
+/// emu.set_io(io.clone());
+/// emu.set_program(&asm::to_bytes(&code)).unwrap();
+/// while emu.fetch_decode().and_then(|instr| emu.execute(instr)).unwrap() {}
+/// assert_eq!("3 2 1 ", io.output_string());
+/// ```
+///
+/// A primitive or label that does not exist is a compile error:
+///
+/// ```compile_fail
+/// let code = tam_rs::tam! { CALL(SB) sqrt[PB]; HALT };
+/// ```
+///
+/// ```compile_fail
+/// let code = tam_rs::tam! { JUMP nowhere[CB] };
+/// ```
+///
+/// So is a displacement that does not fit in 16 bits:
+///
+/// ```compile_fail
+/// let code = tam_rs::tam! { LOADL 40000; HALT };
+/// ```
+#[macro_export]
+macro_rules! tam {
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]) => {{
+        $($labels)*
+        const __TAM_CODE: &[u32] = &[$($code),*];
+        __TAM_CODE.to_vec()
+    }};
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]
+        $label:ident : $($rest:tt)*
+    ) => {
+        $crate::tam!(@munch [$addr]
+            [$($labels)* #[allow(non_upper_case_globals)] const $label: u16 = $addr;]
+            [$($code,)*] $($rest)*)
+    };
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]
+        $op:ident $(($n:tt))? $d:ident [PB] $(; $($rest:tt)*)?
+    ) => {
+        $crate::tam!(@munch [$addr + 1] [$($labels)*]
+            [$($code,)* $crate::tam!(@encode $op [$($n)?] PB
+                $crate::asm::primitive(stringify!($d))),]
+            $($($rest)*)?)
+    };
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]
+        $op:ident $(($n:tt))? $d:ident [$r:ident] $(; $($rest:tt)*)?
+    ) => {
+        $crate::tam!(@munch [$addr + 1] [$($labels)*]
+            [$($code,)* $crate::tam!(@encode $op [$($n)?] $r $crate::asm::displacement($d as i64)),]
+            $($($rest)*)?)
+    };
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]
+        $op:ident $(($n:tt))? $d:literal [$r:ident] $(; $($rest:tt)*)?
+    ) => {
+        $crate::tam!(@munch [$addr + 1] [$($labels)*]
+            [$($code,)* $crate::tam!(@encode $op [$($n)?] $r $crate::asm::displacement($d as i64)),]
+            $($($rest)*)?)
+    };
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]
+        $op:ident $(($n:tt))? $d:literal $(; $($rest:tt)*)?
+    ) => {
+        $crate::tam!(@munch [$addr + 1] [$($labels)*]
+            [$($code,)* $crate::tam!(@encode $op [$($n)?] CB $crate::asm::displacement($d as i64)),]
+            $($($rest)*)?)
+    };
+    (@munch [$addr:expr] [$($labels:tt)*] [$($code:expr,)*]
+        $op:ident $(($n:tt))? $(; $($rest:tt)*)?
+    ) => {
+        $crate::tam!(@munch [$addr + 1] [$($labels)*]
+            [$($code,)* $crate::tam!(@encode $op [$($n)?] CB 0),]
+            $($($rest)*)?)
+    };
+
+    (@encode $op:ident [$($n:tt)?] $r:ident $d:expr) => {
+        $crate::asm::encode($crate::$op, $crate::$r as u8, $crate::tam!(@n $($n)?), $d)
+    };
+    (@n) => { 0 };
+    (@n $n:literal) => { $n };
+    (@n $n:ident) => { $crate::$n as u8 };
+
+    ($($program:tt)*) => {
+        $crate::tam!(@munch [0u16] [] [] $($program)*)
+    };
+}
+
+/// Gets instruction words in the big-endian byte format read by
+/// `TamEmulator::set_program`.
+pub fn to_bytes(code: &[u32]) -> Vec<u8> {
+    code.iter().flat_map(|i| i.to_be_bytes()).collect()
+}
+
+/// Packs the fields of an instruction into a word.
+#[doc(hidden)]
+pub const fn encode(op: u8, r: u8, n: u8, d: i16) -> u32 {
+    ((op as u32 & 0xf) << 28) | ((r as u32 & 0xf) << 24) | ((n as u32) << 16) | (d as u16 as u32)
+}
+
+/// Checks that a displacement fits in an instruction, which in the constant built by
+/// `tam!` makes one that does not a compile error.
+#[doc(hidden)]
+pub const fn displacement(d: i64) -> i16 {
+    if d < i16::MIN as i64 || d > i16::MAX as i64 {
+        panic!("displacement does not fit in 16 bits");
+    }
+    d as i16
+}
+
+/// Gets the displacement from `PB` of the primitive with the given name.
+#[doc(hidden)]
+pub const fn primitive(name: &str) -> i16 {
+    let name = name.as_bytes();
+    let mut id = 1;
+    while id < PRIMITIVE_NAMES.len() {
+        let candidate = PRIMITIVE_NAMES[id].as_bytes();
+        if candidate.len() == name.len() {
+            let mut i = 0;
+            while i < name.len() && candidate[i] == name[i] {
+                i += 1;
+            }
+            if i == name.len() {
+                return id as i16;
+            }
+        }
+        id += 1;
+    }
+    panic!("unknown primitive")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CALL, CB, HALT, JUMP, JUMPIF, L1, L6, LB, LOAD, LOADL, PB, POP, RETURN, SB};
+    use rstest::*;
+
+    use super::encode;
+
+    #[rstest]
+    fn test_tam_operand_forms() {
+        let code = tam! {
+            LOAD(1) -1[LB];
+            LOADL -2;
+            LOADL 'a';
+            POP(1) 2;
+            CALL(SB) add[PB];
+            CALL(SB) mod[PB];
+            CALL(SB) 26[PB];
+            RETURN(1) 3;
+            HALT;
+        };
+        assert_eq!(
+            vec![
+                encode(LOAD, LB as u8, 1, -1),
+                encode(LOADL, 0, 0, -2),
+                encode(LOADL, 0, 0, 97),
+                encode(POP, 0, 1, 2),
+                encode(CALL, PB as u8, SB as u8, 8),
+                encode(CALL, PB as u8, SB as u8, 12),
+                encode(CALL, PB as u8, SB as u8, 26),
+                encode(RETURN, 0, 1, 3),
+                encode(HALT, 0, 0, 0),
+            ],
+            code
+        );
+    }
+
+    #[rstest]
+    fn test_tam_display_registers() {
+        let code = tam! {
+            LOAD(1) -1[L1];
+            CALL(L6) 0[CB];
+        };
+        assert_eq!(
+            vec![
+                encode(LOAD, L1 as u8, 1, -1),
+                encode(CALL, CB as u8, L6 as u8, 0),
+            ],
+            code
+        );
+    }
+
+    #[rstest]
+    fn test_tam_labels() {
+        let code = tam! {
+            JUMP end[CB];
+        start:
+        again:
+            JUMPIF(0) again[CB];
+            CALL(LB) start[CB];
+        end:
+            HALT
+        };
+        assert_eq!(
+            vec![
+                encode(JUMP, CB as u8, 0, 3),
+                encode(JUMPIF, CB as u8, 0, 1),
+                encode(CALL, CB as u8, LB as u8, 1),
+                encode(HALT, 0, 0, 0),
+            ],
+            code
+        );
+    }
+
+    #[rstest]
+    fn test_tam_empty() {
+        assert_eq!(Vec::<u32>::new(), tam! {});
+    }
+
+    #[rstest]
+    #[case("id", 1)]
+    #[case("putint", 26)]
+    #[case("dispose", 28)]
+    fn test_primitive(#[case] name: &str, #[case] id: i16) {
+        assert_eq!(id, super::primitive(name));
+    }
+
+    #[rstest]
+    #[should_panic]
+    fn test_primitive_unknown() {
+        super::primitive("sqrt");
+    }
+}
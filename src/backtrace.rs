@@ -0,0 +1,187 @@
+//! Unwinding of the call stack built by `CALL` instructions.
+
+use crate::{CP, LB, ST, TamEmulator};
+
+/// An activation record on the stack.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Address of the instruction being executed in this frame, which for every
+    /// frame but the innermost is the `CALL` to the next frame in
+    pub code_address: u16,
+    /// Value of `LB` while this frame is active
+    pub lb: u16,
+    /// Static link stored in the frame, or `None` for the global frame
+    pub static_link: Option<u16>,
+}
+
+impl TamEmulator {
+    /// Gets the active frames, innermost first, by following dynamic links from `LB`.
+    ///
+    /// Each frame's link data is checked as `RETURN` would check it before it is
+    /// followed, so a corrupted stack yields a truncated backtrace rather than a
+    /// garbage one, whatever the count of active calls says.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut lb = self.registers[LB];
+        let mut code_address = self.registers[CP].saturating_sub(1);
+
+        for _ in 0..self.call_depth {
+            let base = lb as usize;
+            if base + 3 > self.registers[ST] as usize {
+                return frames;
+            }
+
+            frames.push(Frame {
+                code_address,
+                lb,
+                static_link: Some(self.data_store[base] as u16),
+            });
+
+            let dynamic_link = self.data_store[base + 1];
+            let return_addr = self.data_store[base + 2];
+            if self.check_frame(lb, dynamic_link, return_addr).is_err() {
+                return frames;
+            }
+            lb = dynamic_link as u16;
+            code_address = (return_addr as u16).wrapping_sub(1);
+        }
+
+        frames.push(Frame {
+            code_address,
+            lb,
+            static_link: None,
+        });
+        frames
+    }
+}
+
+/// Splits a backtrace into its innermost and outermost `ends` frames and the number
+/// of frames between them, so that deep recursion doesn't bury the interesting ends.
+///
+/// A backtrace of no more than `2 * ends` frames is returned whole as the innermost
+/// part.
+pub fn elide(frames: &[Frame], ends: usize) -> (&[Frame], usize, &[Frame]) {
+    if frames.len() <= 2 * ends {
+        return (frames, 0, &[]);
+    }
+
+    let elided = frames.len() - 2 * ends;
+    (&frames[..ends], elided, &frames[ends + elided..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CT, SB};
+    use rstest::*;
+
+    #[fixture]
+    fn emulator() -> TamEmulator {
+        TamEmulator::new(false)
+    }
+
+    #[rstest]
+    fn test_backtrace_global_frame_only(mut emulator: TamEmulator) {
+        emulator.registers[CP] = 5;
+
+        assert_eq!(
+            vec![Frame {
+                code_address: 4,
+                lb: 0,
+                static_link: None
+            }],
+            emulator.backtrace()
+        );
+    }
+
+    #[rstest]
+    fn test_backtrace_follows_dynamic_links(mut emulator: TamEmulator) {
+        // a frame called from 0x10, then one called from 0x21
+        let stack = [0, 0, 0x11, 9, 0, 0, 0x22, 3];
+        emulator.data_store[..stack.len()].copy_from_slice(&stack);
+        emulator.registers[CT] = 0x40;
+        emulator.registers[ST] = stack.len() as u16;
+        emulator.registers[LB] = 4;
+        emulator.registers[CP] = 0x31;
+        emulator.call_depth = 2;
+
+        assert_eq!(
+            vec![
+                Frame {
+                    code_address: 0x30,
+                    lb: 4,
+                    static_link: Some(0)
+                },
+                Frame {
+                    code_address: 0x21,
+                    lb: 0,
+                    static_link: Some(0)
+                },
+                Frame {
+                    code_address: 0x10,
+                    lb: 0,
+                    static_link: None
+                },
+            ],
+            emulator.backtrace()
+        );
+    }
+
+    #[rstest]
+    fn test_backtrace_stops_at_out_of_range_link(mut emulator: TamEmulator) {
+        emulator.data_store[..4].copy_from_slice(&[0, 0, 300, 0x10]);
+        emulator.registers[ST] = 4;
+        emulator.registers[LB] = 1;
+        emulator.call_depth = 2;
+
+        let frames = emulator.backtrace();
+        assert_eq!(1, frames.len());
+        assert_eq!(1, frames[0].lb);
+    }
+
+    #[rstest]
+    // the outer frame's return address is past the end of the code
+    #[case::return_address(&[0, 0, 0x50, 9, 0, 0, 0x22, 3])]
+    // the outer frame's dynamic link points above it
+    #[case::dynamic_link(&[0, 6, 0x11, 9, 0, 0, 0x22, 3])]
+    fn test_backtrace_stops_at_corrupt_frame(mut emulator: TamEmulator, #[case] stack: &[i16]) {
+        emulator.data_store[..stack.len()].copy_from_slice(stack);
+        emulator.registers[CT] = 0x40;
+        emulator.registers[SB] = 8;
+        emulator.registers[ST] = stack.len() as u16;
+        emulator.registers[LB] = 4;
+        emulator.call_depth = 2;
+
+        let lbs: Vec<_> = emulator.backtrace().iter().map(|frame| frame.lb).collect();
+        assert_eq!(vec![4, 0], lbs);
+    }
+
+    #[rstest]
+    #[case::short(3, 2, 3, 0, 0)]
+    #[case::exactly_both_ends(4, 2, 4, 0, 0)]
+    #[case::long(10, 2, 2, 6, 2)]
+    #[case::no_ends(3, 0, 0, 3, 0)]
+    fn test_elide(
+        #[case] len: u16,
+        #[case] ends: usize,
+        #[case] inner: usize,
+        #[case] elided: usize,
+        #[case] outer: usize,
+    ) {
+        let frames: Vec<_> = (0..len)
+            .map(|lb| Frame {
+                code_address: 0,
+                lb,
+                static_link: None,
+            })
+            .collect();
+
+        let (inner_frames, count, outer_frames) = elide(&frames, ends);
+        assert_eq!(inner, inner_frames.len());
+        assert_eq!(elided, count);
+        assert_eq!(outer, outer_frames.len());
+        if let Some(last) = outer_frames.last() {
+            assert_eq!(len - 1, last.lb);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Export of routine activations in the Chrome trace event format, which can be opened
+//! in trace viewers such as Perfetto or `chrome://tracing`.
+
+use crate::{
+    TamEmulator, TamInstruction,
+    analysis::procedure_name,
+    debug_info::DebugInfo,
+    observer::{CallEvent, CallTracker, Observer},
+    profile::json_string,
+};
+use std::fmt::Write;
+
+/// An [`Observer`] that records when each routine activation begins and ends.
+///
+/// Time is measured in instructions executed, so an activation's duration is the
+/// number of instructions executed while it was active.
+#[derive(Clone, Debug, Default)]
+pub struct ChromeTrace {
+    calls: CallTracker,
+    instructions: u64,
+    /// Routine entry address, whether the activation begins, and the time
+    events: Vec<(u16, bool, u64)>,
+}
+
+impl ChromeTrace {
+    pub fn new() -> ChromeTrace {
+        ChromeTrace::default()
+    }
+
+    /// Writes the trace as a JSON object with a `traceEvents` array.
+    ///
+    /// The main program is active for the whole trace. Activations still active when
+    /// the trace is written, such as after a fault, end at the last instruction.
+    pub fn to_json(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut events = vec![(0, true, 0)];
+        events.extend(self.events.iter().copied());
+        events.extend(
+            self.calls
+                .stack()
+                .iter()
+                .rev()
+                .map(|&entry| (entry, false, self.instructions)),
+        );
+
+        let mut out = String::from("{\"traceEvents\": [\n");
+        for (i, (entry, begin, ts)) in events.iter().enumerate() {
+            let _ = write!(
+                out,
+                "  {{\"name\": {}, \"cat\": \"call\", \"ph\": \"{}\", \"ts\": {ts}, \"pid\": 1, \
+                 \"tid\": 1}}",
+                json_string(&procedure_name(*entry, debug_info)),
+                if *begin { "B" } else { "E" }
+            );
+            out.push_str(if i + 1 < events.len() { ",\n" } else { "\n" });
+        }
+        out.push_str("]}\n");
+        out
+    }
+}
+
+impl Observer for ChromeTrace {
+    fn after_instruction(&mut self, emu: &TamEmulator, _: u16, _: TamInstruction) {
+        self.instructions += 1;
+        match self.calls.update(emu) {
+            Some(CallEvent::Enter(entry)) => self.events.push((entry, true, self.instructions)),
+            Some(CallEvent::Leave(entry)) => self.events.push((entry, false, self.instructions)),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, tam};
+    use rstest::*;
+
+    fn trace(code: &[u32]) -> ChromeTrace {
+        let bytes = asm::to_bytes(code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+
+        let mut trace = ChromeTrace::new();
+        while emu.step(&mut [&mut trace]).is_ok_and(|running| running) {}
+        trace
+    }
+
+    fn event(name: &str, ph: &str, ts: u64) -> String {
+        format!(
+            "{{\"name\": \"{name}\", \"cat\": \"call\", \"ph\": \"{ph}\", \"ts\": {ts}, \
+             \"pid\": 1, \"tid\": 1}}"
+        )
+    }
+
+    #[rstest]
+    fn test_chrome_trace_nested_calls() {
+        let code = tam! {
+            CALL(SB) 2[CB];
+            HALT;
+            CALL(SB) 4[CB];
+            RETURN(0) 0;
+            LOADL 1;
+            RETURN(0) 0;
+        };
+        let expected = [
+            event("main", "B", 0),
+            event("proc_0x0002", "B", 1),
+            event("proc_0x0004", "B", 2),
+            event("proc_0x0004", "E", 4),
+            event("proc_0x0002", "E", 5),
+            event("main", "E", 6),
+        ];
+        assert_eq!(
+            format!("{{\"traceEvents\": [\n  {}\n]}}\n", expected.join(",\n  ")),
+            trace(&code).to_json(None)
+        );
+    }
+
+    #[rstest]
+    fn test_chrome_trace_closes_activations_after_fault() {
+        // the routine runs off the end of the code store
+        let code = tam! { CALL(SB) 1[CB]; LOADL 1 };
+        let json = trace(&code).to_json(None);
+        assert!(json.contains(&event("proc_0x0001", "E", 2)));
+        assert!(json.ends_with(&format!("{}\n]}}\n", event("main", "E", 2))));
+    }
+}
@@ -0,0 +1,420 @@
+//! Recording of the instructions and branches a program executes.
+//!
+//! Coverage data is saved in a text format with one record per line, so that the
+//! results of several runs can be accumulated in one file:
+//!
+//! ```text
+//! hit 0x0005 12          # instruction at 0x0005 executed 12 times
+//! branch 0x0007 3 9      # JUMPIF at 0x0007 taken 3 times and not taken 9 times
+//! ```
+
+use crate::{
+    CP, JUMPIF, TamEmulator, TamInstruction,
+    debug_info::{DebugInfo, parse_address},
+    errors::{TamError, TamResult},
+    observer::Observer,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Write},
+};
+
+/// An [`Observer`] that records which instructions and branch outcomes are executed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    /// Execution count of each address executed
+    hits: BTreeMap<u16, u64>,
+    /// Number of times each `JUMPIF` was taken and not taken
+    branches: BTreeMap<u16, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Parses coverage data from its text representation.
+    ///
+    /// If a record is malformed, the error holds its 1-based line number.
+    pub fn parse(text: &str) -> TamResult<Coverage> {
+        let mut coverage = Coverage::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            let bad = TamError::InvalidCoverageData(i + 1);
+            match fields[..] {
+                ["hit", addr, count] => {
+                    let addr = parse_address(addr).ok_or(bad)?;
+                    *coverage.hits.entry(addr).or_default() +=
+                        count.parse::<u64>().map_err(|_| bad)?;
+                }
+                ["branch", addr, taken, not_taken] => {
+                    let addr = parse_address(addr).ok_or(bad)?;
+                    let outcomes = coverage.branches.entry(addr).or_default();
+                    outcomes.0 += taken.parse::<u64>().map_err(|_| bad)?;
+                    outcomes.1 += not_taken.parse::<u64>().map_err(|_| bad)?;
+                }
+                _ => return Err(bad),
+            }
+        }
+
+        Ok(coverage)
+    }
+
+    /// Adds the results of another run to these.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (&addr, &count) in &other.hits {
+            *self.hits.entry(addr).or_default() += count;
+        }
+        for (&addr, &(taken, not_taken)) in &other.branches {
+            let outcomes = self.branches.entry(addr).or_default();
+            outcomes.0 += taken;
+            outcomes.1 += not_taken;
+        }
+    }
+
+    /// Gets the number of times the instruction at `addr` was executed.
+    pub fn hits(&self, addr: u16) -> u64 {
+        self.hits.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Gets the number of times the `JUMPIF` at `addr` was taken and not taken.
+    pub fn branch(&self, addr: u16) -> (u64, u64) {
+        self.branches.get(&addr).copied().unwrap_or_default()
+    }
+
+    /// Writes every instruction of `code` with its execution count, and the outcomes
+    /// of each `JUMPIF`, followed by a summary.
+    ///
+    /// Instructions that were never executed are marked `#####`.
+    pub fn listing(&self, code: &[u32], debug_info: Option<&DebugInfo>) -> String {
+        let mut out = String::new();
+        let mut executed = 0;
+        let (mut outcomes, mut outcomes_hit) = (0, 0);
+
+        for (addr, &word) in code.iter().enumerate() {
+            let addr = addr as u16;
+            let instr = TamInstruction::from(word);
+            let count = match self.hits(addr) {
+                0 => "#####".to_string(),
+                n => {
+                    executed += 1;
+                    n.to_string()
+                }
+            };
+
+            let mut notes = Vec::new();
+            if instr.op == JUMPIF {
+                let (taken, not_taken) = self.branch(addr);
+                outcomes += 2;
+                outcomes_hit += (taken > 0) as usize + (not_taken > 0) as usize;
+                notes.push(format!("taken {taken}, not taken {not_taken}"));
+            }
+            if let Some(loc) = debug_info.and_then(|info| info.describe(addr)) {
+                notes.push(loc);
+            }
+
+            let _ = if notes.is_empty() {
+                writeln!(out, "{count:>9}  {addr:#06x}: {instr}")
+            } else {
+                let instr = instr.to_string();
+                writeln!(
+                    out,
+                    "{count:>9}  {addr:#06x}: {instr:<20} ; {}",
+                    notes.join("; ")
+                )
+            };
+        }
+
+        let _ = writeln!(
+            out,
+            "\ninstructions executed: {executed} of {} ({})",
+            code.len(),
+            percentage(executed, code.len())
+        );
+        let _ = writeln!(
+            out,
+            "branch outcomes taken: {outcomes_hit} of {outcomes} ({})",
+            percentage(outcomes_hit, outcomes)
+        );
+        out
+    }
+
+    /// Writes coverage of the source lines described by `debug_info` in lcov format.
+    ///
+    /// A line's count is the highest count of the instructions generated for it. Gets
+    /// `None` if the line table does not describe any instruction of `code`.
+    pub fn to_lcov(&self, code: &[u32], debug_info: &DebugInfo) -> Option<String> {
+        let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+        for (addr, &word) in code.iter().enumerate() {
+            let addr = addr as u16;
+            let Some(loc) = debug_info.location(addr) else {
+                continue;
+            };
+            let file = files.entry(&loc.file).or_default();
+            let count = file.lines.entry(loc.line).or_default();
+            *count = (*count).max(self.hits(addr));
+
+            if TamInstruction::from(word).op == JUMPIF {
+                let outcomes = (self.hits(addr) > 0).then(|| self.branch(addr));
+                file.branches.push(BranchCoverage {
+                    line: loc.line,
+                    addr,
+                    outcomes,
+                });
+            }
+        }
+
+        if files.is_empty() {
+            return None;
+        }
+
+        let mut out = String::new();
+        for (name, file) in &files {
+            let _ = writeln!(out, "TN:\nSF:{name}");
+
+            let procedures: Vec<_> = debug_info
+                .procedures()
+                .iter()
+                .filter_map(|p| {
+                    let loc = debug_info.location(p.start)?;
+                    (loc.file == *name).then_some((p, loc.line))
+                })
+                .collect();
+            for (p, line) in &procedures {
+                let _ = writeln!(out, "FN:{line},{}", p.name);
+            }
+            for (p, _) in &procedures {
+                let _ = writeln!(out, "FNDA:{},{}", self.hits(p.start), p.name);
+            }
+            let procedures_hit = procedures.iter().filter(|(p, _)| self.hits(p.start) > 0);
+            let _ = writeln!(
+                out,
+                "FNF:{}\nFNH:{}",
+                procedures.len(),
+                procedures_hit.count()
+            );
+
+            let mut branches_hit = 0;
+            for &BranchCoverage {
+                line,
+                addr,
+                outcomes,
+            } in &file.branches
+            {
+                for (i, taken) in [outcomes.map(|o| o.0), outcomes.map(|o| o.1)]
+                    .into_iter()
+                    .enumerate()
+                {
+                    match taken {
+                        Some(n) => {
+                            branches_hit += (n > 0) as usize;
+                            let _ = writeln!(out, "BRDA:{line},{addr},{i},{n}");
+                        }
+                        None => {
+                            let _ = writeln!(out, "BRDA:{line},{addr},{i},-");
+                        }
+                    }
+                }
+            }
+            let _ = writeln!(out, "BRF:{}\nBRH:{branches_hit}", file.branches.len() * 2);
+
+            for (line, count) in &file.lines {
+                let _ = writeln!(out, "DA:{line},{count}");
+            }
+            let lines_hit = file.lines.values().filter(|&&count| count > 0).count();
+            let _ = writeln!(out, "LF:{}\nLH:{lines_hit}", file.lines.len());
+            let _ = writeln!(out, "end_of_record");
+        }
+        Some(out)
+    }
+}
+
+/// Coverage of the lines of a single source file.
+#[derive(Default)]
+struct FileCoverage {
+    /// Highest execution count of the instructions for each line
+    lines: BTreeMap<u32, u64>,
+    branches: Vec<BranchCoverage>,
+}
+
+struct BranchCoverage {
+    line: u32,
+    addr: u16,
+    /// Times taken and not taken, or `None` if the `JUMPIF` was never executed
+    outcomes: Option<(u64, u64)>,
+}
+
+fn percentage(part: usize, whole: usize) -> String {
+    if whole == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.1}%", 100.0 * part as f64 / whole as f64)
+    }
+}
+
+impl Observer for Coverage {
+    fn after_instruction(&mut self, emu: &TamEmulator, addr: u16, instr: TamInstruction) {
+        *self.hits.entry(addr).or_default() += 1;
+
+        if instr.op == JUMPIF {
+            let target = emu.register(instr.r as usize).wrapping_add_signed(instr.d);
+            let outcomes = self.branches.entry(addr).or_default();
+            if emu.registers[CP] == target {
+                outcomes.0 += 1;
+            } else {
+                outcomes.1 += 1;
+            }
+        }
+    }
+}
+
+impl Display for Coverage {
+    /// Writes the coverage data in the text format read by [`Coverage::parse`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (addr, count) in &self.hits {
+            writeln!(f, "hit {addr:#06x} {count}")?;
+        }
+        for (addr, (taken, not_taken)) in &self.branches {
+            writeln!(f, "branch {addr:#06x} {taken} {not_taken}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        LOADL, asm,
+        debug_info::{Procedure, SourceLocation},
+        tam,
+    };
+    use rstest::*;
+
+    /// Branches on `value`, skipping one instruction when it is 1.
+    fn program(value: i16) -> Vec<u32> {
+        let mut code = vec![asm::encode(LOADL, 0, 0, value)];
+        code.extend(tam! {
+            JUMPIF(1) 3[CB];
+            LOADL 7;
+            HALT;
+            JUMP 0[CB];
+        });
+        code
+    }
+
+    fn run(code: &[u32]) -> Coverage {
+        let bytes = asm::to_bytes(code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+
+        let mut coverage = Coverage::new();
+        while emu.step(&mut [&mut coverage]).unwrap() {}
+        coverage
+    }
+
+    #[rstest]
+    #[case(1, 0, (1, 0))]
+    #[case(0, 1, (0, 1))]
+    fn test_coverage_records_hits_and_branches(
+        #[case] value: i16,
+        #[case] skipped_hits: u64,
+        #[case] outcomes: (u64, u64),
+    ) {
+        let coverage = run(&program(value));
+        assert_eq!(1, coverage.hits(0));
+        assert_eq!(skipped_hits, coverage.hits(2));
+        assert_eq!(0, coverage.hits(4));
+        assert_eq!(outcomes, coverage.branch(1));
+    }
+
+    #[rstest]
+    fn test_coverage_merge() {
+        let mut coverage = run(&program(1));
+        coverage.merge(&run(&program(0)));
+        assert_eq!(2, coverage.hits(0));
+        assert_eq!(1, coverage.hits(2));
+        assert_eq!((1, 1), coverage.branch(1));
+    }
+
+    #[rstest]
+    fn test_coverage_round_trip() {
+        let coverage = run(&program(0));
+        let text = coverage.to_string();
+        assert_eq!(
+            "hit 0x0000 1\nhit 0x0001 1\nhit 0x0002 1\nhit 0x0003 1\nbranch 0x0001 0 1\n",
+            text
+        );
+        assert_eq!(coverage, Coverage::parse(&text).unwrap());
+    }
+
+    #[rstest]
+    fn test_coverage_parse_accumulates_duplicates() {
+        let coverage = Coverage::parse("hit 3 1\nhit 0x0003 2 # again\n").unwrap();
+        assert_eq!(3, coverage.hits(3));
+    }
+
+    #[rstest]
+    #[case("hit 3")]
+    #[case("hit 3 x")]
+    #[case("\nbranch 3 1")]
+    fn test_coverage_parse_invalid_err(#[case] text: &str) {
+        let line = text.lines().count();
+        assert_eq!(
+            TamError::InvalidCoverageData(line),
+            Coverage::parse(text).unwrap_err()
+        );
+    }
+
+    #[rstest]
+    fn test_coverage_listing() {
+        let listing = run(&program(1)).listing(&program(1), None);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!("        1  0x0000: LOADL 1", lines[0]);
+        assert_eq!(
+            "        1  0x0001: JUMPIF(1) 3[0]       ; taken 1, not taken 0",
+            lines[1]
+        );
+        assert_eq!("    #####  0x0002: LOADL 7", lines[2]);
+        assert!(listing.contains("instructions executed: 3 of 5 (60.0%)"));
+        assert!(listing.contains("branch outcomes taken: 1 of 2 (50.0%)"));
+    }
+
+    #[rstest]
+    fn test_coverage_lcov() {
+        let mut info = DebugInfo::default();
+        let loc = |line| SourceLocation {
+            file: "t.tri".into(),
+            line,
+            column: 1,
+        };
+        info.add_line(0, loc(1));
+        info.add_line(2, loc(2));
+        info.add_line(3, loc(3));
+        info.add_procedure(Procedure {
+            name: "skip".into(),
+            start: 4,
+            end: 5,
+        });
+
+        let lcov = run(&program(1)).to_lcov(&program(1), &info).unwrap();
+        assert_eq!(
+            "TN:\nSF:t.tri\nFN:3,skip\nFNDA:0,skip\nFNF:1\nFNH:0\n\
+             BRDA:1,1,0,1\nBRDA:1,1,1,0\nBRF:2\nBRH:1\n\
+             DA:1,1\nDA:2,0\nDA:3,1\nLF:3\nLH:2\nend_of_record\n",
+            lcov
+        );
+    }
+
+    #[rstest]
+    fn test_coverage_lcov_without_lines_none() {
+        let coverage = run(&program(1));
+        assert_eq!(None, coverage.to_lcov(&program(1), &DebugInfo::default()));
+    }
+}
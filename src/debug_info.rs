@@ -0,0 +1,310 @@
+//! Source-level debugging information for TAM programs.
+//!
+//! Debug info is read from a plain-text file that accompanies the bytecode. Each
+//! non-empty line holds one record, and `#` starts a comment:
+//!
+//! ```text
+//! line <address> <file>:<line>:<column>
+//! proc <name> <start> <end>
+//! var <procedure|-> <name> <register> <offset> <size> <type>
+//! ```
+//!
+//! A `line` record applies to every address from its own up to the next record's.
+//! A `proc` record covers the addresses `[start, end)`. A `var` record belongs to the
+//! named procedure, or to the globals when the procedure is `-`. Addresses may be
+//! written in decimal or as `0x`-prefixed hex.
+
+use crate::{
+    REGISTER_NAMES,
+    errors::{TamError, TamResult},
+};
+use std::fmt::{self, Display};
+
+/// A position in a Triangle source file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// A named procedure occupying a range of code addresses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    /// First code address of the procedure
+    pub start: u16,
+    /// One past the last code address of the procedure
+    pub end: u16,
+}
+
+/// A variable stored at a fixed offset from a frame register.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variable {
+    /// Procedure the variable is local to, or `None` for globals
+    pub scope: Option<String>,
+    pub name: String,
+    /// Register the offset is relative to
+    pub register: u8,
+    pub offset: i16,
+    /// Size in words
+    pub size: u8,
+    /// Name of the variable's Triangle type
+    pub ty: String,
+}
+
+/// Line, procedure and variable tables for a single program.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    lines: Vec<(u16, SourceLocation)>,
+    procedures: Vec<Procedure>,
+    variables: Vec<Variable>,
+}
+
+impl DebugInfo {
+    /// Parses debug info from its text representation.
+    ///
+    /// If a record is malformed, the error holds its 1-based line number.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let info = tam_rs::debug_info::DebugInfo::parse(
+    ///     "proc fact 0x10 0x20\nline 0x12 fact.tri:12:5\n",
+    /// )
+    /// .unwrap();
+    /// assert_eq!(
+    ///     Some("in procedure `fact`, line 12".to_string()),
+    ///     info.describe(0x13)
+    /// );
+    /// ```
+    pub fn parse(text: &str) -> TamResult<DebugInfo> {
+        let mut info = DebugInfo::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            let bad = TamError::InvalidDebugInfo(i + 1);
+            match fields[..] {
+                ["line", addr, loc] => {
+                    let mut parts = loc.rsplitn(3, ':');
+                    let column = parts.next().and_then(|c| c.parse().ok()).ok_or(bad)?;
+                    let line = parts.next().and_then(|l| l.parse().ok()).ok_or(bad)?;
+                    let file = parts.next().ok_or(bad)?.to_string();
+                    let addr = parse_address(addr).ok_or(bad)?;
+                    info.lines
+                        .push((addr, SourceLocation { file, line, column }));
+                }
+                ["proc", name, start, end] => info.procedures.push(Procedure {
+                    name: name.to_string(),
+                    start: parse_address(start).ok_or(bad)?,
+                    end: parse_address(end).ok_or(bad)?,
+                }),
+                ["var", scope, name, register, offset, size, ty] => info.variables.push(Variable {
+                    scope: (scope != "-").then(|| scope.to_string()),
+                    name: name.to_string(),
+                    register: parse_register(register).ok_or(bad)?,
+                    offset: offset.parse().map_err(|_| bad)?,
+                    size: size.parse().map_err(|_| bad)?,
+                    ty: ty.to_string(),
+                }),
+                _ => return Err(bad),
+            }
+        }
+
+        info.lines.sort_by_key(|(addr, _)| *addr);
+        Ok(info)
+    }
+
+    /// Adds a line table entry, replacing any existing entry for the same address.
+    pub fn add_line(&mut self, addr: u16, location: SourceLocation) {
+        let idx = self.lines.partition_point(|(a, _)| *a < addr);
+        match self.lines.get_mut(idx) {
+            Some(entry) if entry.0 == addr => entry.1 = location,
+            _ => self.lines.insert(idx, (addr, location)),
+        }
+    }
+
+    pub fn add_procedure(&mut self, procedure: Procedure) {
+        self.procedures.push(procedure);
+    }
+
+    pub fn add_variable(&mut self, variable: Variable) {
+        self.variables.push(variable);
+    }
+
+    /// Gets the source location of the instruction at `addr`.
+    pub fn location(&self, addr: u16) -> Option<&SourceLocation> {
+        let idx = self.lines.partition_point(|(a, _)| *a <= addr);
+        idx.checked_sub(1).map(|i| &self.lines[i].1)
+    }
+
+    /// Gets the innermost procedure containing `addr`.
+    pub fn procedure(&self, addr: u16) -> Option<&Procedure> {
+        self.procedures
+            .iter()
+            .filter(|p| p.start <= addr && addr < p.end)
+            .min_by_key(|p| p.end - p.start)
+    }
+
+    /// Gets every procedure, in the order they were added.
+    pub fn procedures(&self) -> &[Procedure] {
+        &self.procedures
+    }
+
+    /// Gets the procedure beginning at exactly `addr`.
+    pub fn procedure_at(&self, addr: u16) -> Option<&Procedure> {
+        self.procedures.iter().find(|p| p.start == addr)
+    }
+
+    /// Gets the variables declared in the given procedure, or the globals if `None`.
+    pub fn variables<'a>(&'a self, scope: Option<&'a str>) -> impl Iterator<Item = &'a Variable> {
+        self.variables
+            .iter()
+            .filter(move |v| v.scope.as_deref() == scope)
+    }
+
+    /// Describes `addr` in source terms, such as "in procedure `fact`, line 12".
+    pub fn describe(&self, addr: u16) -> Option<String> {
+        let proc = self
+            .procedure(addr)
+            .map(|p| format!("in procedure `{}`", p.name));
+        let line = self.location(addr).map(|l| format!("line {}", l.line));
+        match (proc, line) {
+            (Some(p), Some(l)) => Some(format!("{p}, {l}")),
+            (p, l) => p.or(l),
+        }
+    }
+}
+
+impl Display for DebugInfo {
+    /// Writes the debug info in the text format read by [`DebugInfo::parse`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for p in &self.procedures {
+            writeln!(f, "proc {} {:#06x} {:#06x}", p.name, p.start, p.end)?;
+        }
+        for (addr, loc) in &self.lines {
+            writeln!(f, "line {addr:#06x} {loc}")?;
+        }
+        for v in &self.variables {
+            writeln!(
+                f,
+                "var {} {} {} {} {} {}",
+                v.scope.as_deref().unwrap_or("-"),
+                v.name,
+                REGISTER_NAMES[v.register as usize],
+                v.offset,
+                v.size,
+                v.ty
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn parse_address(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_register(s: &str) -> Option<u8> {
+    REGISTER_NAMES
+        .iter()
+        .position(|r| r.eq_ignore_ascii_case(s))
+        .map(|r| r as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LB, SB};
+    use rstest::*;
+
+    #[fixture]
+    fn info() -> DebugInfo {
+        DebugInfo::parse(
+            "# factorial
+            proc fact 0x10 0x20
+            line 0x00 fact.tri:1:1
+            line 0x10 fact.tri:3:5
+            line 0x14 fact.tri:4:9
+            var - result SB 0 1 Integer
+            var fact n LB -1 1 Integer",
+        )
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case(0x00, Some(1))]
+    #[case(0x13, Some(3))]
+    #[case(0x30, Some(4))]
+    fn test_location_finds_preceding_entry(
+        info: DebugInfo,
+        #[case] addr: u16,
+        #[case] line: Option<u32>,
+    ) {
+        assert_eq!(line, info.location(addr).map(|l| l.line));
+    }
+
+    #[rstest]
+    #[case(0x05, Some("line 1"))]
+    #[case(0x15, Some("in procedure `fact`, line 4"))]
+    fn test_describe(info: DebugInfo, #[case] addr: u16, #[case] expected: Option<&str>) {
+        assert_eq!(expected.map(String::from), info.describe(addr));
+    }
+
+    #[rstest]
+    fn test_variables_filtered_by_scope(info: DebugInfo) {
+        let globals: Vec<_> = info.variables(None).collect();
+        assert_eq!(1, globals.len());
+        assert_eq!(SB as u8, globals[0].register);
+
+        let locals: Vec<_> = info.variables(Some("fact")).collect();
+        assert_eq!(1, locals.len());
+        assert_eq!("n", locals[0].name);
+        assert_eq!(LB as u8, locals[0].register);
+        assert_eq!(-1, locals[0].offset);
+    }
+
+    #[rstest]
+    fn test_display_round_trips(info: DebugInfo) {
+        assert_eq!(info, DebugInfo::parse(&info.to_string()).unwrap());
+    }
+
+    #[rstest]
+    fn test_add_line_replaces_same_address(mut info: DebugInfo) {
+        let loc = SourceLocation {
+            file: "fact.tri".into(),
+            line: 7,
+            column: 1,
+        };
+        info.add_line(0x10, loc.clone());
+        info.add_line(0x12, loc);
+        assert_eq!(Some(7), info.location(0x10).map(|l| l.line));
+        assert_eq!(4, info.lines.len());
+    }
+
+    #[rstest]
+    #[case("line 0x10 fact.tri:3")]
+    #[case("proc fact ten 0x20")]
+    #[case("var fact n XX 0 1 Integer")]
+    #[case("frame 1 2 3")]
+    fn test_parse_malformed_record_err(#[case] text: &str) {
+        assert_eq!(
+            TamError::InvalidDebugInfo(2),
+            DebugInfo::parse(&format!("\n{text}")).unwrap_err()
+        );
+    }
+}
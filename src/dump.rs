@@ -0,0 +1,298 @@
+//! Annotated dumps and raw images of the data store.
+
+use crate::{HB, HT, LB, MEMORY_SIZE, SB, ST, TamEmulator};
+use std::{
+    fmt::Write,
+    ops::{Bound, Range, RangeBounds},
+};
+
+/// A contiguous part of the data store shown under its own heading.
+struct Region {
+    heading: String,
+    words: Range<usize>,
+    /// Register whose offsets name the variables in the region, and its value
+    base: Option<(usize, u16)>,
+    /// Scope of the variables in the region, as used by the debug info
+    scope: Option<String>,
+    /// Whether the region is a routine frame starting with link data
+    frame: bool,
+}
+
+fn to_range(range: impl RangeBounds<u16>) -> Range<usize> {
+    let start = match range.start_bound() {
+        Bound::Included(&s) => s as usize,
+        Bound::Excluded(&s) => s as usize + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(&e) => e as usize + 1,
+        Bound::Excluded(&e) => e as usize,
+        Bound::Unbounded => MEMORY_SIZE,
+    };
+    start..end.max(start)
+}
+
+impl TamEmulator {
+    /// Formats the data words in `range` as a table, grouped into the globals, each
+    /// stack frame and the heap.
+    ///
+    /// Each word is shown in hex and decimal. The link data of each frame is labelled,
+    /// the word at each frame's `LB` is marked, words holding variables named in the
+    /// debug info are annotated with the variable, and the first word of each heap
+    /// block is annotated with its size and allocation site. Words in `range` between
+    /// the stack and the heap are not in use and are left out.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tam_rs::TamEmulator;
+    ///
+    /// let mut emu = TamEmulator::new(false);
+    /// // LOADL 7; HALT
+    /// emu.set_program(&[0x30, 0, 0, 7, 0xf0, 0, 0, 0]).unwrap();
+    /// while emu.fetch_decode().and_then(|instr| emu.execute(instr)).unwrap() {}
+    ///
+    /// let dump = emu.dump_data(..);
+    /// assert!(dump.starts_with("globals [0x0000, 0x0001)\n"));
+    /// assert!(dump.contains("0x0000  0x0007       7"));
+    /// ```
+    pub fn dump_data(&self, range: impl RangeBounds<u16>) -> String {
+        let range = to_range(range);
+        let mut out = String::new();
+
+        for region in self.regions() {
+            let start = region.words.start.max(range.start);
+            let end = region.words.end.min(range.end);
+            if start >= end {
+                continue;
+            }
+
+            let _ = writeln!(
+                out,
+                "{} [{:#06x}, {:#06x})",
+                region.heading, region.words.start, region.words.end
+            );
+            for addr in start..end {
+                let value = self.data_store[addr];
+                let marker = if region.frame && addr == region.words.start {
+                    "LB"
+                } else {
+                    ""
+                };
+                let _ = write!(
+                    out,
+                    "{marker:>4}  {addr:#06x}  {:#06x}  {value:6}",
+                    value as u16
+                );
+                match self.annotation(&region, addr) {
+                    Some(note) => {
+                        let _ = writeln!(out, "  {note}");
+                    }
+                    None => out.push('\n'),
+                }
+            }
+        }
+        out
+    }
+
+    /// Gets the data words in `range` as a raw image, with each word written as two
+    /// big-endian bytes.
+    pub fn data_image(&self, range: impl RangeBounds<u16>) -> Vec<u8> {
+        self.data_store[to_range(range)]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect()
+    }
+
+    /// Splits the used part of the data store into regions, in address order.
+    fn regions(&self) -> Vec<Region> {
+        let mut frames = self.backtrace();
+        frames.reverse();
+        let routines: Vec<_> = frames.iter().filter(|f| f.static_link.is_some()).collect();
+
+        let st = self.registers[ST] as usize;
+        let globals_end = routines.first().map_or(st, |f| f.lb as usize);
+        let mut regions = vec![Region {
+            heading: String::from("globals"),
+            words: self.registers[SB] as usize..globals_end,
+            base: Some((SB, self.registers[SB])),
+            scope: None,
+            frame: false,
+        }];
+
+        for (i, frame) in routines.iter().enumerate() {
+            let name = self
+                .debug_info
+                .as_ref()
+                .and_then(|info| info.procedure(frame.code_address))
+                .map(|p| p.name.clone());
+            let end = routines.get(i + 1).map_or(st, |f| f.lb as usize);
+            regions.push(Region {
+                heading: format!(
+                    "frame #{} in {}",
+                    routines.len() - i - 1,
+                    name.as_deref().unwrap_or("?")
+                ),
+                words: frame.lb as usize..end.max(frame.lb as usize),
+                base: Some((LB, frame.lb)),
+                scope: name,
+                frame: true,
+            });
+        }
+
+        regions.push(Region {
+            heading: String::from("heap"),
+            words: self.registers[HT] as usize + 1..self.registers[HB] as usize + 1,
+            base: None,
+            scope: None,
+            frame: false,
+        });
+        regions
+    }
+
+    /// Describes what the word at `addr` holds, if known.
+    fn annotation(&self, region: &Region, addr: usize) -> Option<String> {
+        if region.frame {
+            match addr - region.words.start {
+                0 => return Some(String::from("static link")),
+                1 => return Some(String::from("dynamic link")),
+                2 => return Some(String::from("return address")),
+                _ => {}
+            }
+        }
+
+        if let Some(block) = self.heap.block_at(addr as u16) {
+            return Some(format!(
+                "block of {} words allocated at {:#06x}",
+                block.size, block.site
+            ));
+        }
+
+        let (register, base) = region.base?;
+        let info = self.debug_info.as_ref()?;
+        info.variables(region.scope.as_deref())
+            .filter(|var| var.register as usize == register)
+            .find_map(|var| {
+                let start = base as isize + var.offset as isize;
+                let offset = addr as isize - start;
+                (0..var.size as isize).contains(&offset).then(|| {
+                    if offset == 0 {
+                        format!("{}: {}", var.name, var.ty)
+                    } else {
+                        format!("{}+{offset}", var.name)
+                    }
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CP, asm,
+        debug_info::{DebugInfo, Procedure, Variable},
+        tam,
+    };
+    use rstest::*;
+
+    /// Runs `code` until it halts, faults or reaches `stop`.
+    fn run(code: &[u32], stop: u16) -> TamEmulator {
+        let bytes = asm::to_bytes(code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+        while emu.registers[CP] != stop
+            && emu.fetch_decode().and_then(|instr| emu.execute(instr)) == Ok(true)
+        {}
+        emu
+    }
+
+    #[rstest]
+    fn test_dump_data_marks_frames_and_variables() {
+        let code = tam! {
+            PUSH 1;
+            CALL(LB) 3[CB];
+            HALT;
+            LOADL -2;
+            STORE(1) 0[SB];
+            HALT;
+        };
+        let mut emu = run(&code, 5);
+        let mut info = DebugInfo::default();
+        info.add_procedure(Procedure {
+            name: String::from("f"),
+            start: 3,
+            end: 6,
+        });
+        info.add_variable(Variable {
+            scope: None,
+            name: String::from("x"),
+            register: SB as u8,
+            offset: 0,
+            size: 1,
+            ty: String::from("Integer"),
+        });
+        emu.set_debug_info(info);
+
+        let expected = "\
+globals [0x0000, 0x0001)
+      0x0000  0xfffe      -2  x: Integer
+frame #0 in f [0x0001, 0x0004)
+  LB  0x0001  0x0000       0  static link
+      0x0002  0x0000       0  dynamic link
+      0x0003  0x0002       2  return address
+";
+        assert_eq!(expected, emu.dump_data(..));
+    }
+
+    #[rstest]
+    fn test_dump_data_range_skips_other_regions() {
+        let code = tam! {
+            PUSH 1;
+            CALL(LB) 3[CB];
+            HALT;
+            HALT;
+        };
+        let emu = run(&code, 3);
+
+        let expected = "\
+frame #0 in ? [0x0001, 0x0004)
+      0x0002  0x0000       0  dynamic link
+";
+        assert_eq!(expected, emu.dump_data(2..3));
+    }
+
+    #[rstest]
+    fn test_dump_data_heap_blocks() {
+        let code = tam! {
+            LOADL 2;
+            CALL(CB) new[PB];
+            HALT;
+        };
+        let emu = run(&code, 3);
+
+        let expected = "\
+heap [0xfffe, 0x10000)
+      0xfffe  0x0000       0  block of 2 words allocated at 0x0001
+      0xffff  0x0000       0
+";
+        assert_eq!(expected, emu.dump_data(0xfff0..));
+    }
+
+    #[rstest]
+    #[case(1..3, vec![0xff, 0xff, 0x12, 0x34])]
+    #[case(2..=2, vec![0x12, 0x34])]
+    #[case(3..3, vec![])]
+    fn test_data_image_range(#[case] range: impl RangeBounds<u16>, #[case] expected: Vec<u8>) {
+        let mut emu = TamEmulator::new(false);
+        emu.data_store[..3].copy_from_slice(&[1, -1, 0x1234]);
+
+        assert_eq!(expected, emu.data_image(range));
+    }
+
+    #[rstest]
+    fn test_data_image_whole_store() {
+        let emu = TamEmulator::new(false);
+        assert_eq!(MEMORY_SIZE * 2, emu.data_image(..).len());
+    }
+}
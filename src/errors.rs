@@ -1,3 +1,5 @@
+use std::fmt::{self, Display};
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TamError {
     OutOfMemory,
@@ -6,7 +8,121 @@ pub enum TamError {
     StackOverflow,
     StackUnderflow,
     UnknownOpcode(u8),
+    /// A `RETURN` found invalid link data in the frame at `lb`
+    CorruptFrame {
+        lb: u16,
+        dynamic_link: i16,
+        return_addr: i16,
+    },
+    /// A load read the data word at this address before anything was written to it
+    UninitialisedRead(u16),
+    /// In trapping mode, an arithmetic primitive or address computation overflowed
+    ArithmeticOverflow {
+        operation: &'static str,
+        lhs: i32,
+        rhs: i32,
+    },
+    DivisionByZero,
+    /// `new` was asked for a block of this many words, which is not positive
+    InvalidAllocationSize(i16),
+    /// `dispose` was given an address and size that are not those of a live block
+    InvalidDispose {
+        address: u16,
+        size: i16,
+    },
+    /// In checked heap mode, a load or store touched this address in a disposed block
+    UseAfterDispose {
+        address: u16,
+        allocated_at: u16,
+        disposed_at: u16,
+    },
+    /// In checked heap mode, the block at this address was disposed a second time
+    DoubleDispose {
+        address: u16,
+        allocated_at: u16,
+        disposed_at: u16,
+    },
     IOError,
+    InvalidDebugInfo(usize),
+    InvalidCoverageData(usize),
+    InvalidObjectFile(usize),
+}
+
+impl Display for TamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TamError::OutOfMemory => write!(f, "out of memory"),
+            TamError::CodeAccessViolation => write!(f, "code access violation"),
+            TamError::DataAccessViolation => write!(f, "data access violation"),
+            TamError::StackOverflow => write!(f, "stack overflow"),
+            TamError::StackUnderflow => write!(f, "stack underflow"),
+            TamError::UnknownOpcode(op) => write!(f, "unknown opcode {op}"),
+            TamError::CorruptFrame {
+                lb,
+                dynamic_link,
+                return_addr,
+            } => write!(
+                f,
+                "corrupt frame at {lb:#06x} with dynamic link {dynamic_link:#06x} and return address {return_addr:#06x}"
+            ),
+            TamError::UninitialisedRead(address) => {
+                write!(f, "read of uninitialised word {address:#06x}")
+            }
+            TamError::ArithmeticOverflow {
+                operation,
+                lhs,
+                rhs,
+            } => write!(f, "{operation} overflowed with operands {lhs} and {rhs}"),
+            TamError::DivisionByZero => write!(f, "division by zero"),
+            TamError::InvalidAllocationSize(size) => {
+                write!(
+                    f,
+                    "new asked for {size} words, which is not a positive size"
+                )
+            }
+            TamError::InvalidDispose { address, size } => write!(
+                f,
+                "dispose of {size} words at {address:#06x}, which is not an allocated block"
+            ),
+            TamError::UseAfterDispose { address, .. } => {
+                write!(f, "use of heap word {address:#06x} after dispose")
+            }
+            TamError::DoubleDispose { address, .. } => {
+                write!(f, "second dispose of heap block {address:#06x}")
+            }
+            TamError::IOError => write!(f, "I/O error"),
+            TamError::InvalidDebugInfo(line) => write!(f, "invalid debug info on line {line}"),
+            TamError::InvalidCoverageData(line) => {
+                write!(f, "invalid coverage data on line {line}")
+            }
+            TamError::InvalidObjectFile(line) => write!(f, "invalid object file on line {line}"),
+        }
+    }
 }
 
 pub type TamResult<T> = Result<T, TamError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case(TamError::StackUnderflow, "stack underflow")]
+    #[case(TamError::UninitialisedRead(0x12), "read of uninitialised word 0x0012")]
+    #[case(
+        TamError::ArithmeticOverflow { operation: "add", lhs: 32767, rhs: 1 },
+        "add overflowed with operands 32767 and 1"
+    )]
+    #[case(
+        TamError::CorruptFrame { lb: 4, dynamic_link: -1, return_addr: 3 },
+        "corrupt frame at 0x0004 with dynamic link 0xffff and return address 0x0003"
+    )]
+    #[case(
+        TamError::UseAfterDispose { address: 0xfff0, allocated_at: 1, disposed_at: 2 },
+        "use of heap word 0xfff0 after dispose"
+    )]
+    fn test_display(#[case] err: TamError, #[case] text: &str) {
+        assert_eq!(text, err.to_string());
+    }
+}
@@ -1,23 +1,101 @@
 mod primitive;
 use crate::{
-    CP, CT, HT, LB, ST, TamEmulator, TamInstruction,
+    ArithmeticMode, CP, CT, HT, L1, L6, LB, PB, PT, SB, ST, TamEmulator, TamInstruction,
     errors::{TamError, TamResult},
 };
 
+/// A routine implementing a primitive.
+pub(crate) type Primitive = fn(&mut TamEmulator) -> TamResult<()>;
+
+/// Gets the routine implementing the primitive at displacement `offset` from `PB`.
+pub(crate) fn primitive_handler(offset: i16) -> Primitive {
+    match offset {
+        2 => TamEmulator::exec_prim_and,
+        3 => TamEmulator::exec_prim_or,
+        4 => TamEmulator::exec_prim_not,
+        5 => TamEmulator::exec_prim_succ,
+        6 => TamEmulator::exec_prim_pred,
+        7 => TamEmulator::exec_prim_neg,
+        8 => TamEmulator::exec_prim_add,
+        9 => TamEmulator::exec_prim_sub,
+        10 => TamEmulator::exec_prim_mult,
+        11 => TamEmulator::exec_prim_div,
+        12 => TamEmulator::exec_prim_mod,
+        13 => TamEmulator::exec_prim_lt,
+        14 => TamEmulator::exec_prim_le,
+        15 => TamEmulator::exec_prim_ge,
+        16 => TamEmulator::exec_prim_gt,
+        17 => TamEmulator::exec_prim_eq,
+        18 => TamEmulator::exec_prim_ne,
+        19 => TamEmulator::exec_prim_eol,
+        20 => TamEmulator::exec_prim_eof,
+        21 => TamEmulator::exec_prim_get,
+        22 => TamEmulator::exec_prim_put,
+        23 => TamEmulator::exec_prim_geteol,
+        24 => TamEmulator::exec_prim_puteol,
+        25 => TamEmulator::exec_prim_getint,
+        26 => TamEmulator::exec_prim_putint,
+        27 => TamEmulator::exec_prim_new,
+        28 => TamEmulator::exec_prim_dispose,
+        // id leaves its operand where it is
+        _ => |_| Ok(()),
+    }
+}
+
 impl TamEmulator {
-    fn calc_address(&self, instr: TamInstruction) -> u16 {
-        u16::wrapping_add_signed(self.registers[instr.r as usize], instr.d)
+    /// Gets the value of register `r`.
+    ///
+    /// The display registers `L1`..`L6` are not kept up to date, but found by following
+    /// that many static links from the frame at `LB`.
+    pub(crate) fn register(&self, r: usize) -> u16 {
+        if !(L1..=L6).contains(&r) {
+            return self.registers[r];
+        }
+        (LB..r).fold(self.registers[LB], |frame, _| {
+            self.data_store[frame as usize] as u16
+        })
+    }
+
+    fn calc_address(&self, instr: TamInstruction) -> TamResult<u16> {
+        let base = self.register(instr.r as usize);
+        match self.arithmetic_mode {
+            ArithmeticMode::Wrapping => Ok(base.wrapping_add_signed(instr.d)),
+            ArithmeticMode::Trapping => {
+                base.checked_add_signed(instr.d)
+                    .ok_or(TamError::ArithmeticOverflow {
+                        operation: "address",
+                        lhs: base as i32,
+                        rhs: instr.d as i32,
+                    })
+            }
+            ArithmeticMode::Saturating => Ok(base.saturating_add_signed(instr.d)),
+        }
+    }
+
+    /// Gets the address of the word `i` words after `addr`, failing if it would lie past
+    /// the end of the data store.
+    fn word_address(&self, addr: u16, i: u16) -> TamResult<u16> {
+        addr.checked_add(i).ok_or(match self.arithmetic_mode {
+            ArithmeticMode::Trapping => TamError::ArithmeticOverflow {
+                operation: "address",
+                lhs: addr as i32,
+                rhs: i as i32,
+            },
+            _ => TamError::DataAccessViolation,
+        })
     }
 
     pub(super) fn exec_load(&mut self, instr: TamInstruction) -> TamResult<()> {
-        let addr = self.calc_address(instr);
+        let addr = self.calc_address(instr)?;
 
         for i in 0..instr.n {
-            let addr = addr + i as u16;
+            let addr = self.word_address(addr, i as u16)?;
             if addr >= self.registers[ST] && addr <= self.registers[HT] {
                 return Err(TamError::DataAccessViolation);
             }
 
+            self.check_initialised(addr)?;
+            self.check_heap_access(addr)?;
             self.push(self.data_store[addr as usize])?;
         }
 
@@ -25,7 +103,7 @@ impl TamEmulator {
     }
 
     pub(super) fn exec_loada(&mut self, instr: TamInstruction) -> TamResult<()> {
-        let addr = self.calc_address(instr);
+        let addr = self.calc_address(instr)?;
         self.push(addr as i16)
     }
 
@@ -33,11 +111,13 @@ impl TamEmulator {
         let addr = self.pop()? as u16;
 
         for i in 0..instr.n {
-            let addr = addr + i as u16;
+            let addr = self.word_address(addr, i as u16)?;
             if addr >= self.registers[ST] && addr <= self.registers[HT] {
                 return Err(TamError::DataAccessViolation);
             }
 
+            self.check_initialised(addr)?;
+            self.check_heap_access(addr)?;
             self.push(self.data_store[addr as usize])?;
         }
 
@@ -48,39 +128,68 @@ impl TamEmulator {
         self.push(instr.d)
     }
 
-    pub(super) fn exec_store(&mut self, instr: TamInstruction) -> TamResult<()> {
-        let mut data = Vec::new();
-        for _ in 0..instr.n {
-            data.push(self.pop()?);
+    /// Pops `n` words off the stack, returning the address of the first of them.
+    ///
+    /// The words stay in the data store above `ST` until something else is pushed, so
+    /// they can be copied from there. If the stack holds fewer than `n` words, it is
+    /// emptied and the pop fails.
+    fn pop_words(&mut self, n: u16) -> TamResult<u16> {
+        let st = self.registers[ST];
+        let Some(start) = st.checked_sub(n) else {
+            for addr in 0..st {
+                self.mark_written(addr, false);
+            }
+            self.registers[ST] = 0;
+            return Err(TamError::StackUnderflow);
+        };
+
+        for addr in start..st {
+            self.mark_written(addr, false);
         }
+        self.registers[ST] = start;
+        Ok(start)
+    }
 
-        let addr = self.calc_address(instr);
-        for i in 0..instr.n {
-            let addr = addr + i as u16;
-            if addr >= self.registers[ST] && addr <= self.registers[HT] {
-                return Err(TamError::DataAccessViolation);
+    /// Copies `n` words from `src` to `addr` and onwards.
+    ///
+    /// The words are checked in order, and those before the first that may not be
+    /// written are still copied.
+    fn store_words(&mut self, addr: u16, src: u16, n: u16) -> TamResult<()> {
+        let mut result = Ok(());
+        let mut count = 0;
+        for i in 0..n {
+            let checked = self.word_address(addr, i).and_then(|addr| {
+                if addr >= self.registers[ST] && addr <= self.registers[HT] {
+                    return Err(TamError::DataAccessViolation);
+                }
+                self.check_heap_access(addr)
+            });
+            if let Err(e) = checked {
+                result = Err(e);
+                break;
             }
-            self.data_store[addr as usize] = data.pop().expect("unexpectedly stored too much data");
+            count += 1;
         }
-        Ok(())
+
+        let src = src as usize;
+        self.data_store
+            .copy_within(src..src + count as usize, addr as usize);
+        for i in 0..count {
+            self.mark_written(addr + i, true);
+        }
+        result
+    }
+
+    pub(super) fn exec_store(&mut self, instr: TamInstruction) -> TamResult<()> {
+        let src = self.pop_words(instr.n as u16)?;
+        let addr = self.calc_address(instr)?;
+        self.store_words(addr, src, instr.n as u16)
     }
 
     pub(super) fn exec_storei(&mut self, instr: TamInstruction) -> TamResult<()> {
         let addr = self.pop()? as u16;
-
-        let mut data = Vec::new();
-        for _ in 0..instr.n {
-            data.push(self.pop()?);
-        }
-
-        for i in 0..instr.n {
-            let addr = addr + i as u16;
-            if addr >= self.registers[ST] && addr <= self.registers[HT] {
-                return Err(TamError::DataAccessViolation);
-            }
-            self.data_store[addr as usize] = data.pop().expect("unexpectedly stored too much data");
-        }
-        Ok(())
+        let src = self.pop_words(instr.n as u16)?;
+        self.store_words(addr, src, instr.n as u16)
     }
 
     pub(super) fn exec_call_primitive(&mut self, offset: i16) -> TamResult<()> {
@@ -89,17 +198,11 @@ impl TamEmulator {
             "exec_call_primitive received invalid offset {offset}"
         );
 
-        match offset {
-            1 => Ok(()), // id
-            2 => self.exec_prim_and(),
-            3 => self.exec_prim_or(),
-            4 => self.exec_prim_not(),
-            _ => Ok(()),
-        }
+        primitive_handler(offset)(self)
     }
 
     pub(super) fn exec_call(&mut self, instr: TamInstruction) -> TamResult<()> {
-        let static_link = self.registers[instr.n as usize];
+        let static_link = self.register(instr.n as usize);
         let dynamic_link = self.registers[LB];
         let return_address = self.registers[CP];
 
@@ -108,52 +211,157 @@ impl TamEmulator {
         self.push(return_address as i16)?;
 
         self.registers[LB] = self.registers[ST] - 3;
-        let addr = self.calc_address(instr);
+        let addr = self.calc_address(instr)?;
         if addr >= self.registers[CT] {
             return Err(TamError::CodeAccessViolation);
         }
 
         self.registers[CP] = addr;
+        self.call_depth += 1;
         Ok(())
     }
 
-    pub(super) fn exec_return(&mut self, instr: TamInstruction) -> TamResult<()> {
-        // pop result and save link data
-        let mut return_val = Vec::new();
-        for _ in 0..instr.n {
-            return_val.push(self.pop()?);
+    pub(super) fn exec_calli(&mut self) -> TamResult<()> {
+        let addr = self.pop()? as u16;
+        let static_link = self.pop()?;
+
+        if addr > self.registers[PB] && addr < self.registers[PT] {
+            return self.exec_call_primitive((addr - self.registers[PB]) as i16);
         }
 
-        let dynamic_link_addr = self.registers[LB] + 1;
-        let dynamic_link = self.data_store[dynamic_link_addr as usize];
-        let return_addr_addr = self.registers[LB] + 2;
-        let return_addr = self.data_store[return_addr_addr as usize];
+        let dynamic_link = self.registers[LB];
+        let return_address = self.registers[CP];
+
+        self.push(static_link)?;
+        self.push(dynamic_link as i16)?;
+        self.push(return_address as i16)?;
 
-        // pop stack frame
-        while self.registers[ST] != self.registers[LB] {
-            self.pop()?;
+        self.registers[LB] = self.registers[ST] - 3;
+        if addr >= self.registers[CT] {
+            return Err(TamError::CodeAccessViolation);
         }
 
-        // pop args
-        for _ in 0..instr.d {
-            self.pop()?;
+        self.registers[CP] = addr;
+        self.call_depth += 1;
+        Ok(())
+    }
+
+    /// Checks that the link data of the frame at `lb` can be safely returned through.
+    ///
+    /// The frame must lie entirely below `ST`, its return address must be inside the
+    /// code store, and its dynamic link must point to an earlier frame, or to `SB` for
+    /// a frame called from the main program.
+    pub(crate) fn check_frame(
+        &self,
+        lb: u16,
+        dynamic_link: i16,
+        return_addr: i16,
+    ) -> TamResult<()> {
+        let on_stack = lb as usize + 3 <= self.registers[ST] as usize;
+        let return_ok = (return_addr as u16) < self.registers[CT];
+        let link_ok = (dynamic_link as u16) < lb || dynamic_link as u16 == self.registers[SB];
+
+        if on_stack && return_ok && link_ok {
+            Ok(())
+        } else {
+            Err(TamError::CorruptFrame {
+                lb,
+                dynamic_link,
+                return_addr,
+            })
         }
+    }
+
+    pub(super) fn exec_return(&mut self, instr: TamInstruction) -> TamResult<()> {
+        // pop result, leaving it in place to be copied down, and read link data
+        let n = instr.n as u16;
+        let result = self.pop_words(n)?;
+
+        let lb = self.registers[LB];
+        let dynamic_link = self.data_store.get(lb as usize + 1).copied().unwrap_or(0);
+        let return_addr = self.data_store.get(lb as usize + 2).copied().unwrap_or(0);
+        self.check_frame(lb, dynamic_link, return_addr)?;
+
+        // pop stack frame and args
+        self.pop_words(self.registers[ST] - lb)?;
+        self.pop_words(instr.d.max(0) as u16)?;
 
         // push result
-        for _ in 0..instr.n {
-            self.push(
-                return_val
-                    .pop()
-                    .expect("return value had wrong number of bytes"),
-            )?;
+        let st = self.registers[ST];
+        let result = result as usize;
+        self.data_store
+            .copy_within(result..result + n as usize, st as usize);
+        for addr in st..st + n {
+            self.mark_written(addr, true);
         }
+        self.registers[ST] = st + n;
 
         // update registers
         self.registers[LB] = dynamic_link as u16;
         self.registers[CP] = return_addr as u16;
+        self.call_depth = self.call_depth.saturating_sub(1);
+
+        Ok(())
+    }
+
+    pub(super) fn exec_push(&mut self, instr: TamInstruction) -> TamResult<()> {
+        let new_top = self.registers[ST] as i32 + instr.d as i32;
+        if new_top < 0 {
+            return Err(TamError::StackUnderflow);
+        }
+        if new_top > self.registers[HT] as i32 {
+            return Err(TamError::StackOverflow);
+        }
+
+        // space given up by a negative push no longer holds the program's values
+        for addr in new_top as u16..self.registers[ST] {
+            self.mark_written(addr, false);
+        }
+        self.registers[ST] = new_top as u16;
+        Ok(())
+    }
 
+    pub(super) fn exec_pop(&mut self, instr: TamInstruction) -> TamResult<()> {
+        let mut result = Vec::new();
+        for _ in 0..instr.n {
+            result.push(self.pop()?);
+        }
+
+        for _ in 0..instr.d {
+            self.pop()?;
+        }
+
+        while let Some(value) = result.pop() {
+            self.push(value)?;
+        }
+        Ok(())
+    }
+
+    fn jump_to(&mut self, addr: u16) -> TamResult<()> {
+        if addr >= self.registers[CT] {
+            return Err(TamError::CodeAccessViolation);
+        }
+
+        self.registers[CP] = addr;
         Ok(())
     }
+
+    pub(super) fn exec_jump(&mut self, instr: TamInstruction) -> TamResult<()> {
+        self.jump_to(self.calc_address(instr)?)
+    }
+
+    pub(super) fn exec_jumpi(&mut self) -> TamResult<()> {
+        let addr = self.pop()? as u16;
+        self.jump_to(addr)
+    }
+
+    pub(super) fn exec_jumpif(&mut self, instr: TamInstruction) -> TamResult<()> {
+        if self.pop()? == instr.n as i16 {
+            self.jump_to(self.calc_address(instr)?)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1,6 +1,47 @@
-use crate::{TamEmulator, errors::TamResult};
+use crate::{
+    ArithmeticMode, HT, ST, TamEmulator,
+    errors::{TamError, TamResult},
+};
 
 impl TamEmulator {
+    /// Fits the exact result of an arithmetic operation into a word according to the
+    /// arithmetic mode.
+    fn arithmetic_result(
+        &self,
+        operation: &'static str,
+        lhs: i16,
+        rhs: i16,
+        result: i32,
+    ) -> TamResult<i16> {
+        match i16::try_from(result) {
+            Ok(result) => Ok(result),
+            Err(_) => match self.arithmetic_mode {
+                ArithmeticMode::Wrapping => Ok(result as i16),
+                ArithmeticMode::Trapping => Err(TamError::ArithmeticOverflow {
+                    operation,
+                    lhs: lhs as i32,
+                    rhs: rhs as i32,
+                }),
+                ArithmeticMode::Saturating => {
+                    Ok(result.clamp(i16::MIN as i32, i16::MAX as i32) as i16)
+                }
+            },
+        }
+    }
+
+    fn exec_prim_arithmetic(
+        &mut self,
+        operation: &'static str,
+        f: fn(i32, i32) -> Option<i32>,
+    ) -> TamResult<()> {
+        let op2 = self.pop()?;
+        let op1 = self.pop()?;
+        // the exact result always fits in an i32, so `f` only fails on division by zero
+        let exact = f(op1 as i32, op2 as i32).ok_or(TamError::DivisionByZero)?;
+        let result = self.arithmetic_result(operation, op1, op2, exact)?;
+        self.push(result)
+    }
+
     pub(super) fn exec_prim_and(&mut self) -> TamResult<()> {
         let op2 = self.pop()?;
         let op1 = self.pop()?;
@@ -17,4 +58,156 @@ impl TamEmulator {
         let op = self.pop()?;
         self.push(if op == 0 { 1 } else { 0 })
     }
+
+    pub(super) fn exec_prim_succ(&mut self) -> TamResult<()> {
+        let op = self.pop()?;
+        let result = self.arithmetic_result("succ", op, 1, op as i32 + 1)?;
+        self.push(result)
+    }
+
+    pub(super) fn exec_prim_pred(&mut self) -> TamResult<()> {
+        let op = self.pop()?;
+        let result = self.arithmetic_result("pred", op, 1, op as i32 - 1)?;
+        self.push(result)
+    }
+
+    pub(super) fn exec_prim_neg(&mut self) -> TamResult<()> {
+        let op = self.pop()?;
+        let result = self.arithmetic_result("neg", 0, op, -(op as i32))?;
+        self.push(result)
+    }
+
+    pub(super) fn exec_prim_add(&mut self) -> TamResult<()> {
+        self.exec_prim_arithmetic("add", i32::checked_add)
+    }
+
+    pub(super) fn exec_prim_sub(&mut self) -> TamResult<()> {
+        self.exec_prim_arithmetic("sub", i32::checked_sub)
+    }
+
+    pub(super) fn exec_prim_mult(&mut self) -> TamResult<()> {
+        self.exec_prim_arithmetic("mult", i32::checked_mul)
+    }
+
+    pub(super) fn exec_prim_div(&mut self) -> TamResult<()> {
+        self.exec_prim_arithmetic("div", i32::checked_div)
+    }
+
+    pub(super) fn exec_prim_mod(&mut self) -> TamResult<()> {
+        self.exec_prim_arithmetic("mod", i32::checked_rem)
+    }
+
+    fn exec_prim_comparison(&mut self, f: fn(&i16, &i16) -> bool) -> TamResult<()> {
+        let op2 = self.pop()?;
+        let op1 = self.pop()?;
+        self.push(f(&op1, &op2) as i16)
+    }
+
+    pub(super) fn exec_prim_lt(&mut self) -> TamResult<()> {
+        self.exec_prim_comparison(i16::lt)
+    }
+
+    pub(super) fn exec_prim_le(&mut self) -> TamResult<()> {
+        self.exec_prim_comparison(i16::le)
+    }
+
+    pub(super) fn exec_prim_ge(&mut self) -> TamResult<()> {
+        self.exec_prim_comparison(i16::ge)
+    }
+
+    pub(super) fn exec_prim_gt(&mut self) -> TamResult<()> {
+        self.exec_prim_comparison(i16::gt)
+    }
+
+    /// Pops an operand size and two operands of that size, and compares the operands.
+    fn pop_equal_operands(&mut self) -> TamResult<bool> {
+        let size = self.pop()? as u16 as usize;
+        let st = self.registers[ST] as usize;
+        if 2 * size > st {
+            return Err(TamError::StackUnderflow);
+        }
+
+        let equal = self.data_store[st - 2 * size..st - size] == self.data_store[st - size..st];
+        for _ in 0..2 * size {
+            self.pop()?;
+        }
+        Ok(equal)
+    }
+
+    pub(super) fn exec_prim_eq(&mut self) -> TamResult<()> {
+        let equal = self.pop_equal_operands()?;
+        self.push(equal as i16)
+    }
+
+    pub(super) fn exec_prim_ne(&mut self) -> TamResult<()> {
+        let equal = self.pop_equal_operands()?;
+        self.push(!equal as i16)
+    }
+
+    /// Stores a value read from the input at the address given to `get` or `getint`.
+    fn store_input(&mut self, addr: u16, value: i16) -> TamResult<()> {
+        if addr >= self.registers[ST] && addr <= self.registers[HT] {
+            return Err(TamError::DataAccessViolation);
+        }
+        self.check_heap_access(addr)?;
+        self.data_store[addr as usize] = value;
+        self.mark_written(addr, true);
+        Ok(())
+    }
+
+    pub(super) fn exec_prim_eol(&mut self) -> TamResult<()> {
+        let eol = self.io.peek()? == Some(b'\n');
+        self.push(eol as i16)
+    }
+
+    pub(super) fn exec_prim_eof(&mut self) -> TamResult<()> {
+        let eof = self.io.peek()?.is_none();
+        self.push(eof as i16)
+    }
+
+    pub(super) fn exec_prim_get(&mut self) -> TamResult<()> {
+        let addr = self.pop()? as u16;
+        // reading past the end of the input gives -1, which is not a character
+        let ch = self.io.read()?.map_or(-1, |byte| byte as i16);
+        self.store_input(addr, ch)
+    }
+
+    pub(super) fn exec_prim_put(&mut self) -> TamResult<()> {
+        let ch = self.pop()?;
+        self.io.write(&[ch as u8])
+    }
+
+    pub(super) fn exec_prim_geteol(&mut self) -> TamResult<()> {
+        while let Some(byte) = self.io.read()?
+            && byte != b'\n'
+        {}
+        Ok(())
+    }
+
+    pub(super) fn exec_prim_puteol(&mut self) -> TamResult<()> {
+        self.io.write(b"\n")
+    }
+
+    pub(super) fn exec_prim_getint(&mut self) -> TamResult<()> {
+        let addr = self.pop()? as u16;
+        let value = self.read_int()?;
+        self.store_input(addr, value)
+    }
+
+    pub(super) fn exec_prim_putint(&mut self) -> TamResult<()> {
+        let value = self.pop()?;
+        self.io.write(value.to_string().as_bytes())
+    }
+
+    pub(super) fn exec_prim_new(&mut self) -> TamResult<()> {
+        let size = self.pop()?;
+        let address = self.allocate(size)?;
+        self.push(address as i16)
+    }
+
+    pub(super) fn exec_prim_dispose(&mut self) -> TamResult<()> {
+        let address = self.pop()? as u16;
+        let size = self.pop()?;
+        self.dispose(address, size)
+    }
 }
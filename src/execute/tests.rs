@@ -1,7 +1,14 @@
 use super::*;
-use crate::SB;
+use crate::{ArithmeticMode, L2, LOAD, PB, PT, RETURN, SB, STORE, STOREI, asm, io::MemoryIo};
 use rstest::*;
 
+/// Decodes a single instruction written as for `tam!`.
+macro_rules! instr {
+    ($($instr:tt)*) => {
+        TamInstruction::from(crate::tam!($($instr)*)[0])
+    };
+}
+
 #[fixture]
 fn emulator() -> TamEmulator {
     TamEmulator::new(false)
@@ -25,12 +32,7 @@ fn set_test_data(emu: &mut TamEmulator, data: &[i16]) {
 fn test_exec_load_all_in_range_ok(mut emulator: TamEmulator) {
     set_test_data(&mut emulator, &[0x12, 0x98]);
 
-    let instr = TamInstruction {
-        op: 0,
-        r: 4,
-        n: 2,
-        d: 0,
-    };
+    let instr = instr!(LOAD(2) 0[SB]);
 
     let res = emulator.exec_load(instr);
     assert!(res.is_ok());
@@ -50,12 +52,7 @@ fn test_exec_load_all_in_range_ok(mut emulator: TamEmulator) {
 fn test_exec_load_addr_out_of_range_data_access_violation(mut emulator: TamEmulator) {
     set_test_program(&mut emulator, &[0x12, 0x98]);
 
-    let instr = TamInstruction {
-        op: 0,
-        r: 4,
-        n: 2,
-        d: 20,
-    };
+    let instr = instr!(LOAD(2) 20[SB]);
 
     let res = emulator.exec_load(instr);
     assert_eq!(TamError::DataAccessViolation, res.unwrap_err());
@@ -66,24 +63,14 @@ fn test_exec_load_stack_full_stack_overflow(mut emulator: TamEmulator) {
     emulator.registers[ST] = 2;
     emulator.registers[HT] = 1;
 
-    let res = emulator.exec_load(TamInstruction {
-        op: 0,
-        r: 0,
-        n: 1,
-        d: 0,
-    });
+    let res = emulator.exec_load(instr!(LOAD(1) 0[CB]));
     assert_eq!(TamError::StackOverflow, res.unwrap_err());
 }
 
 #[rstest]
 fn text_exec_loada_ok(mut emulator: TamEmulator) {
     emulator.registers[SB] = 5;
-    let instr = TamInstruction {
-        op: 1,
-        r: SB as u8,
-        n: 0,
-        d: 3,
-    };
+    let instr = instr!(LOADA 3[SB]);
 
     let res = emulator.exec_loada(instr);
     assert!(res.is_ok());
@@ -95,12 +82,7 @@ fn test_exec_loada_stack_full_stack_overflow(mut emulator: TamEmulator) {
     emulator.registers[ST] = 3;
     emulator.registers[HT] = 3;
 
-    let res = emulator.exec_loada(TamInstruction {
-        op: 1,
-        r: 0,
-        n: 1,
-        d: 0,
-    });
+    let res = emulator.exec_loada(instr!(LOADA 0[CB]));
     assert_eq!(TamError::StackOverflow, res.unwrap_err());
 }
 
@@ -109,12 +91,7 @@ fn test_exec_loadi_all_in_range_ok(mut emulator: TamEmulator) {
     set_test_data(&mut emulator, &[5, 10, 15, 1]);
     emulator.registers[ST] = 4;
 
-    let instr = TamInstruction {
-        op: 2,
-        r: SB as u8,
-        n: 2,
-        d: 1,
-    };
+    let instr = instr!(LOADI(2));
     let res = emulator.exec_loadi(instr);
 
     assert!(res.is_ok());
@@ -125,12 +102,7 @@ fn test_exec_loadi_all_in_range_ok(mut emulator: TamEmulator) {
 
 #[rstest]
 fn test_exec_loadi_empty_stack_stack_underflow(mut emulator: TamEmulator) {
-    let res = emulator.exec_loadi(TamInstruction {
-        op: 0,
-        r: 0,
-        n: 0,
-        d: 0,
-    });
+    let res = emulator.exec_loadi(instr!(LOADI(0)));
     assert_eq!(TamError::StackUnderflow, res.unwrap_err());
 }
 
@@ -139,12 +111,7 @@ fn test_exec_loadi_full_stack_stack_overflow(mut emulator: TamEmulator) {
     emulator.registers[ST] = 2;
     emulator.registers[HT] = 2;
 
-    let res = emulator.exec_loadi(TamInstruction {
-        op: 0,
-        r: 0,
-        n: 2,
-        d: 0,
-    });
+    let res = emulator.exec_loadi(instr!(LOADI(2)));
     assert_eq!(TamError::StackOverflow, res.unwrap_err());
 }
 
@@ -153,23 +120,40 @@ fn test_exec_loadi_addr_out_of_range_data_access_violation(mut emulator: TamEmul
     emulator.data_store[0] = 25;
     emulator.registers[ST] = 1;
 
-    let res = emulator.exec_loadi(TamInstruction {
-        op: 0,
-        r: 0,
-        n: 1,
-        d: 0,
-    });
+    let res = emulator.exec_loadi(instr!(LOADI(1)));
     assert_eq!(TamError::DataAccessViolation, res.unwrap_err());
 }
 
 #[rstest]
-fn test_exec_loadl_all_in_range_ok(mut emulator: TamEmulator) {
-    let instr = TamInstruction {
-        op: 3,
-        r: 0,
-        n: 0,
-        d: 84,
+#[case::load(instr!(LOAD(2) -1[SB]), ArithmeticMode::Wrapping, TamError::DataAccessViolation)]
+#[case::loadi(instr!(LOADI(2)), ArithmeticMode::Wrapping, TamError::DataAccessViolation)]
+#[case::loadi_trapping(
+    instr!(LOADI(2)),
+    ArithmeticMode::Trapping,
+    TamError::ArithmeticOverflow { operation: "address", lhs: 0xffff, rhs: 1 }
+)]
+fn test_exec_load_past_end_of_memory(
+    mut emulator: TamEmulator,
+    #[case] instr: TamInstruction,
+    #[case] mode: ArithmeticMode,
+    #[case] expected: TamError,
+) {
+    emulator.set_arithmetic_mode(mode);
+    // a one-word block at the top of the heap, with its address on the stack
+    assert_eq!(Ok(0xffff), emulator.allocate(1));
+    set_test_data(&mut emulator, &[-1]);
+
+    let res = if instr.op == LOAD {
+        emulator.exec_load(instr)
+    } else {
+        emulator.exec_loadi(instr)
     };
+    assert_eq!(expected, res.unwrap_err());
+}
+
+#[rstest]
+fn test_exec_loadl_all_in_range_ok(mut emulator: TamEmulator) {
+    let instr = instr!(LOADL 84);
     let res = emulator.exec_loadl(instr);
 
     assert!(res.is_ok());
@@ -182,12 +166,7 @@ fn test_exec_loadl_stack_full_stack_overflow(mut emulator: TamEmulator) {
     emulator.registers[ST] = 2;
     emulator.registers[HT] = 2;
 
-    let instr = TamInstruction {
-        op: 3,
-        r: 0,
-        n: 0,
-        d: 84,
-    };
+    let instr = instr!(LOADL 84);
     let res = emulator.exec_loadl(instr);
 
     assert_eq!(TamError::StackOverflow, res.unwrap_err());
@@ -197,12 +176,7 @@ fn test_exec_loadl_stack_full_stack_overflow(mut emulator: TamEmulator) {
 fn test_exec_store_all_in_range_ok(mut emulator: TamEmulator) {
     set_test_data(&mut emulator, &[0, 1, 2, 3, 4, 5]);
 
-    let instr = TamInstruction {
-        op: 4,
-        r: SB as u8,
-        n: 2,
-        d: 1,
-    };
+    let instr = instr!(STORE(2) 1[SB]);
     let res = emulator.exec_store(instr);
 
     assert!(res.is_ok());
@@ -213,12 +187,7 @@ fn test_exec_store_all_in_range_ok(mut emulator: TamEmulator) {
 
 #[rstest]
 fn test_exec_store_not_enough_data_stack_underflow(mut emulator: TamEmulator) {
-    let instr = TamInstruction {
-        op: 4,
-        r: SB as u8,
-        n: 2,
-        d: 1,
-    };
+    let instr = instr!(STORE(2) 1[SB]);
     let res = emulator.exec_store(instr);
     assert_eq!(TamError::StackUnderflow, res.unwrap_err());
 }
@@ -227,12 +196,7 @@ fn test_exec_store_not_enough_data_stack_underflow(mut emulator: TamEmulator) {
 fn test_exec_store_addr_out_of_range_data_access_violation(mut emulator: TamEmulator) {
     emulator.data_store[0] = 1;
     emulator.registers[ST] = 1;
-    let instr = TamInstruction {
-        op: 4,
-        r: SB as u8,
-        n: 1,
-        d: 10,
-    };
+    let instr = instr!(STORE(1) 10[SB]);
     let res = emulator.exec_store(instr);
     assert_eq!(
         TamError::DataAccessViolation,
@@ -244,12 +208,7 @@ fn test_exec_store_addr_out_of_range_data_access_violation(mut emulator: TamEmul
 fn test_exec_storei_all_in_range_ok(mut emulator: TamEmulator) {
     set_test_data(&mut emulator, &[0, 1, 2, 3, 4, 5, 1]);
 
-    let instr = TamInstruction {
-        op: 4,
-        r: SB as u8,
-        n: 2,
-        d: 1,
-    };
+    let instr = instr!(STOREI(2));
     let res = emulator.exec_storei(instr);
 
     assert!(res.is_ok());
@@ -260,12 +219,7 @@ fn test_exec_storei_all_in_range_ok(mut emulator: TamEmulator) {
 
 #[rstest]
 fn test_exec_storei_not_enough_data_stack_underflow(mut emulator: TamEmulator) {
-    let instr = TamInstruction {
-        op: 4,
-        r: SB as u8,
-        n: 2,
-        d: 1,
-    };
+    let instr = instr!(STOREI(2));
     let res = emulator.exec_storei(instr);
     assert_eq!(TamError::StackUnderflow, res.unwrap_err());
 }
@@ -274,12 +228,7 @@ fn test_exec_storei_not_enough_data_stack_underflow(mut emulator: TamEmulator) {
 fn test_exec_storei_addr_out_of_range_data_access_violation(mut emulator: TamEmulator) {
     emulator.data_store[0] = 1;
     emulator.registers[ST] = 1;
-    let instr = TamInstruction {
-        op: 4,
-        r: SB as u8,
-        n: 1,
-        d: 10,
-    };
+    let instr = instr!(STORE(1) 10[SB]);
     let res = emulator.exec_store(instr);
     assert_eq!(
         TamError::DataAccessViolation,
@@ -293,12 +242,7 @@ fn test_exec_call_all_in_range_ok(mut emulator: TamEmulator) {
     emulator.registers[LB] = 3;
     emulator.registers[CP] = 7;
 
-    let instr = TamInstruction {
-        op: 6,
-        r: 0,
-        n: 4,
-        d: 2,
-    };
+    let instr = instr!(CALL(SB) 2[CB]);
     let res = emulator.exec_call(instr);
 
     assert!(res.is_ok());
@@ -316,12 +260,7 @@ fn test_exec_call_stack_full_stack_overflow(mut emulator: TamEmulator) {
     emulator.registers[ST] = 4;
     emulator.registers[HT] = 4;
 
-    let instr = TamInstruction {
-        op: 6,
-        r: 0,
-        n: 4,
-        d: 2,
-    };
+    let instr = instr!(CALL(SB) 2[CB]);
     let res = emulator.exec_call(instr);
 
     assert_eq!(TamError::StackOverflow, res.unwrap_err());
@@ -333,12 +272,7 @@ fn test_exec_call_invalid_target_code_access_violation(mut emulator: TamEmulator
     emulator.registers[LB] = 3;
     emulator.registers[CP] = 7;
 
-    let instr = TamInstruction {
-        op: 6,
-        r: 0,
-        n: 4,
-        d: 22,
-    };
+    let instr = instr!(CALL(SB) 22[CB]);
     let res = emulator.exec_call(instr);
 
     assert_eq!(TamError::CodeAccessViolation, res.unwrap_err());
@@ -350,12 +284,7 @@ fn test_exec_return_all_valid_ok(mut emulator: TamEmulator) {
     emulator.registers[LB] = 2;
     emulator.registers[CT] = 15;
 
-    let instr = TamInstruction {
-        op: 8,
-        r: 0,
-        n: 1,
-        d: 2,
-    };
+    let instr = instr!(RETURN(1) 2);
     let res = emulator.exec_return(instr);
 
     assert!(res.is_ok());
@@ -364,3 +293,692 @@ fn test_exec_return_all_valid_ok(mut emulator: TamEmulator) {
     assert_eq!(1, emulator.registers[ST], "wrong ST after return");
     assert_eq!(4, emulator.data_store[0], "incorrect stack after return");
 }
+
+#[rstest]
+fn test_exec_call_and_return_track_call_depth(mut emulator: TamEmulator) {
+    emulator.registers[CT] = 20;
+    emulator.registers[CP] = 7;
+
+    let call = instr!(CALL(SB) 2[CB]);
+    emulator.exec_call(call).unwrap();
+    assert_eq!(1, emulator.call_depth);
+
+    let ret = instr!(RETURN(0) 0);
+    emulator.exec_return(ret).unwrap();
+    assert_eq!(0, emulator.call_depth);
+}
+
+#[rstest]
+#[case::frame_above_st(&[0, 0, 0, 0], 3, 0, 2)]
+#[case::return_addr_outside_code(&[0, 0, 0, 40, 4], 1, 0, 40)]
+#[case::dynamic_link_above_lb(&[0, 0, 5, 7, 4], 1, 5, 7)]
+#[case::negative_return_addr(&[0, 0, 0, -1, 4], 1, 0, -1)]
+fn test_exec_return_bad_link_data_corrupt_frame(
+    mut emulator: TamEmulator,
+    #[case] data: &[i16],
+    #[case] lb: u16,
+    #[case] dynamic_link: i16,
+    #[case] return_addr: i16,
+) {
+    set_test_data(&mut emulator, data);
+    emulator.registers[LB] = lb;
+    emulator.registers[CT] = 15;
+    emulator.data_store[lb as usize + 2] = return_addr;
+
+    let instr = instr!(RETURN(0) 0);
+    let res = emulator.exec_return(instr);
+
+    assert_eq!(
+        TamError::CorruptFrame {
+            lb,
+            dynamic_link,
+            return_addr
+        },
+        res.unwrap_err()
+    );
+}
+
+#[rstest]
+fn test_exec_return_from_first_frame_without_globals_ok(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[0, 0, 9]);
+    emulator.registers[CT] = 15;
+
+    let instr = instr!(RETURN(0) 0);
+    let res = emulator.exec_return(instr);
+
+    assert!(res.is_ok());
+    assert_eq!(9, emulator.registers[CP]);
+    assert_eq!(0, emulator.registers[ST]);
+}
+
+#[rstest]
+fn test_exec_calli_routine_ok(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[6, 2]);
+    emulator.registers[CT] = 20;
+    emulator.registers[PB] = 20;
+    emulator.registers[PT] = 49;
+    emulator.registers[CP] = 7;
+
+    let res = emulator.exec_calli();
+
+    assert!(res.is_ok());
+    assert_eq!(6, emulator.data_store[0], "wrong static link");
+    assert_eq!(0, emulator.data_store[1], "wrong dynamic link");
+    assert_eq!(7, emulator.data_store[2], "wrong return address");
+    assert_eq!(2, emulator.registers[CP], "jumped to wrong location");
+    assert_eq!(1, emulator.call_depth);
+}
+
+#[rstest]
+fn test_exec_calli_primitive_ok(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[0, 0, 24]);
+    emulator.registers[CT] = 20;
+    emulator.registers[PB] = 20;
+    emulator.registers[PT] = 49;
+    emulator.registers[CP] = 7;
+
+    // not(0) through a closure for the primitive
+    let res = emulator.exec_calli();
+
+    assert!(res.is_ok());
+    assert_eq!(1, emulator.registers[ST]);
+    assert_eq!(1, emulator.data_store[0]);
+    assert_eq!(7, emulator.registers[CP]);
+    assert_eq!(0, emulator.call_depth);
+}
+
+#[rstest]
+fn test_exec_calli_invalid_target_code_access_violation(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[0, 60]);
+    emulator.registers[CT] = 20;
+    emulator.registers[PB] = 20;
+    emulator.registers[PT] = 49;
+
+    let res = emulator.exec_calli();
+
+    assert_eq!(TamError::CodeAccessViolation, res.unwrap_err());
+}
+
+#[rstest]
+#[case(instr!(PUSH 3), 5)]
+#[case(instr!(PUSH -2), 0)]
+fn test_exec_push_ok(mut emulator: TamEmulator, #[case] instr: TamInstruction, #[case] st: u16) {
+    set_test_data(&mut emulator, &[1, 2]);
+
+    let res = emulator.exec_push(instr);
+
+    assert!(res.is_ok());
+    assert_eq!(st, emulator.registers[ST]);
+}
+
+#[rstest]
+#[case(instr!(PUSH 5), TamError::StackOverflow)]
+#[case(instr!(PUSH -3), TamError::StackUnderflow)]
+fn test_exec_push_out_of_range_err(
+    mut emulator: TamEmulator,
+    #[case] instr: TamInstruction,
+    #[case] err: TamError,
+) {
+    set_test_data(&mut emulator, &[1, 2]);
+    emulator.registers[HT] = 6;
+
+    let res = emulator.exec_push(instr);
+
+    assert_eq!(err, res.unwrap_err());
+}
+
+#[rstest]
+fn test_exec_pop_keeps_result_ok(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[1, 2, 3, 4, 5]);
+
+    let instr = instr!(POP(2) 2);
+    let res = emulator.exec_pop(instr);
+
+    assert!(res.is_ok());
+    assert_eq!(3, emulator.registers[ST]);
+    assert_eq!([1, 4, 5], emulator.data_store[..3]);
+}
+
+#[rstest]
+fn test_exec_pop_not_enough_data_stack_underflow(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[1, 2]);
+
+    let instr = instr!(POP(1) 2);
+    let res = emulator.exec_pop(instr);
+
+    assert_eq!(TamError::StackUnderflow, res.unwrap_err());
+}
+
+#[rstest]
+fn test_exec_jump_ok(mut emulator: TamEmulator) {
+    emulator.registers[CT] = 20;
+
+    let instr = instr!(JUMP 9[CB]);
+    let res = emulator.exec_jump(instr);
+
+    assert!(res.is_ok());
+    assert_eq!(9, emulator.registers[CP]);
+}
+
+#[rstest]
+fn test_exec_jump_invalid_target_code_access_violation(mut emulator: TamEmulator) {
+    emulator.registers[CT] = 20;
+
+    let instr = instr!(JUMP 20[CB]);
+    let res = emulator.exec_jump(instr);
+
+    assert_eq!(TamError::CodeAccessViolation, res.unwrap_err());
+}
+
+#[rstest]
+fn test_exec_jumpi_ok(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[11]);
+    emulator.registers[CT] = 20;
+
+    let res = emulator.exec_jumpi();
+
+    assert!(res.is_ok());
+    assert_eq!(11, emulator.registers[CP]);
+    assert_eq!(0, emulator.registers[ST]);
+}
+
+#[rstest]
+#[case(1, instr!(JUMPIF(1) 9), 9)]
+#[case(0, instr!(JUMPIF(1) 9), 4)]
+#[case(0, instr!(JUMPIF(0) 9), 9)]
+fn test_exec_jumpif_ok(
+    mut emulator: TamEmulator,
+    #[case] value: i16,
+    #[case] instr: TamInstruction,
+    #[case] cp: u16,
+) {
+    set_test_data(&mut emulator, &[value]);
+    emulator.registers[CT] = 20;
+    emulator.registers[CP] = 4;
+
+    let res = emulator.exec_jumpif(instr);
+
+    assert!(res.is_ok());
+    assert_eq!(cp, emulator.registers[CP]);
+    assert_eq!(0, emulator.registers[ST]);
+}
+
+#[rstest]
+#[case(5, &[7], 8)]
+#[case(6, &[7], 6)]
+#[case(7, &[7], -7)]
+#[case(8, &[7, -3], 4)]
+#[case(9, &[7, -3], 10)]
+#[case(10, &[7, -3], -21)]
+#[case(11, &[-7, 2], -3)]
+#[case(12, &[-7, 2], -1)]
+fn test_exec_call_primitive_arithmetic_ok(
+    mut emulator: TamEmulator,
+    #[case] prim: i16,
+    #[case] data: &[i16],
+    #[case] expected: i16,
+) {
+    set_test_data(&mut emulator, data);
+
+    let res = emulator.exec_call_primitive(prim);
+
+    assert!(res.is_ok());
+    assert_eq!(1, emulator.registers[ST]);
+    assert_eq!(expected, emulator.data_store[0]);
+}
+
+#[rstest]
+#[case(13, &[-2, 3], 1)]
+#[case(13, &[3, 3], 0)]
+#[case(14, &[3, 3], 1)]
+#[case(15, &[3, -2], 1)]
+#[case(15, &[-2, 3], 0)]
+#[case(16, &[3, 3], 0)]
+#[case(17, &[4, 4, 1], 1)]
+#[case(17, &[1, 2, 1, 3, 2], 0)]
+#[case(17, &[1, 2, 1, 2, 2], 1)]
+#[case(18, &[1, 2, 1, 3, 2], 1)]
+#[case(18, &[0], 0)]
+fn test_exec_call_primitive_relational_ok(
+    mut emulator: TamEmulator,
+    #[case] prim: i16,
+    #[case] data: &[i16],
+    #[case] expected: i16,
+) {
+    set_test_data(&mut emulator, data);
+
+    let res = emulator.exec_call_primitive(prim);
+
+    assert!(res.is_ok());
+    assert_eq!(1, emulator.registers[ST]);
+    assert_eq!(expected, emulator.data_store[0]);
+}
+
+#[rstest]
+fn test_exec_call_primitive_eq_operands_missing_stack_underflow(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[1, 2, 2]);
+
+    assert_eq!(
+        TamError::StackUnderflow,
+        emulator.exec_call_primitive(17).unwrap_err()
+    );
+}
+
+#[rstest]
+#[case(5, "succ", &[i16::MAX], (i16::MAX as i32, 1), i16::MIN, i16::MAX)]
+#[case(6, "pred", &[i16::MIN], (i16::MIN as i32, 1), i16::MAX, i16::MIN)]
+#[case(7, "neg", &[i16::MIN], (0, i16::MIN as i32), i16::MIN, i16::MAX)]
+#[case(8, "add", &[i16::MAX, 2], (i16::MAX as i32, 2), i16::MIN + 1, i16::MAX)]
+#[case(9, "sub", &[i16::MIN, 2], (i16::MIN as i32, 2), i16::MAX - 1, i16::MIN)]
+#[case(10, "mult", &[300, -300], (300, -300), -24464, i16::MIN)]
+#[case(11, "div", &[i16::MIN, -1], (i16::MIN as i32, -1), i16::MIN, i16::MAX)]
+fn test_exec_call_primitive_overflow_by_mode(
+    mut emulator: TamEmulator,
+    #[case] prim: i16,
+    #[case] operation: &str,
+    #[case] data: &[i16],
+    #[case] operands: (i32, i32),
+    #[case] wrapped: i16,
+    #[case] saturated: i16,
+) {
+    set_test_data(&mut emulator, data);
+    emulator.exec_call_primitive(prim).unwrap();
+    assert_eq!(wrapped, emulator.data_store[0]);
+
+    emulator.set_arithmetic_mode(ArithmeticMode::Saturating);
+    set_test_data(&mut emulator, data);
+    emulator.exec_call_primitive(prim).unwrap();
+    assert_eq!(saturated, emulator.data_store[0]);
+
+    emulator.set_arithmetic_mode(ArithmeticMode::Trapping);
+    set_test_data(&mut emulator, data);
+    match emulator.exec_call_primitive(prim) {
+        Err(TamError::ArithmeticOverflow {
+            operation: op,
+            lhs,
+            rhs,
+        }) => {
+            assert_eq!(operation, op);
+            assert_eq!(operands, (lhs, rhs));
+        }
+        res => panic!("expected an arithmetic overflow, got {res:?}"),
+    }
+}
+
+#[rstest]
+#[case(11)]
+#[case(12)]
+fn test_exec_call_primitive_divide_by_zero_err(
+    mut emulator: TamEmulator,
+    #[case] prim: i16,
+    #[values(
+        ArithmeticMode::Wrapping,
+        ArithmeticMode::Trapping,
+        ArithmeticMode::Saturating
+    )]
+    mode: ArithmeticMode,
+) {
+    emulator.set_arithmetic_mode(mode);
+    set_test_data(&mut emulator, &[5, 0]);
+
+    let res = emulator.exec_call_primitive(prim);
+
+    assert_eq!(TamError::DivisionByZero, res.unwrap_err());
+}
+
+#[rstest]
+#[case(ArithmeticMode::Wrapping, Ok(u16::MAX))]
+#[case(ArithmeticMode::Saturating, Ok(0))]
+#[case(ArithmeticMode::Trapping, Err(TamError::ArithmeticOverflow { operation: "address", lhs: 2, rhs: -3 }))]
+fn test_calc_address_out_of_range_by_mode(
+    mut emulator: TamEmulator,
+    #[case] mode: ArithmeticMode,
+    #[case] expected: TamResult<u16>,
+) {
+    emulator.set_arithmetic_mode(mode);
+    emulator.registers[SB] = 2;
+
+    let instr = instr!(LOAD(1) - 3[SB]);
+
+    assert_eq!(expected, emulator.calc_address(instr));
+}
+
+#[rstest]
+#[case(LB, 9)]
+#[case(L1, 5)]
+#[case(L2, 1)]
+fn test_register_follows_static_chain(
+    mut emulator: TamEmulator,
+    #[case] r: usize,
+    #[case] expected: u16,
+) {
+    // frames at 1, 5 and 9, each with a static link to the one before
+    set_test_data(&mut emulator, &[0, 0, 0, 0, 0, 1, 0, 0, 0, 5, 0, 0]);
+    emulator.registers[LB] = 9;
+
+    assert_eq!(expected, emulator.register(r));
+}
+
+#[rstest]
+fn test_exec_call_primitive_new_and_dispose_ok(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[3]);
+    emulator.registers[CP] = 5;
+
+    assert!(emulator.exec_call_primitive(27).is_ok());
+    assert_eq!(1, emulator.registers[ST]);
+    assert_eq!(0xfffd_u16 as i16, emulator.data_store[0]);
+    assert_eq!(0xfffc, emulator.registers[HT]);
+
+    // dispose(3, address)
+    set_test_data(&mut emulator, &[3, 0xfffd_u16 as i16]);
+    assert!(emulator.exec_call_primitive(28).is_ok());
+    assert_eq!(0, emulator.registers[ST]);
+    assert_eq!(0xffff, emulator.registers[HT]);
+}
+
+#[rstest]
+#[case(0)]
+#[case(-5)]
+fn test_exec_call_primitive_new_size_not_positive_invalid_allocation_size(
+    mut emulator: TamEmulator,
+    #[case] size: i16,
+) {
+    set_test_data(&mut emulator, &[size]);
+
+    assert_eq!(
+        TamError::InvalidAllocationSize(size),
+        emulator.exec_call_primitive(27).unwrap_err()
+    );
+    assert_eq!(0xffff, emulator.registers[HT]);
+}
+
+#[rstest]
+fn test_exec_call_primitive_dispose_not_allocated_invalid_dispose(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[3, 0xfffd_u16 as i16]);
+
+    assert_eq!(
+        TamError::InvalidDispose {
+            address: 0xfffd,
+            size: 3
+        },
+        emulator.exec_call_primitive(28).unwrap_err()
+    );
+}
+
+#[rstest]
+fn test_exec_call_primitive_new_collides_with_stack_out_of_memory(mut emulator: TamEmulator) {
+    set_test_data(&mut emulator, &[3]);
+    emulator.registers[HT] = 2;
+
+    assert_eq!(
+        TamError::OutOfMemory,
+        emulator.exec_call_primitive(27).unwrap_err()
+    );
+}
+
+#[rstest]
+#[case(19, "\nx", 1)]
+#[case(19, "x\n", 0)]
+#[case(19, "", 0)]
+#[case(20, "", 1)]
+#[case(20, "x", 0)]
+fn test_exec_call_primitive_input_tests_ok(
+    mut emulator: TamEmulator,
+    #[case] prim: i16,
+    #[case] input: &str,
+    #[case] expected: i16,
+) {
+    let io = MemoryIo::new(input);
+    emulator.set_io(io.clone());
+    set_test_data(&mut emulator, &[]);
+
+    assert!(emulator.exec_call_primitive(prim).is_ok());
+    assert_eq!(1, emulator.registers[ST]);
+    assert_eq!(expected, emulator.data_store[0]);
+    assert_eq!(input.as_bytes(), io.remaining_input());
+}
+
+#[rstest]
+#[case(21, "ab", 'a' as i16, "b")]
+#[case(21, "", -1, "")]
+#[case(25, " -12\n3", -12, "\n3")]
+fn test_exec_call_primitive_get_stores_input(
+    mut emulator: TamEmulator,
+    #[case] prim: i16,
+    #[case] input: &str,
+    #[case] expected: i16,
+    #[case] remaining: &str,
+) {
+    let io = MemoryIo::new(input);
+    emulator.set_io(io.clone());
+    // a variable at 0[SB], with its address on the stack
+    set_test_data(&mut emulator, &[0, 0]);
+
+    assert!(emulator.exec_call_primitive(prim).is_ok());
+    assert_eq!(1, emulator.registers[ST]);
+    assert_eq!(expected, emulator.data_store[0]);
+    assert_eq!(remaining.as_bytes(), io.remaining_input());
+}
+
+#[rstest]
+fn test_exec_call_primitive_getint_not_a_number_io_error(mut emulator: TamEmulator) {
+    emulator.set_io(MemoryIo::new("abc"));
+    set_test_data(&mut emulator, &[0, 0]);
+
+    assert_eq!(
+        TamError::IOError,
+        emulator.exec_call_primitive(25).unwrap_err()
+    );
+}
+
+#[rstest]
+fn test_exec_call_primitive_get_into_free_space_data_access_violation(mut emulator: TamEmulator) {
+    emulator.set_io(MemoryIo::new("a"));
+    set_test_data(&mut emulator, &[5]);
+
+    assert_eq!(
+        TamError::DataAccessViolation,
+        emulator.exec_call_primitive(21).unwrap_err()
+    );
+}
+
+#[rstest]
+fn test_exec_call_primitive_geteol_skips_line(mut emulator: TamEmulator) {
+    let io = MemoryIo::new("abc\ndef");
+    emulator.set_io(io.clone());
+
+    assert!(emulator.exec_call_primitive(23).is_ok());
+    assert_eq!(b"def".to_vec(), io.remaining_input());
+}
+
+#[rstest]
+fn test_exec_call_primitive_output_ok(mut emulator: TamEmulator) {
+    let io = MemoryIo::new("");
+    emulator.set_io(io.clone());
+
+    set_test_data(&mut emulator, &['x' as i16]);
+    assert!(emulator.exec_call_primitive(22).is_ok());
+    set_test_data(&mut emulator, &[-305]);
+    assert!(emulator.exec_call_primitive(26).is_ok());
+    assert!(emulator.exec_call_primitive(24).is_ok());
+
+    assert_eq!(0, emulator.registers[ST]);
+    assert_eq!("x-305\n", io.output_string());
+}
+
+/// The implementations of `STORE`, `STOREI` and `RETURN` before they were made to
+/// move words within the data store, which the current ones must behave exactly like.
+mod reference {
+    use super::*;
+
+    pub fn exec_store(emu: &mut TamEmulator, instr: TamInstruction) -> TamResult<()> {
+        let mut data = Vec::new();
+        for _ in 0..instr.n {
+            data.push(emu.pop()?);
+        }
+
+        let addr = emu.calc_address(instr)?;
+        for i in 0..instr.n {
+            let addr = emu.word_address(addr, i as u16)?;
+            if addr >= emu.registers[ST] && addr <= emu.registers[HT] {
+                return Err(TamError::DataAccessViolation);
+            }
+            emu.check_heap_access(addr)?;
+            emu.data_store[addr as usize] = data.pop().expect("unexpectedly stored too much data");
+            emu.mark_written(addr, true);
+        }
+        Ok(())
+    }
+
+    pub fn exec_storei(emu: &mut TamEmulator, instr: TamInstruction) -> TamResult<()> {
+        let addr = emu.pop()? as u16;
+
+        let mut data = Vec::new();
+        for _ in 0..instr.n {
+            data.push(emu.pop()?);
+        }
+
+        for i in 0..instr.n {
+            let addr = emu.word_address(addr, i as u16)?;
+            if addr >= emu.registers[ST] && addr <= emu.registers[HT] {
+                return Err(TamError::DataAccessViolation);
+            }
+            emu.check_heap_access(addr)?;
+            emu.data_store[addr as usize] = data.pop().expect("unexpectedly stored too much data");
+            emu.mark_written(addr, true);
+        }
+        Ok(())
+    }
+
+    pub fn exec_return(emu: &mut TamEmulator, instr: TamInstruction) -> TamResult<()> {
+        let mut return_val = Vec::new();
+        for _ in 0..instr.n {
+            return_val.push(emu.pop()?);
+        }
+
+        let lb = emu.registers[LB];
+        let dynamic_link = emu.data_store.get(lb as usize + 1).copied().unwrap_or(0);
+        let return_addr = emu.data_store.get(lb as usize + 2).copied().unwrap_or(0);
+        emu.check_frame(lb, dynamic_link, return_addr)?;
+
+        while emu.registers[ST] != emu.registers[LB] {
+            emu.pop()?;
+        }
+        for _ in 0..instr.d {
+            emu.pop()?;
+        }
+        for _ in 0..instr.n {
+            emu.push(
+                return_val
+                    .pop()
+                    .expect("return value had wrong number of bytes"),
+            )?;
+        }
+
+        emu.registers[LB] = dynamic_link as u16;
+        emu.registers[CP] = return_addr as u16;
+        emu.call_depth = emu.call_depth.saturating_sub(1);
+
+        Ok(())
+    }
+}
+
+type Exec = fn(&mut TamEmulator, TamInstruction) -> TamResult<()>;
+
+/// Everything an instruction can change.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    result: TamResult<()>,
+    registers: [u16; 16],
+    data_store: Vec<i16>,
+    shadow: Option<crate::shadow::ShadowMemory>,
+    call_depth: usize,
+}
+
+/// Executes `instr` on a stack holding `data`, with a heap when `heap` is set, and
+/// records the outcome.
+///
+/// The heap holds a live word at 0xffff, a disposed block at 0xfffd and a live block
+/// at 0xfffb.
+fn outcome(exec: Exec, instr: TamInstruction, data: &[i16], lb: u16, heap: bool) -> Outcome {
+    let mut emu = TamEmulator::new(false);
+    set_test_data(&mut emu, data);
+    emu.registers[LB] = lb;
+    emu.registers[CT] = 15;
+    emu.call_depth = 1;
+    emu.set_check_uninit(true);
+    for addr in 0..data.len() {
+        emu.mark_written(addr as u16, true);
+    }
+    if heap {
+        emu.set_check_heap(true);
+        emu.allocate(1).unwrap();
+        let disposed = emu.allocate(2).unwrap();
+        emu.allocate(2).unwrap();
+        emu.dispose(disposed, 2).unwrap();
+    }
+
+    let result = exec(&mut emu, instr);
+    Outcome {
+        result,
+        registers: emu.registers,
+        data_store: emu.data_store.to_vec(),
+        shadow: emu.shadow.clone(),
+        call_depth: emu.call_depth,
+    }
+}
+
+#[rstest]
+#[case::store(STORE, 2, 0, &[1, 2, 3, 4], false)]
+#[case::store_nothing(STORE, 0, 0, &[1], false)]
+#[case::store_across_st(STORE, 3, 1, &[1, 2, 3, 4, 5], false)]
+#[case::store_outside_memory(STORE, 1, -1, &[1, 2], false)]
+#[case::store_stack_underflow(STORE, 4, 0, &[1, 2], false)]
+#[case::storei(STOREI, 2, 0, &[0, 0, 7, 8, 0], false)]
+#[case::storei_above_st(STOREI, 2, 0, &[7, 8, 5], false)]
+#[case::store_past_end_of_memory(STORE, 2, -1, &[7, 8], true)]
+#[case::storei_into_disposed_block(STOREI, 2, 0, &[7, 8, 0xfffc_u16 as i16], true)]
+#[case::storei_past_end_of_memory(STOREI, 2, 0, &[7, 8, -1], true)]
+#[case::storei_stack_underflow(STOREI, 2, 0, &[9], false)]
+#[case::storei_no_address(STOREI, 1, 0, &[], false)]
+fn test_exec_store_matches_reference(
+    #[case] op: u8,
+    #[case] n: u8,
+    #[case] d: i16,
+    #[case] data: &[i16],
+    #[case] heap: bool,
+) {
+    let instr = TamInstruction::from(asm::encode(op, SB as u8, n, d));
+    let (exec, reference): (Exec, Exec) = if op == STORE {
+        (TamEmulator::exec_store, reference::exec_store)
+    } else {
+        (TamEmulator::exec_storei, reference::exec_storei)
+    };
+
+    assert_eq!(
+        outcome(reference, instr, data, 0, heap),
+        outcome(exec, instr, data, 0, heap)
+    );
+}
+
+#[rstest]
+#[case::result_and_args(1, 2, &[1, 2, 0, 0, 7, 2, 4], 2)]
+#[case::result_above_locals(2, 0, &[5, 0, 0, 3, 9, 8, 7], 1)]
+#[case::result_overlapping_frame(4, 0, &[0, 0, 3, 1, 2, 3, 4], 0)]
+#[case::negative_args(1, -1, &[0, 0, 3, 6], 0)]
+#[case::corrupt_frame(0, 0, &[0, 0, 0, 40, 4], 1)]
+#[case::missing_args(0, 2, &[0, 0, 3], 0)]
+#[case::missing_result(5, 0, &[0, 0, 3], 0)]
+fn test_exec_return_matches_reference(
+    #[case] n: u8,
+    #[case] d: i16,
+    #[case] data: &[i16],
+    #[case] lb: u16,
+) {
+    let instr = TamInstruction::from(asm::encode(RETURN, 0, n, d));
+
+    assert_eq!(
+        outcome(reference::exec_return, instr, data, lb, false),
+        outcome(TamEmulator::exec_return, instr, data, lb, false)
+    );
+}
@@ -0,0 +1,430 @@
+//! Diagrams of the activation records on the stack, as text or Graphviz DOT.
+//!
+//! Each record is laid out as `CALL` builds it: the arguments pushed by the caller
+//! just below `LB`, then the static link, dynamic link and return address, then the
+//! routine's locals. The global frame holds the variables of the main program.
+
+use crate::{LB, SB, ST, TamEmulator, debug_info::DebugInfo};
+use std::{fmt::Write, ops::Range};
+
+/// A word of an activation record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slot {
+    pub address: u16,
+    pub value: i16,
+    /// Variable held in the word, such as `n: Integer`, if the debug info names it
+    pub label: Option<String>,
+}
+
+/// An activation record on the stack.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActivationRecord {
+    /// Position in the backtrace, where the innermost record is 0
+    pub index: usize,
+    /// Name of the routine, or `None` for the global frame or an unknown routine
+    pub name: Option<String>,
+    pub lb: u16,
+    /// Static link, dynamic link and return address, or `None` for the global frame
+    pub links: Option<[u16; 3]>,
+    /// Arguments named in the debug info, which lie below `LB`
+    pub arguments: Vec<Slot>,
+    /// Words above the link data, or the globals for the global frame
+    pub locals: Vec<Slot>,
+}
+
+/// The activation records on the stack, outermost first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackDiagram {
+    pub records: Vec<ActivationRecord>,
+}
+
+const LINK_NAMES: [&str; 3] = ["static link", "dynamic link", "return address"];
+
+fn slots(
+    emu: &TamEmulator,
+    words: Range<u16>,
+    register: usize,
+    scope: Option<&str>,
+    base: u16,
+) -> Vec<Slot> {
+    words
+        .map(|address| Slot {
+            address,
+            value: emu.data_store[address as usize],
+            label: emu
+                .debug_info()
+                .and_then(|info| variable_label(info, scope, register, base, address)),
+        })
+        .collect()
+}
+
+/// Names the variable of `scope` held at `address`, when the variables are addressed
+/// relative to `register` holding `base`.
+fn variable_label(
+    info: &DebugInfo,
+    scope: Option<&str>,
+    register: usize,
+    base: u16,
+    address: u16,
+) -> Option<String> {
+    info.variables(scope)
+        .filter(|var| var.register as usize == register)
+        .find_map(|var| {
+            let offset = address as i32 - (base as i32 + var.offset as i32);
+            (0..var.size as i32).contains(&offset).then(|| {
+                if offset == 0 {
+                    format!("{}: {}", var.name, var.ty)
+                } else {
+                    format!("{}+{offset}", var.name)
+                }
+            })
+        })
+}
+
+impl TamEmulator {
+    /// Builds a diagram of the activation records currently on the stack.
+    ///
+    /// Routines and their variables are named from the debug info, if any. Without
+    /// it the arguments of each routine are unknown, and are shown only as part of
+    /// the caller's frame.
+    pub fn stack_diagram(&self) -> StackDiagram {
+        let mut frames = self.backtrace();
+        frames.reverse();
+        let st = self.registers[ST];
+        let info = self.debug_info();
+
+        let mut records = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let index = frames.len() - i - 1;
+            let end = frames.get(i + 1).map_or(st, |f| f.lb).max(frame.lb);
+            if frame.static_link.is_none() {
+                let sb = self.registers[SB];
+                records.push(ActivationRecord {
+                    index,
+                    name: None,
+                    lb: frame.lb,
+                    links: None,
+                    arguments: Vec::new(),
+                    locals: slots(self, sb..end, SB, None, sb),
+                });
+                continue;
+            }
+
+            let name = info
+                .and_then(|info| info.procedure(frame.code_address))
+                .map(|p| p.name.clone());
+            let scope = name.as_deref();
+            let lb = frame.lb as usize;
+            let links = [0, 1, 2].map(|i| self.data_store[lb + i] as u16);
+            let arguments = match (info, scope) {
+                (Some(info), Some(scope)) => {
+                    let mut addresses: Vec<u16> = info
+                        .variables(Some(scope))
+                        .filter(|var| var.register as usize == LB && var.offset < 0)
+                        .flat_map(|var| {
+                            let start = frame.lb.wrapping_add_signed(var.offset);
+                            (0..var.size as u16).map(move |i| start.wrapping_add(i))
+                        })
+                        .collect();
+                    addresses.sort_unstable();
+                    addresses
+                        .into_iter()
+                        .flat_map(|a| slots(self, a..a + 1, LB, Some(scope), frame.lb))
+                        .collect()
+                }
+                _ => Vec::new(),
+            };
+            let locals = slots(self, (frame.lb + 3).min(end)..end, LB, scope, frame.lb);
+            records.push(ActivationRecord {
+                index,
+                name,
+                lb: frame.lb,
+                links: Some(links),
+                arguments,
+                locals,
+            });
+        }
+        StackDiagram { records }
+    }
+}
+
+impl ActivationRecord {
+    fn title(&self) -> String {
+        match (&self.links, &self.name) {
+            (None, _) => format!("#{} main program", self.index),
+            (Some(_), Some(name)) => format!("#{} {name}", self.index),
+            (Some(_), None) => format!("#{} ?", self.index),
+        }
+    }
+}
+
+impl StackDiagram {
+    /// Finds the record a link in the record at `pos` points to, which is the nearest
+    /// record outside it whose frame starts at `lb`.
+    fn link_target(&self, pos: usize, lb: u16) -> Option<&ActivationRecord> {
+        self.records[..pos].iter().rev().find(|r| r.lb == lb)
+    }
+
+    /// Draws the records as boxes, outermost first, with each link followed by the
+    /// record it points to.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for (pos, record) in self.records.iter().enumerate() {
+            let mut rows = Vec::new();
+            for slot in &record.arguments {
+                let label = slot.label.as_deref().unwrap_or("");
+                rows.push(format!(
+                    "{:#06x}  {:<20} {:>6}  argument",
+                    slot.address, label, slot.value
+                ));
+            }
+            if let Some(links) = &record.links {
+                for (i, (name, value)) in LINK_NAMES.iter().zip(links).enumerate() {
+                    let target = match i {
+                        2 => String::new(),
+                        _ => match self.link_target(pos, *value) {
+                            Some(target) => format!("  -> {}", target.title()),
+                            None => String::from("  -> ?"),
+                        },
+                    };
+                    rows.push(format!(
+                        "{:#06x}  {name:<20} {value:#06x}{target}",
+                        record.lb + i as u16
+                    ));
+                }
+            }
+            for slot in &record.locals {
+                let label = slot.label.as_deref().unwrap_or("");
+                rows.push(format!(
+                    "{:#06x}  {label:<20} {:>6}",
+                    slot.address, slot.value
+                ));
+            }
+
+            let title = match record.links {
+                Some(_) => format!("{}  LB={:#06x}", record.title(), record.lb),
+                None => record.title(),
+            };
+            let width = rows
+                .iter()
+                .chain([&title])
+                .map(|r| r.len())
+                .max()
+                .unwrap_or(0);
+            let rule = format!("+{}+\n", "-".repeat(width + 2));
+            out.push_str(&rule);
+            let _ = writeln!(out, "| {title:<width$} |");
+            out.push_str(&rule);
+            for row in &rows {
+                let _ = writeln!(out, "| {row:<width$} |");
+            }
+            out.push_str(&rule);
+        }
+        out
+    }
+
+    /// Writes the records in Graphviz DOT format, with solid edges for dynamic links
+    /// and dashed edges for static links.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from(
+            "digraph stack {\n    rankdir=LR;\n    node [shape=record, fontname=\"monospace\"];\n",
+        );
+        for record in &self.records {
+            let mut fields = vec![escape(&record.title())];
+            for slot in &record.arguments {
+                fields.push(escape(&format!(
+                    "{:#06x} {} = {} (argument)",
+                    slot.address,
+                    slot.label.as_deref().unwrap_or("?"),
+                    slot.value
+                )));
+            }
+            if let Some(links) = &record.links {
+                for (i, (name, value)) in LINK_NAMES.iter().zip(links).enumerate() {
+                    fields.push(format!(
+                        "<l{i}> {:#06x} {name} = {value:#06x}",
+                        record.lb + i as u16
+                    ));
+                }
+            }
+            for slot in &record.locals {
+                let label = match &slot.label {
+                    Some(label) => format!("{label} = {}", slot.value),
+                    None => slot.value.to_string(),
+                };
+                fields.push(escape(&format!("{:#06x} {label}", slot.address)));
+            }
+            let _ = writeln!(
+                dot,
+                "    f{} [label=\"{{{}}}\"];",
+                record.index,
+                fields.join("|")
+            );
+        }
+
+        for (pos, record) in self.records.iter().enumerate() {
+            let Some([static_link, dynamic_link, _]) = record.links else {
+                continue;
+            };
+            if let Some(target) = self.link_target(pos, dynamic_link) {
+                let _ = writeln!(dot, "    f{}:l1 -> f{};", record.index, target.index);
+            }
+            if let Some(target) = self.link_target(pos, static_link) {
+                let _ = writeln!(
+                    dot,
+                    "    f{}:l0 -> f{} [style=dashed];",
+                    record.index, target.index
+                );
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Escapes text for a quoted DOT label.
+///
+/// As well as quotes and backslashes, this escapes the characters that have a meaning
+/// in a record label, which a plain label shows unchanged.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CP, asm,
+        debug_info::{Procedure, Variable},
+        tam,
+    };
+    use rstest::*;
+
+    fn variable(scope: Option<&str>, name: &str, register: usize, offset: i16) -> Variable {
+        Variable {
+            scope: scope.map(String::from),
+            name: String::from(name),
+            register: register as u8,
+            offset,
+            size: 1,
+            ty: String::from("Integer"),
+        }
+    }
+
+    /// Stops inside a call to `f(9)` from a program with one global.
+    #[fixture]
+    fn emulator() -> TamEmulator {
+        let code = tam! {
+            PUSH 1;
+            LOADL 9;
+            CALL(SB) 4[CB];
+            HALT;
+            PUSH 1;
+            HALT;
+        };
+        let bytes = asm::to_bytes(&code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+        while emu.registers[CP] != 5 {
+            let instr = emu.fetch_decode().unwrap();
+            emu.execute(instr).unwrap();
+        }
+
+        let mut info = DebugInfo::default();
+        info.add_procedure(Procedure {
+            name: String::from("f"),
+            start: 4,
+            end: 6,
+        });
+        info.add_variable(variable(None, "g", SB, 0));
+        info.add_variable(variable(Some("f"), "n", LB, -1));
+        info.add_variable(variable(Some("f"), "r", LB, 3));
+        emu.set_debug_info(info);
+        emu
+    }
+
+    #[rstest]
+    fn test_stack_diagram_records(emulator: TamEmulator) {
+        let diagram = emulator.stack_diagram();
+
+        assert_eq!(2, diagram.records.len());
+        let f = &diagram.records[1];
+        assert_eq!((0, Some("f"), 2), (f.index, f.name.as_deref(), f.lb));
+        assert_eq!(Some([0, 0, 3]), f.links);
+        assert_eq!(
+            vec![Slot {
+                address: 1,
+                value: 9,
+                label: Some(String::from("n: Integer"))
+            }],
+            f.arguments
+        );
+        assert_eq!(
+            vec![5],
+            f.locals.iter().map(|s| s.address).collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    fn test_stack_diagram_to_text(emulator: TamEmulator) {
+        let expected = "\
++-------------------------------------+
+| #1 main program                     |
++-------------------------------------+
+| 0x0000  g: Integer                0 |
+| 0x0001                            9 |
++-------------------------------------+
++---------------------------------------------------------+
+| #0 f  LB=0x0002                                         |
++---------------------------------------------------------+
+| 0x0001  n: Integer                9  argument           |
+| 0x0002  static link          0x0000  -> #1 main program |
+| 0x0003  dynamic link         0x0000  -> #1 main program |
+| 0x0004  return address       0x0003                     |
+| 0x0005  r: Integer                0                     |
++---------------------------------------------------------+
+";
+        assert_eq!(expected, emulator.stack_diagram().to_text());
+    }
+
+    #[rstest]
+    fn test_stack_diagram_to_dot(emulator: TamEmulator) {
+        let expected = "\
+digraph stack {
+    rankdir=LR;
+    node [shape=record, fontname=\"monospace\"];
+    f1 [label=\"{#1 main program|0x0000 g: Integer = 0|0x0001 9}\"];
+    f0 [label=\"{#0 f|0x0001 n: Integer = 9 (argument)|<l0> 0x0002 static link = 0x0000|<l1> 0x0003 dynamic link = 0x0000|<l2> 0x0004 return address = 0x0003|0x0005 r: Integer = 0}\"];
+    f0:l1 -> f1;
+    f0:l0 -> f1 [style=dashed];
+}
+";
+        assert_eq!(expected, emulator.stack_diagram().to_dot());
+    }
+
+    #[rstest]
+    fn test_stack_diagram_without_debug_info() {
+        let code = tam! { CALL(SB) 1[CB]; HALT };
+        let bytes = asm::to_bytes(&code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+        let instr = emu.fetch_decode().unwrap();
+        emu.execute(instr).unwrap();
+
+        let diagram = emu.stack_diagram();
+        assert_eq!(None, diagram.records[1].name);
+        assert!(diagram.records[1].arguments.is_empty());
+        assert!(
+            diagram
+                .to_dot()
+                .contains("    f0:l1 -> f1;\n    f0:l0 -> f1 [style=dashed];\n")
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! Conservative mark-and-sweep garbage collection of the heap.
+//!
+//! TAM words carry no type, so the collector treats every word on the stack, from
+//! `SB` up to `ST`, as a possible pointer. Any block containing the address such a word
+//! holds is reachable, as is any block containing an address held in a reachable
+//! block. Every other live block is freed as if it had been disposed. A word that only
+//! happens to look like a heap address keeps its block alive, but a block that is
+//! reachable is never freed.
+
+use crate::{HT, SB, ST, TamEmulator, heap::Heap};
+use std::collections::BTreeSet;
+
+/// Counts of the work done by the garbage collector.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: u32,
+    pub blocks_freed: u32,
+    pub words_freed: u32,
+}
+
+impl Heap {
+    /// Gets the work done by the garbage collector, or `None` if it is disabled.
+    pub fn gc_stats(&self) -> Option<GcStats> {
+        self.gc
+    }
+
+    /// Finds the address of the live block containing `address`.
+    fn block_containing(&self, address: u16) -> Option<u16> {
+        let (&start, block) = self.live.range(..=address).next_back()?;
+        ((address - start) < block.size).then_some(start)
+    }
+}
+
+impl TamEmulator {
+    /// Enables or disables garbage collection of the heap.
+    ///
+    /// While enabled, a `new` that would otherwise run out of memory first frees every
+    /// block the program can no longer reach, so programs need not call `dispose`.
+    pub fn set_garbage_collection(&mut self, enabled: bool) {
+        self.heap.gc = enabled.then(GcStats::default);
+    }
+
+    /// Frees every heap block that cannot be reached from the stack, and returns the
+    /// number of blocks freed.
+    pub fn collect_garbage(&mut self) -> usize {
+        let mut reachable = BTreeSet::new();
+        let mut pending = Vec::new();
+        let roots = self.registers[SB]..self.registers[ST];
+        self.mark(roots, &mut reachable, &mut pending);
+        while let Some(start) = pending.pop() {
+            let block = self.heap.live[&start];
+            self.mark(
+                (0..block.size).map(|i| start + i),
+                &mut reachable,
+                &mut pending,
+            );
+        }
+
+        let garbage: Vec<_> = self
+            .heap
+            .live
+            .values()
+            .filter(|block| !reachable.contains(&block.address))
+            .copied()
+            .collect();
+        for block in &garbage {
+            self.heap.live.remove(&block.address);
+            self.free_block(*block);
+        }
+
+        if let Some(stats) = &mut self.heap.gc {
+            stats.collections += 1;
+            stats.blocks_freed += garbage.len() as u32;
+            stats.words_freed += garbage.iter().map(|b| b.size as u32).sum::<u32>();
+        }
+        garbage.len()
+    }
+
+    /// Marks the blocks pointed into by the words in `words` as reachable, adding any
+    /// not already marked to `pending` so their own words are scanned.
+    fn mark(
+        &self,
+        words: impl Iterator<Item = u16>,
+        reachable: &mut BTreeSet<u16>,
+        pending: &mut Vec<u16>,
+    ) {
+        for addr in words {
+            let word = self.data_store[addr as usize] as u16;
+            if word <= self.registers[HT] {
+                continue;
+            }
+            if let Some(start) = self.heap.block_containing(word)
+                && reachable.insert(start)
+            {
+                pending.push(start);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HB, asm, errors::TamError, tam};
+    use rstest::*;
+
+    #[fixture]
+    fn emulator() -> TamEmulator {
+        let mut emu = TamEmulator::new(false);
+        emu.set_garbage_collection(true);
+        emu
+    }
+
+    #[rstest]
+    fn test_collect_garbage_keeps_blocks_reachable_from_stack(mut emulator: TamEmulator) {
+        let a = emulator.allocate(2).unwrap();
+        let b = emulator.allocate(2).unwrap();
+        let c = emulator.allocate(2).unwrap();
+        // the stack points into the middle of a, which points to c
+        emulator.data_store[0] = (a + 1) as i16;
+        emulator.data_store[a as usize] = c as i16;
+        emulator.registers[ST] = 1;
+
+        assert_eq!(1, emulator.collect_garbage());
+        assert_eq!(
+            vec![c, a],
+            emulator
+                .heap()
+                .live_blocks()
+                .map(|b| b.address)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![(b, 2)],
+            emulator.heap().free_blocks().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(GcStats {
+                collections: 1,
+                blocks_freed: 1,
+                words_freed: 2
+            }),
+            emulator.heap().gc_stats()
+        );
+    }
+
+    #[rstest]
+    fn test_collect_garbage_frees_unreachable_cycle(mut emulator: TamEmulator) {
+        let a = emulator.allocate(1).unwrap();
+        let b = emulator.allocate(1).unwrap();
+        emulator.data_store[a as usize] = b as i16;
+        emulator.data_store[b as usize] = a as i16;
+
+        assert_eq!(2, emulator.collect_garbage());
+        assert_eq!(0, emulator.heap().live_blocks().count());
+        assert_eq!(emulator.registers[HB], emulator.registers[HT]);
+    }
+
+    #[rstest]
+    #[case(true, Ok(()))]
+    #[case(false, Err(TamError::OutOfMemory))]
+    fn test_new_in_loop_without_dispose(
+        mut emulator: TamEmulator,
+        #[case] gc: bool,
+        #[case] expected: Result<(), TamError>,
+    ) {
+        // allocate 1000 words 200 times, dropping each block
+        let code = tam! {
+            LOADL 200;
+            LOADL 1000;
+            CALL(CB) new[PB];
+            POP(0) 1;
+            CALL(CB) pred[PB];
+            LOAD(1) -1[ST];
+            JUMPIF(0) 8[CB];
+            JUMP 1[CB];
+            HALT;
+        };
+        let bytes = asm::to_bytes(&code);
+        emulator.set_program(&bytes).unwrap();
+        emulator.set_garbage_collection(gc);
+
+        let mut result = Ok(());
+        loop {
+            match emulator
+                .fetch_decode()
+                .and_then(|instr| emulator.execute(instr))
+            {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        assert_eq!(expected, result);
+    }
+}
@@ -0,0 +1,579 @@
+//! The heap allocator behind the `new` and `dispose` primitives, and reports on the
+//! blocks it holds.
+//!
+//! The heap occupies the words above `HT` up to and including `HB`, and grows down
+//! towards the stack. `new` reuses the lowest free block large enough for the
+//! request, and otherwise lowers `HT`. Disposed blocks are merged with adjacent free
+//! blocks, and free space at the bottom of the heap is given back by raising `HT`.
+//!
+//! In checked mode disposed blocks are not reused straight away. They are filled with
+//! [`POISON`] and kept in quarantine, so that a load, store or `dispose` through a
+//! dangling pointer can be reported along with where the block was allocated and
+//! disposed. The quarantine is only released when `new` would otherwise run out of
+//! memory.
+
+use crate::{
+    CP, HT, ST, TamEmulator,
+    debug_info::DebugInfo,
+    errors::{TamError, TamResult},
+    gc::GcStats,
+};
+use std::{collections::BTreeMap, fmt::Write};
+
+/// A block allocated by `new`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Block {
+    pub address: u16,
+    /// Size in words
+    pub size: u16,
+    /// Code address of the call to `new` that allocated the block
+    pub site: u16,
+}
+
+/// Value written to every word of a block disposed in checked mode.
+pub const POISON: i16 = 0xdead_u16 as i16;
+
+/// A block disposed in checked mode and held in quarantine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DisposedBlock {
+    pub block: Block,
+    /// Code address of the call to `dispose` that freed the block
+    pub disposed_at: u16,
+}
+
+/// The blocks allocated from the heap and the free space between them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Heap {
+    pub(crate) live: BTreeMap<u16, Block>,
+    /// Size of each free block, by address
+    free: BTreeMap<u16, u16>,
+    /// Work done by the garbage collector, or `None` if it is disabled
+    pub(crate) gc: Option<GcStats>,
+    /// Blocks disposed in checked mode, or `None` if checking is disabled
+    quarantine: Option<BTreeMap<u16, DisposedBlock>>,
+}
+
+/// Describes a code address, in source terms where the debug info allows.
+fn describe_site(site: u16, debug_info: Option<&DebugInfo>) -> String {
+    match debug_info.and_then(|info| info.describe(site)) {
+        Some(loc) => format!("{site:#06x}, {loc}"),
+        None => format!("{site:#06x}"),
+    }
+}
+
+fn plural(n: usize, word: &str) -> String {
+    if n == 1 {
+        format!("{n} {word}")
+    } else {
+        format!("{n} {word}s")
+    }
+}
+
+impl Heap {
+    /// Gets the blocks that have been allocated and not disposed, in address order.
+    pub fn live_blocks(&self) -> impl Iterator<Item = &Block> {
+        self.live.values()
+    }
+
+    /// Gets the live block starting at `address`.
+    pub fn block_at(&self, address: u16) -> Option<&Block> {
+        self.live.get(&address)
+    }
+
+    /// Gets the blocks disposed in checked mode that are still in quarantine, in
+    /// address order.
+    pub fn disposed_blocks(&self) -> impl Iterator<Item = &DisposedBlock> {
+        self.quarantine.iter().flat_map(|q| q.values())
+    }
+
+    /// Finds the quarantined block containing `address`.
+    fn disposed_block_containing(&self, address: u16) -> Option<&DisposedBlock> {
+        let (&start, disposed) = self.quarantine.as_ref()?.range(..=address).next_back()?;
+        (address - start < disposed.block.size).then_some(disposed)
+    }
+
+    /// Gets the address and size of each free block between live blocks, in address
+    /// order.
+    pub fn free_blocks(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.free.iter().map(|(&address, &size)| (address, size))
+    }
+
+    /// Gets the proportion of free words that lie outside the largest free block, from
+    /// 0 when free space is contiguous towards 1 when it is split into many pieces.
+    pub fn fragmentation(&self) -> f64 {
+        let total: u32 = self.free.values().map(|&size| size as u32).sum();
+        match self.free.values().max() {
+            Some(&largest) if total > 0 => 1.0 - largest as f64 / total as f64,
+            _ => 0.0,
+        }
+    }
+
+    /// Lists the live and free blocks, with the allocation site of each live block.
+    pub fn report(&self, debug_info: Option<&DebugInfo>) -> String {
+        let live_words: usize = self.live.values().map(|b| b.size as usize).sum();
+        let free_words: usize = self.free.values().map(|&size| size as usize).sum();
+        let mut out = format!(
+            "heap: {} ({}) live, {} ({}) free, {:.0}% fragmentation\n",
+            plural(self.live.len(), "block"),
+            plural(live_words, "word"),
+            plural(self.free.len(), "block"),
+            plural(free_words, "word"),
+            self.fragmentation() * 100.0
+        );
+        if let Some(gc) = &self.gc {
+            let _ = writeln!(
+                out,
+                "garbage collector: {} freed {} ({})",
+                plural(gc.collections as usize, "collection"),
+                plural(gc.blocks_freed as usize, "block"),
+                plural(gc.words_freed as usize, "word")
+            );
+        }
+
+        if !self.live.is_empty() {
+            out.push_str("live blocks:\n");
+        }
+        for block in self.live.values() {
+            let _ = writeln!(
+                out,
+                "  {:#06x}  {:>5} words  allocated at {}",
+                block.address,
+                block.size,
+                describe_site(block.site, debug_info)
+            );
+        }
+        if !self.free.is_empty() {
+            out.push_str("free blocks:\n");
+        }
+        for (address, size) in self.free_blocks() {
+            let _ = writeln!(out, "  {address:#06x}  {size:>5} words");
+        }
+        if self.disposed_blocks().next().is_some() {
+            out.push_str("disposed blocks in quarantine:\n");
+        }
+        for disposed in self.disposed_blocks() {
+            let _ = writeln!(
+                out,
+                "  {:#06x}  {:>5} words  allocated at {}, disposed at {}",
+                disposed.block.address,
+                disposed.block.size,
+                describe_site(disposed.block.site, debug_info),
+                describe_site(disposed.disposed_at, debug_info)
+            );
+        }
+        out
+    }
+
+    /// Reports the blocks that were never disposed, grouped by allocation site.
+    pub fn leak_report(&self, debug_info: Option<&DebugInfo>) -> String {
+        if self.live.is_empty() {
+            return String::from("every heap block was disposed\n");
+        }
+
+        let mut sites: BTreeMap<u16, (usize, usize)> = BTreeMap::new();
+        for block in self.live.values() {
+            let (blocks, words) = sites.entry(block.site).or_default();
+            *blocks += 1;
+            *words += block.size as usize;
+        }
+
+        let words: usize = self.live.values().map(|b| b.size as usize).sum();
+        let mut out = format!(
+            "{} ({}) never disposed:\n",
+            plural(self.live.len(), "block"),
+            plural(words, "word")
+        );
+        for (site, (blocks, words)) in sites {
+            let _ = writeln!(
+                out,
+                "  {} ({}) allocated at {}",
+                plural(blocks, "block"),
+                plural(words, "word"),
+                describe_site(site, debug_info)
+            );
+        }
+        out
+    }
+
+    /// Finds the lowest free block of at least `size` words and takes a block of
+    /// exactly `size` words from its start.
+    fn take_free(&mut self, size: u16) -> Option<u16> {
+        let (&address, &free) = self.free.iter().find(|&(_, &free)| free >= size)?;
+        self.free.remove(&address);
+        if free > size {
+            self.free.insert(address + size, free - size);
+        }
+        Some(address)
+    }
+
+    /// Adds a block to the free blocks, merging it with any free neighbours.
+    fn release(&mut self, mut address: u16, mut size: u16) {
+        if let Some((&prev, &prev_size)) = self.free.range(..address).next_back()
+            && prev as u32 + prev_size as u32 == address as u32
+        {
+            self.free.remove(&prev);
+            address = prev;
+            size += prev_size;
+        }
+        let end = address as u32 + size as u32;
+        if end <= u16::MAX as u32
+            && let Some(next_size) = self.free.remove(&(end as u16))
+        {
+            size += next_size;
+        }
+        self.free.insert(address, size);
+    }
+}
+
+impl TamEmulator {
+    /// Gets the heap allocator's view of the heap.
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Finds space for a block of `size` words, lowering `HT` if no free block fits.
+    fn find_space(&mut self, size: u16) -> Option<u16> {
+        if let Some(address) = self.heap.take_free(size) {
+            return Some(address);
+        }
+        if size > self.registers[HT] - self.registers[ST] {
+            return None;
+        }
+        self.registers[HT] -= size;
+        Some(self.registers[HT] + 1)
+    }
+
+    /// Finds space for a block of `size` words after freeing unreachable blocks, if the
+    /// garbage collector is enabled, and then after releasing the quarantine.
+    fn reclaim_space(&mut self, size: u16) -> Option<u16> {
+        if self.heap.gc.is_some() {
+            self.collect_garbage();
+            if let Some(address) = self.find_space(size) {
+                return Some(address);
+            }
+        }
+        if let Some(quarantine) = &mut self.heap.quarantine {
+            for disposed in std::mem::take(quarantine).into_values() {
+                self.free_block(disposed.block);
+            }
+        }
+        self.find_space(size)
+    }
+
+    /// Allocates a block of `size` words for the call to `new` that is executing.
+    pub(crate) fn allocate(&mut self, size: i16) -> TamResult<u16> {
+        if size <= 0 {
+            return Err(TamError::InvalidAllocationSize(size));
+        }
+        let size = size as u16;
+        let site = self.registers[CP].wrapping_sub(1);
+        let address = self
+            .find_space(size)
+            .or_else(|| self.reclaim_space(size))
+            .ok_or(TamError::OutOfMemory)?;
+
+        for i in 0..size {
+            self.mark_written(address + i, false);
+        }
+        self.heap.live.insert(
+            address,
+            Block {
+                address,
+                size,
+                site,
+            },
+        );
+        Ok(address)
+    }
+
+    /// Frees the block of `size` words at `address` for the call to `dispose` that is
+    /// executing.
+    ///
+    /// Fails with [`TamError::InvalidDispose`] unless `address` is the start of a live
+    /// block of `size` words, or in checked mode with [`TamError::DoubleDispose`] if
+    /// the block is in quarantine.
+    pub(crate) fn dispose(&mut self, address: u16, size: i16) -> TamResult<()> {
+        let site = self.registers[CP].wrapping_sub(1);
+        if let Some(disposed) = self.heap.quarantine.as_ref().and_then(|q| q.get(&address)) {
+            return Err(TamError::DoubleDispose {
+                address,
+                allocated_at: disposed.block.site,
+                disposed_at: disposed.disposed_at,
+            });
+        }
+        let block = match self.heap.live.get(&address) {
+            Some(&block) if block.size as i16 == size => block,
+            _ => return Err(TamError::InvalidDispose { address, size }),
+        };
+        self.heap.live.remove(&address);
+
+        let Some(quarantine) = &mut self.heap.quarantine else {
+            self.free_block(block);
+            return Ok(());
+        };
+        quarantine.insert(
+            address,
+            DisposedBlock {
+                block,
+                disposed_at: site,
+            },
+        );
+        for i in 0..block.size {
+            self.data_store[(address + i) as usize] = POISON;
+            self.mark_written(address + i, false);
+        }
+        Ok(())
+    }
+
+    /// Returns a block's words to the free space.
+    pub(crate) fn free_block(&mut self, block: Block) {
+        self.heap.release(block.address, block.size);
+
+        // give free space at the bottom of the heap back to the stack
+        let bottom = self.registers[HT] + 1;
+        if let Some(size) = self.heap.free.remove(&bottom) {
+            self.registers[HT] += size;
+        }
+    }
+
+    /// Enables or disables checking for use of heap blocks after they are disposed.
+    ///
+    /// While enabled, a load or store into a disposed block fails with
+    /// [`TamError::UseAfterDispose`], and disposing it again fails with
+    /// [`TamError::DoubleDispose`]. This should be enabled before the program starts.
+    pub fn set_check_heap(&mut self, enabled: bool) {
+        self.heap.quarantine = enabled.then(BTreeMap::new);
+    }
+
+    /// Checks that the data word at `addr` is not part of a disposed block.
+    pub(crate) fn check_heap_access(&self, addr: u16) -> TamResult<()> {
+        match self.heap.disposed_block_containing(addr) {
+            Some(disposed) => Err(TamError::UseAfterDispose {
+                address: addr,
+                allocated_at: disposed.block.site,
+                disposed_at: disposed.disposed_at,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HB, MEMORY_MAX, asm, tam};
+    use rstest::*;
+
+    #[fixture]
+    fn emulator() -> TamEmulator {
+        TamEmulator::new(false)
+    }
+
+    #[rstest]
+    fn test_allocate_grows_heap_down(mut emulator: TamEmulator) {
+        emulator.registers[CP] = 8;
+
+        assert_eq!(Ok(0xfffc), emulator.allocate(4));
+        assert_eq!(Ok(0xfffa), emulator.allocate(2));
+        assert_eq!(0xfff9, emulator.registers[HT]);
+        assert_eq!(
+            vec![
+                Block {
+                    address: 0xfffa,
+                    size: 2,
+                    site: 7
+                },
+                Block {
+                    address: 0xfffc,
+                    size: 4,
+                    site: 7
+                },
+            ],
+            emulator.heap().live_blocks().copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    fn test_allocate_collides_with_stack_out_of_memory(mut emulator: TamEmulator) {
+        emulator.registers[ST] = 0xfff0;
+
+        assert_eq!(Ok(0xfff1), emulator.allocate(15));
+        assert_eq!(Err(TamError::OutOfMemory), emulator.allocate(1));
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(-5)]
+    fn test_allocate_size_not_positive_invalid_allocation_size(
+        mut emulator: TamEmulator,
+        #[case] size: i16,
+    ) {
+        assert_eq!(
+            Err(TamError::InvalidAllocationSize(size)),
+            emulator.allocate(size)
+        );
+        assert_eq!(MEMORY_MAX as u16, emulator.registers[HT]);
+        assert_eq!(0, emulator.heap().live_blocks().count());
+    }
+
+    #[rstest]
+    #[case::not_allocated(0xfff0, 2)]
+    #[case::inside_block(0xfffd, 2)]
+    #[case::wrong_size(0xfffc, 3)]
+    fn test_dispose_not_live_block_invalid_dispose(
+        mut emulator: TamEmulator,
+        #[case] address: u16,
+        #[case] size: i16,
+    ) {
+        let a = emulator.allocate(4).unwrap();
+
+        assert_eq!(
+            Err(TamError::InvalidDispose { address, size }),
+            emulator.dispose(address, size)
+        );
+        assert_eq!(
+            vec![a],
+            emulator.heap().live.keys().copied().collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    fn test_allocate_reuses_lowest_fitting_free_block(mut emulator: TamEmulator) {
+        let a = emulator.allocate(2).unwrap();
+        let b = emulator.allocate(3).unwrap();
+        let _c = emulator.allocate(1).unwrap();
+        emulator.dispose(a, 2).unwrap();
+        emulator.dispose(b, 3).unwrap();
+
+        // a and b were merged into one free block of 5 words
+        assert_eq!(
+            vec![(b, 5)],
+            emulator.heap().free_blocks().collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(b), emulator.allocate(4));
+        assert_eq!(
+            vec![(b + 4, 1)],
+            emulator.heap().free_blocks().collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    fn test_dispose_bottom_block_raises_ht(mut emulator: TamEmulator) {
+        let a = emulator.allocate(2).unwrap();
+        let b = emulator.allocate(3).unwrap();
+        emulator.dispose(b, 3).unwrap();
+        assert_eq!(a - 1, emulator.registers[HT]);
+
+        emulator.dispose(a, 2).unwrap();
+        assert_eq!(emulator.registers[HB], emulator.registers[HT]);
+        assert_eq!(MEMORY_MAX as u16, emulator.registers[HT]);
+        assert_eq!(0, emulator.heap().free_blocks().count());
+    }
+
+    #[rstest]
+    fn test_heap_fragmentation(mut emulator: TamEmulator) {
+        let blocks: Vec<u16> = (0..5).map(|_| emulator.allocate(2).unwrap()).collect();
+        emulator.dispose(blocks[1], 2).unwrap();
+        emulator.dispose(blocks[3], 2).unwrap();
+
+        assert_eq!(0.5, emulator.heap().fragmentation());
+    }
+
+    #[rstest]
+    fn test_heap_leak_report_groups_by_site(mut emulator: TamEmulator) {
+        emulator.registers[CP] = 0x11;
+        emulator.allocate(2).unwrap();
+        emulator.allocate(2).unwrap();
+        emulator.registers[CP] = 0x21;
+        let b = emulator.allocate(3).unwrap();
+        emulator.allocate(1).unwrap();
+        emulator.dispose(b, 3).unwrap();
+
+        let mut info = DebugInfo::default();
+        info.add_procedure(crate::debug_info::Procedure {
+            name: String::from("insert"),
+            start: 0x20,
+            end: 0x30,
+        });
+        let expected = "\
+3 blocks (5 words) never disposed:
+  2 blocks (4 words) allocated at 0x0010
+  1 block (1 word) allocated at 0x0020, in procedure `insert`
+";
+        assert_eq!(expected, emulator.heap().leak_report(Some(&info)));
+    }
+
+    #[rstest]
+    #[case::load(
+        &tam! { LOAD(1) 0[SB]; LOADI(1) },
+        Err(TamError::DataAccessViolation),
+        Err(TamError::UseAfterDispose { address: 0xfffe, allocated_at: 1, disposed_at: 4 })
+    )]
+    #[case::store(
+        &tam! { LOADL 7; LOAD(1) 0[SB]; STOREI(1) },
+        Err(TamError::DataAccessViolation),
+        Err(TamError::UseAfterDispose { address: 0xfffe, allocated_at: 1, disposed_at: 4 })
+    )]
+    #[case::dispose(
+        &tam! { LOADL 2; LOAD(1) 0[SB]; CALL(CB) dispose[PB] },
+        Err(TamError::InvalidDispose { address: 0xfffe, size: 2 }),
+        Err(TamError::DoubleDispose { address: 0xfffe, allocated_at: 1, disposed_at: 4 })
+    )]
+    fn test_check_heap_dangling_pointer(
+        #[case] after_dispose: &[u32],
+        #[case] unchecked: TamResult<()>,
+        #[case] checked: TamResult<()>,
+        #[values(false, true)] check: bool,
+    ) {
+        // p := new(2); dispose(2, p); then use p
+        let mut code = tam! {
+            LOADL 2;
+            CALL(CB) new[PB];
+            LOADL 2;
+            LOAD(1) 0[SB];
+            CALL(CB) dispose[PB];
+        };
+        code.extend_from_slice(after_dispose);
+        code.extend(tam! { HALT });
+        let bytes = asm::to_bytes(&code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+        emu.set_check_heap(check);
+
+        let result = loop {
+            match emu.fetch_decode().and_then(|instr| emu.execute(instr)) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        assert_eq!(if check { checked } else { unchecked }, result);
+    }
+
+    #[rstest]
+    fn test_check_heap_poisons_and_quarantines(mut emulator: TamEmulator) {
+        emulator.set_check_heap(true);
+        let a = emulator.allocate(2).unwrap();
+        emulator.dispose(a, 2).unwrap();
+
+        assert_eq!(
+            [POISON, POISON],
+            emulator.data_store[a as usize..a as usize + 2]
+        );
+        assert_eq!(a - 1, emulator.registers[HT]);
+        assert_eq!(1, emulator.heap().disposed_blocks().count());
+        // the disposed block is not reused while memory remains
+        assert_eq!(Ok(a - 2), emulator.allocate(2));
+    }
+
+    #[rstest]
+    fn test_check_heap_releases_quarantine_when_out_of_memory(mut emulator: TamEmulator) {
+        emulator.set_check_heap(true);
+        emulator.registers[ST] = 0xfff0;
+        let a = emulator.allocate(10).unwrap();
+        emulator.dispose(a, 10).unwrap();
+
+        assert_eq!(Ok(a), emulator.allocate(10));
+        assert_eq!(0, emulator.heap().disposed_blocks().count());
+    }
+}
@@ -0,0 +1,215 @@
+//! Input and output for the I/O primitives.
+//!
+//! The primitives `get`, `getint`, `geteol`, `eol` and `eof` read from the input of
+//! an [`IoBackend`], and `put`, `putint` and `puteol` write to its output. An
+//! emulator uses [`StdIo`] unless it is given another backend, such as a [`MemoryIo`]
+//! that lets a program be run on fixed input with its output captured.
+
+use crate::{
+    TamEmulator,
+    errors::{TamError, TamResult},
+};
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+/// A source of input bytes and a sink for output bytes.
+pub trait IoBackend: Debug {
+    /// Gets the next input byte without consuming it, or `None` at the end of input.
+    fn peek(&mut self) -> TamResult<Option<u8>>;
+
+    /// Consumes the next input byte, or returns `None` at the end of input.
+    fn read(&mut self) -> TamResult<Option<u8>>;
+
+    /// Writes bytes to the output.
+    fn write(&mut self, bytes: &[u8]) -> TamResult<()>;
+}
+
+/// Reads from standard input and writes to standard output.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StdIo;
+
+impl IoBackend for StdIo {
+    fn peek(&mut self) -> TamResult<Option<u8>> {
+        let mut stdin = io::stdin().lock();
+        let buf = stdin.fill_buf().map_err(|_| TamError::IOError)?;
+        Ok(buf.first().copied())
+    }
+
+    fn read(&mut self) -> TamResult<Option<u8>> {
+        let byte = self.peek()?;
+        if byte.is_some() {
+            io::stdin().lock().consume(1);
+        }
+        Ok(byte)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> TamResult<()> {
+        // flushed straight away so output is not lost if the program faults
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(bytes)
+            .and_then(|_| stdout.flush())
+            .map_err(|_| TamError::IOError)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    input: Vec<u8>,
+    position: usize,
+    output: Vec<u8>,
+}
+
+/// Reads from a fixed input and records the output in memory.
+///
+/// Clones share the same buffers, so a clone kept by the caller sees everything
+/// written by the program running on an emulator given the original.
+///
+/// # Example
+///
+/// ```
+/// use tam_rs::{TamEmulator, io::MemoryIo};
+///
+/// let io = MemoryIo::new("");
+/// let mut emu = TamEmulator::new(false);
+/// emu.set_io(io.clone());
+/// // LOADL 42; CALL putint; HALT
+/// emu.set_program(&[0x30, 0, 0, 42, 0x62, 0, 0, 26, 0xf0, 0, 0, 0])
+///     .unwrap();
+/// while emu.fetch_decode().and_then(|instr| emu.execute(instr)).unwrap() {}
+///
+/// assert_eq!("42", io.output_string());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MemoryIo {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+impl MemoryIo {
+    /// Creates a backend that reads the given input.
+    pub fn new(input: impl Into<Vec<u8>>) -> MemoryIo {
+        MemoryIo {
+            buffers: Rc::new(RefCell::new(Buffers {
+                input: input.into(),
+                ..Buffers::default()
+            })),
+        }
+    }
+
+    /// Gets everything written so far.
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    /// Gets everything written so far as text, replacing any invalid UTF-8.
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.buffers.borrow().output).into_owned()
+    }
+
+    /// Gets the input that has not been read yet.
+    pub fn remaining_input(&self) -> Vec<u8> {
+        let buffers = self.buffers.borrow();
+        buffers.input[buffers.position..].to_vec()
+    }
+}
+
+impl IoBackend for MemoryIo {
+    fn peek(&mut self) -> TamResult<Option<u8>> {
+        let buffers = self.buffers.borrow();
+        Ok(buffers.input.get(buffers.position).copied())
+    }
+
+    fn read(&mut self) -> TamResult<Option<u8>> {
+        let mut buffers = self.buffers.borrow_mut();
+        let byte = buffers.input.get(buffers.position).copied();
+        if byte.is_some() {
+            buffers.position += 1;
+        }
+        Ok(byte)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> TamResult<()> {
+        self.buffers.borrow_mut().output.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl TamEmulator {
+    /// Sets where the I/O primitives read input from and write output to.
+    pub fn set_io(&mut self, io: impl IoBackend + 'static) {
+        self.io = Box::new(io);
+    }
+
+    /// Reads a possibly signed decimal integer, after skipping any whitespace.
+    pub(crate) fn read_int(&mut self) -> TamResult<i16> {
+        while let Some(byte) = self.io.peek()?
+            && byte.is_ascii_whitespace()
+        {
+            self.io.read()?;
+        }
+
+        let mut text = String::new();
+        if let Some(sign @ (b'-' | b'+')) = self.io.peek()? {
+            self.io.read()?;
+            text.push(sign as char);
+        }
+        while let Some(digit) = self.io.peek()?
+            && digit.is_ascii_digit()
+        {
+            self.io.read()?;
+            text.push(digit as char);
+        }
+        text.parse().map_err(|_| TamError::IOError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    /// Reads an integer from `input`, returning it with the input left unread.
+    fn read_int(input: &str) -> (TamResult<i16>, Vec<u8>) {
+        let io = MemoryIo::new(input);
+        let mut emu = TamEmulator::new(false);
+        emu.set_io(io.clone());
+        (emu.read_int(), io.remaining_input())
+    }
+
+    #[rstest]
+    #[case("42", 42)]
+    #[case("  \n-17", -17)]
+    #[case("+5", 5)]
+    #[case("32767", i16::MAX)]
+    fn test_read_int_ok(#[case] input: &str, #[case] value: i16) {
+        assert_eq!(Ok(value), read_int(input).0);
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("x")]
+    #[case("-")]
+    #[case("32768")]
+    fn test_read_int_err(#[case] input: &str) {
+        assert_eq!(Err(TamError::IOError), read_int(input).0);
+    }
+
+    #[rstest]
+    fn test_read_int_leaves_following_input() {
+        assert_eq!((Ok(12), b"\nab".to_vec()), read_int(" 12\nab"));
+    }
+
+    #[rstest]
+    fn test_memory_io_clones_share_buffers() {
+        let mut io = MemoryIo::new("ab");
+        let other = io.clone();
+        assert_eq!(Ok(Some(b'a')), io.read());
+        io.write(b"xy").unwrap();
+        assert_eq!(b"b".to_vec(), other.remaining_input());
+        assert_eq!("xy", other.output_string());
+    }
+}
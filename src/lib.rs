@@ -1,7 +1,28 @@
+pub mod analysis;
+pub mod asm;
+pub mod backtrace;
+pub mod chrome_trace;
+pub mod coverage;
+pub mod debug_info;
+pub mod dump;
 pub mod errors;
 mod execute;
+pub mod frames;
+pub mod gc;
+pub mod heap;
+pub mod io;
+pub mod link;
+pub mod observer;
+pub mod optimise;
+pub mod predecode;
+pub mod profile;
+mod shadow;
+pub mod stdlib;
+pub mod triangle;
+pub mod verify;
 
 use byteorder::{BE, ReadBytesExt};
+use debug_info::{DebugInfo, Variable};
 use errors::*;
 use std::{
     fmt::{self, Display},
@@ -11,6 +32,7 @@ use std::{
 pub const MEMORY_SIZE: usize = 65536;
 pub const MEMORY_MAX: usize = MEMORY_SIZE - 1;
 
+pub const CB: usize = 0;
 pub const CT: usize = 1;
 pub const PB: usize = 2;
 pub const PT: usize = 3;
@@ -19,8 +41,48 @@ pub const ST: usize = 5;
 pub const HB: usize = 6;
 pub const HT: usize = 7;
 pub const LB: usize = 8;
+pub const L1: usize = 9;
+pub const L2: usize = 10;
+pub const L3: usize = 11;
+pub const L4: usize = 12;
+pub const L5: usize = 13;
+pub const L6: usize = 14;
 pub const CP: usize = 15;
 
+pub const LOAD: u8 = 0;
+pub const LOADA: u8 = 1;
+pub const LOADI: u8 = 2;
+pub const LOADL: u8 = 3;
+pub const STORE: u8 = 4;
+pub const STOREI: u8 = 5;
+pub const CALL: u8 = 6;
+pub const CALLI: u8 = 7;
+pub const RETURN: u8 = 8;
+pub const PUSH: u8 = 10;
+pub const POP: u8 = 11;
+pub const JUMP: u8 = 12;
+pub const JUMPI: u8 = 13;
+pub const JUMPIF: u8 = 14;
+pub const HALT: u8 = 15;
+
+/// Names of the registers, indexed by register number.
+pub const REGISTER_NAMES: [&str; 16] = [
+    "CB", "CT", "PB", "PT", "SB", "ST", "HB", "HT", "LB", "L1", "L2", "L3", "L4", "L5", "L6", "CP",
+];
+
+/// Mnemonics of the instructions, indexed by opcode.
+pub const OPCODE_NAMES: [&str; 16] = [
+    "LOAD", "LOADA", "LOADI", "LOADL", "STORE", "STOREI", "CALL", "CALLI", "RETURN", "", "PUSH",
+    "POP", "JUMP", "JUMPI", "JUMPIF", "HALT",
+];
+
+/// Names of the primitive routines, indexed by their displacement from `PB`.
+pub const PRIMITIVE_NAMES: [&str; 29] = [
+    "", "id", "and", "or", "not", "succ", "pred", "neg", "add", "sub", "mult", "div", "mod", "lt",
+    "le", "ge", "gt", "eq", "ne", "eol", "eof", "get", "put", "geteol", "puteol", "getint",
+    "putint", "new", "dispose",
+];
+
 /// A single TAM instruction.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct TamInstruction {
@@ -49,6 +111,12 @@ impl From<u32> for TamInstruction {
     }
 }
 
+impl From<TamInstruction> for u32 {
+    fn from(instr: TamInstruction) -> Self {
+        asm::encode(instr.op, instr.r, instr.n, instr.d)
+    }
+}
+
 impl Display for TamInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.op {
@@ -65,19 +133,43 @@ impl Display for TamInstruction {
             11 => write!(f, "POP({}), {}", self.n, self.d),
             12 => write!(f, "JUMP {}[{}]", self.d, self.r),
             13 => write!(f, "JUMPI"),
-            14 => write!(f, "JUMPIF({}) {}[{}]", self.n, self.r, self.d),
+            14 => write!(f, "JUMPIF({}) {}[{}]", self.n, self.d, self.r),
             15 => write!(f, "HALT"),
             x => write!(f, "unrecognised opcode {}", x),
         }
     }
 }
 
+/// How the emulator treats results that do not fit in a 16-bit word.
+///
+/// The mode applies to the arithmetic primitives and to the addresses computed by
+/// instructions from a register and a displacement.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ArithmeticMode {
+    /// Results wrap around in two's complement
+    #[default]
+    Wrapping,
+    /// Results that overflow fail with [`TamError::ArithmeticOverflow`]
+    Trapping,
+    /// Results are clamped to the nearest representable value
+    Saturating,
+}
+
 #[derive(Debug)]
 pub struct TamEmulator {
     pub code_store: [u32; MEMORY_SIZE],
     pub data_store: [i16; MEMORY_SIZE],
     pub registers: [u16; 16],
     trace: bool,
+    debug_info: Option<DebugInfo>,
+    /// Number of routine activations on the stack
+    call_depth: usize,
+    /// Record of written data words, present when checking for uninitialised reads
+    shadow: Option<shadow::ShadowMemory>,
+    arithmetic_mode: ArithmeticMode,
+    heap: heap::Heap,
+    /// Where the I/O primitives read and write
+    io: Box<dyn io::IoBackend>,
 }
 
 impl TamEmulator {
@@ -88,6 +180,12 @@ impl TamEmulator {
             data_store: [0; MEMORY_SIZE],
             registers: [0; 16],
             trace,
+            debug_info: None,
+            call_depth: 0,
+            shadow: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            heap: heap::Heap::default(),
+            io: Box::new(io::StdIo),
         };
 
         emu.registers[HB] = MEMORY_MAX as u16;
@@ -133,6 +231,51 @@ impl TamEmulator {
         Ok(())
     }
 
+    /// Attaches source-level debug info to this emulator.
+    ///
+    /// Once set, traces are annotated with the procedure and source line of each
+    /// instruction.
+    pub fn set_debug_info(&mut self, info: DebugInfo) {
+        self.debug_info = Some(info);
+    }
+
+    /// Gets the debug info attached to this emulator, if any.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Sets how results that overflow a word are treated.
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+
+    /// Gets the number of routine activations on the stack.
+    pub fn call_depth(&self) -> usize {
+        self.call_depth
+    }
+
+    /// Gets the values of the variables local to the procedure containing `addr`.
+    ///
+    /// Each variable is read relative to the current value of its frame register, so
+    /// this is only meaningful while that procedure's frame is the active one.
+    pub fn locals(&self, addr: u16) -> Vec<(&Variable, &[i16])> {
+        let Some(info) = &self.debug_info else {
+            return Vec::new();
+        };
+        let scope = info.procedure(addr).map(|p| p.name.as_str());
+
+        info.variables(scope)
+            .map(|var| {
+                let base = self
+                    .register(var.register as usize)
+                    .wrapping_add_signed(var.offset);
+                let start = (base as usize).min(MEMORY_SIZE);
+                let end = (start + var.size as usize).min(MEMORY_SIZE);
+                (var, &self.data_store[start..end])
+            })
+            .collect()
+    }
+
     /// Gets the next instruction to be executed and increments `CP`.
     pub fn fetch_decode(&mut self) -> TamResult<TamInstruction> {
         let addr = self.registers[CP];
@@ -152,6 +295,7 @@ impl TamEmulator {
         }
 
         self.data_store[addr as usize] = value;
+        self.mark_written(addr, true);
         self.registers[ST] += 1;
         Ok(())
     }
@@ -163,13 +307,22 @@ impl TamEmulator {
 
         self.registers[ST] -= 1;
         let val = self.data_store[self.registers[ST] as usize];
+        self.mark_written(self.registers[ST], false);
         Ok(val)
     }
 
     /// Executes the given instruction.
     pub fn execute(&mut self, instr: TamInstruction) -> TamResult<bool> {
         if self.trace {
-            println!("{:#06x}: {}", self.registers[CP] - 1, instr);
+            let addr = self.registers[CP] - 1;
+            match self
+                .debug_info
+                .as_ref()
+                .and_then(|info| info.describe(addr))
+            {
+                Some(loc) => println!("{addr:#06x}: {:<20} ; {loc}", instr.to_string()),
+                None => println!("{addr:#06x}: {instr}"),
+            }
         }
 
         match instr.op {
@@ -186,13 +339,13 @@ impl TamEmulator {
                     self.exec_call(instr)?
                 }
             }
-            7 => todo!("exec_calli"),
+            7 => self.exec_calli()?,
             8 => self.exec_return(instr)?,
-            10 => todo!("exec_push"),
-            11 => todo!("exec_pop"),
-            12 => todo!("exec_jump"),
-            13 => todo!("exec_jumpi"),
-            14 => todo!("exec_jumpif"),
+            10 => self.exec_push(instr)?,
+            11 => self.exec_pop(instr)?,
+            12 => self.exec_jump(instr)?,
+            13 => self.exec_jumpi()?,
+            14 => self.exec_jumpif(instr)?,
             15 => return Ok(false),
             _ => return Err(TamError::UnknownOpcode(instr.op)),
         }
@@ -220,6 +373,15 @@ mod tests {
         assert_eq!(instr, TamInstruction::from(code));
     }
 
+    #[rstest]
+    #[case(0x00000000)]
+    #[case(0x12345678)]
+    #[case(0xa8765432)]
+    #[case(0xffffffff)]
+    fn test_u32_from_taminstruction_round_trips(#[case] code: u32) {
+        assert_eq!(code, u32::from(TamInstruction::from(code)));
+    }
+
     #[rstest]
     fn test_fetch_decode_cp_in_range_ok(mut emulator: TamEmulator) {
         emulator.code_store[0] = 0x12;
@@ -277,6 +439,28 @@ mod tests {
         assert_eq!(77, res.unwrap());
     }
 
+    #[rstest]
+    fn test_locals_read_from_frame(mut emulator: TamEmulator) {
+        let info = DebugInfo::parse(
+            "proc f 4 8
+            var f x LB 3 2 Pair
+            var - g SB 0 1 Integer",
+        )
+        .unwrap();
+        emulator.set_debug_info(info);
+        emulator.data_store[..7].copy_from_slice(&[9, 0, 0, 0, 0, 11, 12]);
+        emulator.registers[LB] = 2;
+
+        let locals = emulator.locals(5);
+        assert_eq!(1, locals.len());
+        assert_eq!("x", locals[0].0.name);
+        assert_eq!(&[11, 12], locals[0].1);
+
+        let globals = emulator.locals(0);
+        assert_eq!("g", globals[0].0.name);
+        assert_eq!(&[9], globals[0].1);
+    }
+
     #[rstest]
     fn test_pop_stack_empty_stack_underflow(mut emulator: TamEmulator) {
         let res = emulator.pop();
@@ -0,0 +1,460 @@
+//! Linking separately assembled modules into one program.
+//!
+//! An object file holds the code of one module, the number of global data words it
+//! uses, the routines it exports, and the instructions that call routines in other
+//! modules. It is written in a plain-text format, where each non-empty line holds one
+//! record and `#` starts a comment:
+//!
+//! ```text
+//! globals <words>
+//! export <name> <address>
+//! import <name> <address>
+//! code <instruction>
+//! ```
+//!
+//! `code` records give the module's instructions in order, each as a 32-bit word, and
+//! `import` records name the routine whose address replaces the displacement of the
+//! instruction at the given address. Within a module, every `JUMP`, `JUMPIF`, `CALL`
+//! and `LOADA` relative to `CB` targets an address in the module's own code, and every
+//! `LOAD`, `LOADA` and `STORE` relative to `SB` addresses the module's own globals.
+//! Addresses may be written in decimal or as `0x`-prefixed hex.
+
+use crate::{
+    CALL, CB, JUMP, JUMPIF, LOAD, LOADA, PRIMITIVE_NAMES, PUSH, SB, STORE, TamInstruction,
+    debug_info::{DebugInfo, Procedure, parse_address},
+    errors::{TamError, TamResult},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+};
+
+/// A module of code, ready to be linked.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectFile {
+    pub code: Vec<u32>,
+    /// Number of words of global data the module addresses from `SB`
+    pub globals: u16,
+    /// Routines defined by the module for others to call, with their code addresses
+    pub exports: BTreeMap<String, u16>,
+    /// Instructions that refer to routines defined by other modules, by code address
+    pub imports: BTreeMap<u16, String>,
+}
+
+impl ObjectFile {
+    /// Parses an object file from its text representation.
+    ///
+    /// If a record is malformed, the error holds its 1-based line number.
+    pub fn parse(text: &str) -> TamResult<ObjectFile> {
+        let mut object = ObjectFile::default();
+        let mut symbol_lines = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+
+            let bad = TamError::InvalidObjectFile(i + 1);
+            match fields[..] {
+                ["globals", words] => object.globals = words.parse().map_err(|_| bad)?,
+                ["export", name, addr] => {
+                    let addr = parse_address(addr).ok_or(bad)?;
+                    if object.exports.insert(name.to_string(), addr).is_some() {
+                        return Err(bad);
+                    }
+                    symbol_lines.push((addr, bad));
+                }
+                ["import", name, addr] => {
+                    let addr = parse_address(addr).ok_or(bad)?;
+                    object.imports.insert(addr, name.to_string());
+                    symbol_lines.push((addr, bad));
+                }
+                ["code", word] => {
+                    let word = word
+                        .strip_prefix("0x")
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .ok_or(bad)?;
+                    object.code.push(word);
+                }
+                _ => return Err(bad),
+            }
+        }
+
+        // symbols may come before the code they refer to
+        let code_top = object.code.len();
+        match symbol_lines
+            .into_iter()
+            .find(|(addr, _)| *addr as usize >= code_top)
+        {
+            Some((_, bad)) => Err(bad),
+            None => Ok(object),
+        }
+    }
+}
+
+impl Display for ObjectFile {
+    /// Writes the object file in the text format read by [`ObjectFile::parse`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "globals {}", self.globals)?;
+        for (name, addr) in &self.exports {
+            writeln!(f, "export {name} {addr:#06x}")?;
+        }
+        for (addr, name) in &self.imports {
+            writeln!(f, "import {name} {addr:#06x}")?;
+        }
+        for (addr, word) in self.code.iter().enumerate() {
+            writeln!(
+                f,
+                "code {word:#010x}  # {addr:#06x}: {}",
+                TamInstruction::from(*word)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A problem that stops modules from being linked.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkError {
+    /// No module exports a routine imported by the given module
+    UndefinedSymbol { module: usize, name: String },
+    /// More than one module exports a routine with this name
+    DuplicateSymbol(String),
+    /// An import names a primitive's name, which needs no linking
+    PrimitiveImported { module: usize, name: String },
+    /// The linked code or globals would not fit in memory, or could not all be
+    /// addressed by a displacement
+    TooLarge,
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::UndefinedSymbol { name, .. } => write!(f, "undefined routine `{name}`"),
+            LinkError::DuplicateSymbol(name) => write!(f, "routine `{name}` defined twice"),
+            LinkError::PrimitiveImported { name, .. } => {
+                write!(f, "`{name}` is a primitive, so should be called through PB")
+            }
+            LinkError::TooLarge => write!(f, "linked program is too large"),
+        }
+    }
+}
+
+/// A program linked from several modules.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Linked {
+    pub code: Vec<u32>,
+    /// The code address of every exported routine
+    pub symbols: BTreeMap<String, u16>,
+    /// A procedure for every exported routine, which extends to the next exported
+    /// routine of its module or the end of the module
+    pub debug_info: DebugInfo,
+}
+
+impl Linked {
+    /// Gets the code in the big-endian byte format read by `TamEmulator::set_program`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.code.iter().flat_map(|i| i.to_be_bytes()).collect()
+    }
+}
+
+/// Links modules into one program, which starts at the beginning of the first.
+///
+/// The code of the modules is laid out in order. The globals of the other modules
+/// are placed from `SB` upwards, followed by those of the first, so the first module
+/// may allocate more globals with a `PUSH` as it starts, as compiled programs do. If
+/// any module declares globals, the program begins with a `PUSH` of them all.
+///
+/// # Example
+///
+/// ```
+/// use tam_rs::link::{ObjectFile, link};
+///
+/// let main = ObjectFile::parse(
+///     "import seven 0
+///      code 0x60040000  # CALL(SB) seven[CB]
+///      code 0xf0000000  # HALT",
+/// )
+/// .unwrap();
+/// let library = ObjectFile::parse(
+///     "export seven 0
+///      code 0x30000007  # LOADL 7
+///      code 0x80010000  # RETURN(1) 0",
+/// )
+/// .unwrap();
+///
+/// let linked = link(&[main, library]).unwrap();
+/// assert_eq!(0x6004_0002, linked.code[0]);
+/// assert_eq!(Some(&2), linked.symbols.get("seven"));
+/// ```
+pub fn link(modules: &[ObjectFile]) -> Result<Linked, LinkError> {
+    // lay out globals, with the first module's last
+    let mut data_bases = vec![0u32; modules.len()];
+    let mut data_top = 0u32;
+    for (i, module) in modules.iter().enumerate().skip(1) {
+        data_bases[i] = data_top;
+        data_top += module.globals as u32;
+    }
+    if let Some(first) = modules.first() {
+        data_bases[0] = data_top;
+        data_top += first.globals as u32;
+    }
+
+    // lay out code after the prologue
+    let prologue = (data_top > 0) as u32;
+    let mut code_bases = Vec::with_capacity(modules.len());
+    let mut code_top = prologue;
+    for module in modules {
+        code_bases.push(code_top);
+        code_top += module.code.len() as u32;
+    }
+    // every code and data address must fit in a displacement
+    if code_top > i16::MAX as u32 || data_top > i16::MAX as u32 {
+        return Err(LinkError::TooLarge);
+    }
+
+    let mut symbols = BTreeMap::new();
+    let mut debug_info = DebugInfo::default();
+    for (module, &base) in modules.iter().zip(&code_bases) {
+        let mut exports: Vec<(&String, u16)> = module
+            .exports
+            .iter()
+            .map(|(name, &addr)| (name, base as u16 + addr))
+            .collect();
+        exports.sort_by_key(|&(_, addr)| addr);
+
+        let module_end = base as u16 + module.code.len() as u16;
+        for (i, &(name, start)) in exports.iter().enumerate() {
+            if symbols.insert(name.clone(), start).is_some() {
+                return Err(LinkError::DuplicateSymbol(name.clone()));
+            }
+            debug_info.add_procedure(Procedure {
+                name: name.clone(),
+                start,
+                end: exports.get(i + 1).map_or(module_end, |&(_, next)| next),
+            });
+        }
+    }
+
+    let mut code = Vec::with_capacity(code_top as usize);
+    if prologue > 0 {
+        code.push(
+            TamInstruction {
+                op: PUSH,
+                r: 0,
+                n: 0,
+                d: data_top as i16,
+            }
+            .into(),
+        );
+    }
+    for (i, module) in modules.iter().enumerate() {
+        for (addr, &word) in module.code.iter().enumerate() {
+            let mut instr = TamInstruction::from(word);
+            if let Some(name) = module.imports.get(&(addr as u16)) {
+                if PRIMITIVE_NAMES.contains(&name.as_str()) {
+                    return Err(LinkError::PrimitiveImported {
+                        module: i,
+                        name: name.clone(),
+                    });
+                }
+                let target = symbols.get(name).ok_or(LinkError::UndefinedSymbol {
+                    module: i,
+                    name: name.clone(),
+                })?;
+                instr.d = *target as i16;
+            } else if matches!(instr.op, JUMP | JUMPIF | CALL | LOADA) && instr.r as usize == CB {
+                instr.d = relocate(instr.d, code_bases[i])?;
+            } else if matches!(instr.op, LOAD | LOADA | STORE) && instr.r as usize == SB {
+                instr.d = relocate(instr.d, data_bases[i])?;
+            }
+            code.push(instr.into());
+        }
+    }
+
+    Ok(Linked {
+        code,
+        symbols,
+        debug_info,
+    })
+}
+
+/// Moves a displacement by a module's base address.
+fn relocate(d: i16, base: u32) -> Result<i16, LinkError> {
+    i16::try_from(base)
+        .ok()
+        .and_then(|base| d.checked_add(base))
+        .ok_or(LinkError::TooLarge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ST, TamEmulator, io::MemoryIo, tam};
+    use rstest::*;
+
+    fn module(
+        code: &[u32],
+        globals: u16,
+        exports: &[(&str, u16)],
+        imports: &[(u16, &str)],
+    ) -> ObjectFile {
+        ObjectFile {
+            code: code.to_vec(),
+            globals,
+            exports: exports.iter().map(|&(n, a)| (n.to_string(), a)).collect(),
+            imports: imports.iter().map(|&(a, n)| (a, n.to_string())).collect(),
+        }
+    }
+
+    /// Counts calls in a global and returns the count, which it keeps in a global.
+    fn counter() -> ObjectFile {
+        module(
+            &tam! {
+                LOAD(1) 0[SB];
+                CALL(SB) succ[PB];
+                STORE(1) 0[SB];
+                LOAD(1) 0[SB];
+                RETURN(1) 0;
+            },
+            1,
+            &[("count", 0)],
+            &[],
+        )
+    }
+
+    #[rstest]
+    fn test_link_relocates_code_data_and_symbols() {
+        let main = module(
+            &tam! {
+                PUSH 1;
+                CALL(SB) 0[CB];
+                CALL(SB) 0[CB];
+                STORE(1) 0[SB];
+                JUMP 5[CB];
+                HALT;
+            },
+            0,
+            &[],
+            &[(1, "count"), (2, "count")],
+        );
+        let linked = link(&[main, counter()]).unwrap();
+
+        assert_eq!(
+            tam! {
+                PUSH 1;
+                PUSH 1;
+                CALL(SB) 7[CB];
+                CALL(SB) 7[CB];
+                STORE(1) 1[SB];
+                JUMP 6[CB];
+                HALT;
+                LOAD(1) 0[SB];
+                CALL(SB) succ[PB];
+                STORE(1) 0[SB];
+                LOAD(1) 0[SB];
+                RETURN(1) 0;
+            },
+            linked.code
+        );
+        assert_eq!(Some(&7), linked.symbols.get("count"));
+        assert_eq!(
+            Some("count"),
+            linked.debug_info.procedure(11).map(|p| p.name.as_str())
+        );
+    }
+
+    #[rstest]
+    fn test_linked_program_runs() {
+        let main = module(
+            &tam! {
+                CALL(SB) 0[CB];
+                CALL(SB) 0[CB];
+                CALL(SB) putint[PB];
+                HALT;
+            },
+            0,
+            &[],
+            &[(0, "count"), (1, "count")],
+        );
+        let linked = link(&[main, counter()]).unwrap();
+
+        let io = MemoryIo::new("");
+        let mut emu = TamEmulator::new(false);
+        emu.set_io(io.clone());
+        emu.set_program(&linked.to_bytes()).unwrap();
+        while emu.fetch_decode().and_then(|i| emu.execute(i)).unwrap() {}
+
+        // the first result is left below the second
+        assert_eq!("2", io.output_string());
+        assert_eq!(2, emu.registers[ST]);
+    }
+
+    #[rstest]
+    fn test_link_without_globals_has_no_prologue() {
+        let main = module(&tam! { HALT }, 0, &[], &[]);
+        let library = module(&tam! { RETURN(0) 0 }, 0, &[("f", 0)], &[]);
+
+        let linked = link(&[main, library]).unwrap();
+        assert_eq!(tam! { HALT; RETURN(0) 0 }, linked.code);
+        assert_eq!(Some(&1), linked.symbols.get("f"));
+    }
+
+    #[rstest]
+    #[case::undefined(
+        vec![module(&tam! { CALL(LB) 0[CB] }, 0, &[], &[(0, "f")])],
+        LinkError::UndefinedSymbol { module: 0, name: "f".into() }
+    )]
+    #[case::duplicate(
+        vec![counter(), counter()],
+        LinkError::DuplicateSymbol("count".into())
+    )]
+    #[case::primitive(
+        vec![module(&tam! { CALL(LB) 0[CB] }, 0, &[], &[(0, "putint")])],
+        LinkError::PrimitiveImported { module: 0, name: "putint".into() }
+    )]
+    #[case::too_many_globals(
+        vec![
+            module(&tam! { HALT }, 20000, &[], &[]),
+            module(&tam! { RETURN(0) 0 }, 20000, &[], &[]),
+        ],
+        LinkError::TooLarge
+    )]
+    #[case::displacement_overflow(
+        vec![
+            module(&tam! { HALT }, 0, &[], &[]),
+            module(&tam! { JUMP 32767[CB] }, 0, &[], &[]),
+        ],
+        LinkError::TooLarge
+    )]
+    fn test_link_err(#[case] modules: Vec<ObjectFile>, #[case] err: LinkError) {
+        assert_eq!(Err(err), link(&modules));
+    }
+
+    #[rstest]
+    fn test_object_file_round_trips_through_text() {
+        let object = module(
+            &tam! { LOADL 3; CALL(SB) 0[CB] },
+            2,
+            &[("g", 0)],
+            &[(1, "f")],
+        );
+        assert_eq!(Ok(object.clone()), ObjectFile::parse(&object.to_string()));
+    }
+
+    #[rstest]
+    #[case("globals -1", 1)]
+    #[case("code 12", 1)]
+    #[case("\ncode 0x30000001\nimport f 1", 3)]
+    #[case("export f 0\nexport f 0", 2)]
+    #[case("export f 1\ncode 0x30000001", 1)]
+    #[case("symbol f", 1)]
+    fn test_object_file_parse_bad_record_err(#[case] text: &str, #[case] line: usize) {
+        assert_eq!(
+            Err(TamError::InvalidObjectFile(line)),
+            ObjectFile::parse(text)
+        );
+    }
+}
@@ -1,42 +1,638 @@
-use clap::Parser;
-use std::{fs::File, io::Read};
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use std::{fs, path::Path, process};
 use tam_rs::{
-    TamEmulator,
+    ArithmeticMode, CP, CT, HT, LB, SB, TamEmulator, TamInstruction,
+    analysis::{self, cfg::ControlFlowGraph, stack::StackAnalysis},
+    backtrace::{self, Frame},
+    chrome_trace::ChromeTrace,
+    coverage::Coverage,
+    debug_info::DebugInfo,
     errors::{TamError, TamResult},
+    link::{self, LinkError, ObjectFile},
+    observer::Observer,
+    optimise,
+    profile::{FoldedStacks, Profiler},
+    triangle,
 };
 
+/// Number of frames printed at each end of a fault's backtrace.
+const BACKTRACE_ENDS: usize = 10;
+
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile a Triangle program to TAM bytecode
+    Compile {
+        /// Name of file to read Triangle source from
+        source_file: String,
+        /// Name of file to write bytecode to, by default the source file with a .tam extension
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Name of file to write source-level debug info to
+        #[arg(short, long)]
+        debug_info: Option<String>,
+    },
+    /// Print the control-flow graphs of a program in Graphviz DOT format
+    Cfg {
+        /// Name of file to read program from
+        prog_file: String,
+        /// Print the call graph instead of the control-flow graphs
+        #[arg(long)]
+        call_graph: bool,
+        /// Name of file to read source-level debug info from
+        #[arg(short, long)]
+        debug_info: Option<String>,
+    },
+    /// Report the coverage recorded by one or more runs of a program
+    Coverage {
+        /// Name of file to read program from
+        prog_file: String,
+        /// Names of files to read coverage data from, which are merged
+        #[arg(required = true)]
+        data_files: Vec<String>,
+        /// Name of file to read source-level debug info from
+        #[arg(short, long)]
+        debug_info: Option<String>,
+        /// Print coverage of source lines in lcov format, which needs a line table
+        #[arg(long, requires = "debug_info")]
+        lcov: bool,
+    },
+    /// Report the stack usage of each routine and any unbalanced stack operations
+    Stack {
+        /// Name of file to read program from
+        prog_file: String,
+        /// Name of file to read source-level debug info from
+        #[arg(short, long)]
+        debug_info: Option<String>,
+    },
+    /// Link object files into one program
+    Link {
+        /// Names of object files to link, starting with the one the program starts in
+        #[arg(required = true)]
+        object_files: Vec<String>,
+        /// Name of file to write bytecode to
+        #[arg(short, long)]
+        output: String,
+        /// Name of file to write a procedure for each exported routine to, as debug info
+        #[arg(short, long)]
+        debug_info: Option<String>,
+        /// Link the bundled library of routines after the object files
+        #[arg(long)]
+        stdlib: bool,
+    },
+    /// Optimise a program with peephole rewrites
+    Opt {
+        /// Name of file to read program from
+        prog_file: String,
+        /// Name of file to write the optimised program to, by default the program file
+        /// with a .opt.tam extension
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Run the original and optimised programs and check that they stop in the same
+        /// way with the same output, writing nothing if they do not
+        #[arg(long)]
+        verify: bool,
+        /// Name of file to read input for the verification runs from
+        #[arg(long, requires = "verify")]
+        input: Option<String>,
+        /// Number of instructions each verification run may execute
+        #[arg(long, default_value_t = 10_000_000)]
+        max_steps: u64,
+    },
+    /// Check a program for malformed instructions without running it
+    Verify {
+        /// Name of file to read program from
+        prog_file: String,
+    },
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("dump").multiple(true)))]
+struct RunArgs {
     /// Name of file to read program from
-    prog_file: String,
+    #[arg(required = true)]
+    prog_file: Option<String>,
     /// Print each instruction as it is executed
     #[arg(short, long)]
     trace: bool,
+    /// Name of file to read source-level debug info from
+    #[arg(short, long)]
+    debug_info: Option<String>,
+    /// Print execution counts to stderr after the program stops
+    #[arg(long)]
+    profile: bool,
+    /// Name of file to write execution counts to as JSON
+    #[arg(long)]
+    profile_json: Option<String>,
+    /// Name of file to write call stacks weighted by instructions executed to, in the
+    /// collapsed format read by flamegraph tools
+    #[arg(long)]
+    folded: Option<String>,
+    /// Name of file to write routine activations to as Chrome trace events
+    #[arg(long)]
+    chrome_trace: Option<String>,
+    /// Name of file to add coverage data from this run to
+    #[arg(long)]
+    coverage: Option<String>,
+    /// Fail when the program loads a data word that it never wrote
+    #[arg(long)]
+    check_uninit: bool,
+    /// Refuse to run the program if it fails verification
+    #[arg(long)]
+    verify: bool,
+    /// Print the contents of the data store to stderr when the program halts
+    #[arg(long, group = "dump")]
+    dump_on_exit: bool,
+    /// Print the contents of the data store to stderr when the program faults
+    #[arg(long, group = "dump")]
+    dump_on_fault: bool,
+    /// Name of file to write a raw image of the data store to whenever it is dumped
+    #[arg(long, requires = "dump")]
+    dump_image: Option<String>,
+    /// Fail when the program uses or disposes a heap block it has already disposed
+    #[arg(long)]
+    check_heap: bool,
+    /// Free unreachable heap blocks when `new` runs out of memory
+    #[arg(long)]
+    gc: bool,
+    /// Print the heap's blocks to stderr after the program stops, and the blocks that
+    /// were never disposed if it halts
+    #[arg(long)]
+    heap_report: bool,
+    /// Draw the activation records on the stack when the program faults
+    #[arg(long)]
+    frames_on_fault: bool,
+    /// Name of file to write the activation records on the stack to in DOT format when
+    /// the program stops
+    #[arg(long)]
+    frames_dot: Option<String>,
+    /// Decode the whole program before running it, which is faster but cannot be
+    /// combined with options that watch each instruction
+    #[arg(long, conflicts_with_all = [
+        "trace", "profile", "profile_json", "folded", "chrome_trace", "coverage",
+    ])]
+    predecode: bool,
+    /// How arithmetic primitives and address computations handle overflow
+    #[arg(long, value_enum, default_value_t = Arithmetic::Wrapping)]
+    arithmetic: Arithmetic,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Arithmetic {
+    /// Wrap around as two's complement
+    Wrapping,
+    /// Stop the program with an error
+    Trapping,
+    /// Clamp to the nearest representable value
+    Saturating,
+}
+
+impl From<Arithmetic> for ArithmeticMode {
+    fn from(arithmetic: Arithmetic) -> ArithmeticMode {
+        match arithmetic {
+            Arithmetic::Wrapping => ArithmeticMode::Wrapping,
+            Arithmetic::Trapping => ArithmeticMode::Trapping,
+            Arithmetic::Saturating => ArithmeticMode::Saturating,
+        }
+    }
 }
 
 fn main() -> TamResult<()> {
-    // load program from file
     let cli = Cli::parse();
-    let code = read_code_from_file(&cli.prog_file).map_err(|_| TamError::IOError)?;
-    let mut emu = TamEmulator::new(cli.trace);
+    match cli.command {
+        Some(Command::Compile {
+            source_file,
+            output,
+            debug_info,
+        }) => compile(&source_file, output, debug_info.as_deref()),
+        Some(Command::Cfg {
+            prog_file,
+            call_graph,
+            debug_info,
+        }) => cfg(&prog_file, call_graph, debug_info.as_deref()),
+        Some(Command::Coverage {
+            prog_file,
+            data_files,
+            debug_info,
+            lcov,
+        }) => coverage(&prog_file, &data_files, debug_info.as_deref(), lcov),
+        Some(Command::Stack {
+            prog_file,
+            debug_info,
+        }) => stack(&prog_file, debug_info.as_deref()),
+        Some(Command::Link {
+            object_files,
+            output,
+            debug_info,
+            stdlib,
+        }) => link(&object_files, &output, debug_info.as_deref(), stdlib),
+        Some(Command::Opt {
+            prog_file,
+            output,
+            verify,
+            input,
+            max_steps,
+        }) => opt(&prog_file, output, verify, input.as_deref(), max_steps),
+        Some(Command::Verify { prog_file }) => {
+            let mut emu = TamEmulator::new(false);
+            emu.set_program(&fs::read(prog_file).map_err(|_| TamError::IOError)?)?;
+            verify(&emu);
+            Ok(())
+        }
+        None => run(&cli.run),
+    }
+}
+
+fn compile(source_file: &str, output: Option<String>, debug_info: Option<&str>) -> TamResult<()> {
+    let source = fs::read_to_string(source_file).map_err(|_| TamError::IOError)?;
+    let compiled = match triangle::compile(&source, source_file) {
+        Ok(compiled) => compiled,
+        Err(errors) => {
+            for e in errors {
+                eprintln!("{source_file}:{e}");
+            }
+            process::exit(1);
+        }
+    };
+
+    let output = output.unwrap_or_else(|| {
+        let path = Path::new(source_file).with_extension("tam");
+        path.to_string_lossy().into_owned()
+    });
+    fs::write(output, compiled.to_bytes()).map_err(|_| TamError::IOError)?;
+    if let Some(filename) = debug_info {
+        fs::write(filename, compiled.debug_info.to_string()).map_err(|_| TamError::IOError)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a program and, if given, its debug info, for static analysis.
+fn load_for_analysis(
+    prog_file: &str,
+    debug_info: Option<&str>,
+) -> TamResult<(Vec<u32>, Option<DebugInfo>)> {
+    let mut emu = TamEmulator::new(false);
+    emu.set_program(&fs::read(prog_file).map_err(|_| TamError::IOError)?)?;
+    let info = match debug_info {
+        Some(filename) => {
+            let text = fs::read_to_string(filename).map_err(|_| TamError::IOError)?;
+            Some(DebugInfo::parse(&text)?)
+        }
+        None => None,
+    };
+    Ok((emu.code_store[..emu.registers[CT] as usize].to_vec(), info))
+}
+
+fn cfg(prog_file: &str, call_graph: bool, debug_info: Option<&str>) -> TamResult<()> {
+    let (code, info) = load_for_analysis(prog_file, debug_info)?;
+    let graph = ControlFlowGraph::build(&code);
+    if call_graph {
+        print!("{}", graph.call_graph_dot(info.as_ref()));
+    } else {
+        print!("{}", graph.to_dot(info.as_ref()));
+    }
+    Ok(())
+}
+
+fn coverage(
+    prog_file: &str,
+    data_files: &[String],
+    debug_info: Option<&str>,
+    lcov: bool,
+) -> TamResult<()> {
+    let (code, info) = load_for_analysis(prog_file, debug_info)?;
+    let mut coverage = Coverage::new();
+    for filename in data_files {
+        let text = fs::read_to_string(filename).map_err(|_| TamError::IOError)?;
+        coverage.merge(&Coverage::parse(&text)?);
+    }
+
+    if !lcov {
+        print!("{}", coverage.listing(&code, info.as_ref()));
+        return Ok(());
+    }
+    match info.and_then(|info| coverage.to_lcov(&code, &info)) {
+        Some(report) => print!("{report}"),
+        None => {
+            eprintln!("debug info has no line table for this program");
+            process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn stack(prog_file: &str, debug_info: Option<&str>) -> TamResult<()> {
+    let (code, info) = load_for_analysis(prog_file, debug_info)?;
+    let analysis = StackAnalysis::analyse(&code);
+
+    for proc in &analysis.procedures {
+        let name = analysis::procedure_name(proc.entry, info.as_ref());
+        let effect = proc
+            .effect
+            .map_or("never returns".to_string(), |e| format!("{e:+}"));
+        let depth = proc
+            .max_depth
+            .map_or("unbounded".to_string(), |d| d.to_string());
+        println!("{name}: effect {effect}, max depth {depth}");
+    }
+
+    if !analysis.problems.is_empty() {
+        for problem in &analysis.problems {
+            eprintln!("{problem}");
+        }
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn link(
+    object_files: &[String],
+    output: &str,
+    debug_info: Option<&str>,
+    stdlib: bool,
+) -> TamResult<()> {
+    let mut modules = Vec::new();
+    for filename in object_files {
+        let text = fs::read_to_string(filename).map_err(|_| TamError::IOError)?;
+        match ObjectFile::parse(&text) {
+            Ok(module) => modules.push(module),
+            Err(TamError::InvalidObjectFile(line)) => {
+                eprintln!("{filename}:{line}: malformed record");
+                process::exit(1);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if stdlib {
+        modules.push(tam_rs::stdlib::module());
+    }
+
+    let linked = match link::link(&modules) {
+        Ok(linked) => linked,
+        Err(e) => {
+            match &e {
+                LinkError::UndefinedSymbol { module, .. }
+                | LinkError::PrimitiveImported { module, .. } => {
+                    eprintln!("{}: {e}", object_files[*module]);
+                }
+                _ => eprintln!("{e}"),
+            }
+            process::exit(1);
+        }
+    };
+
+    fs::write(output, linked.to_bytes()).map_err(|_| TamError::IOError)?;
+    if let Some(filename) = debug_info {
+        fs::write(filename, linked.debug_info.to_string()).map_err(|_| TamError::IOError)?;
+    }
+    Ok(())
+}
+
+fn opt(
+    prog_file: &str,
+    output: Option<String>,
+    verify: bool,
+    input: Option<&str>,
+    max_steps: u64,
+) -> TamResult<()> {
+    let (code, _) = load_for_analysis(prog_file, None)?;
+    let instrs: Vec<TamInstruction> = code.iter().map(|&word| word.into()).collect();
+    let optimised = match optimise::optimise(&instrs) {
+        Ok(optimised) => optimised,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    eprintln!(
+        "{}; {} instructions down to {}",
+        optimised.rewrites,
+        instrs.len(),
+        optimised.code.len()
+    );
+
+    let bytes = optimised.to_bytes();
+    if verify {
+        let original: Vec<u8> = code.iter().flat_map(|word| word.to_be_bytes()).collect();
+        let input = match input {
+            Some(filename) => fs::read(filename).map_err(|_| TamError::IOError)?,
+            None => Vec::new(),
+        };
+        if let Err(mismatch) = optimise::check_equivalent(&original, &bytes, &input, max_steps) {
+            eprintln!("verification failed: {mismatch}");
+            process::exit(1);
+        }
+        eprintln!("verified: both programs stop in the same way with the same output");
+    }
+
+    let output = output.unwrap_or_else(|| {
+        let path = Path::new(prog_file).with_extension("opt.tam");
+        path.to_string_lossy().into_owned()
+    });
+    fs::write(output, bytes).map_err(|_| TamError::IOError)
+}
+
+fn run(args: &RunArgs) -> TamResult<()> {
+    // load program from file
+    let prog_file = args.prog_file.as_deref().expect("program file is required");
+    let code = fs::read(prog_file).map_err(|_| TamError::IOError)?;
+    let mut emu = TamEmulator::new(args.trace);
+    if let Some(filename) = &args.debug_info {
+        let text = fs::read_to_string(filename).map_err(|_| TamError::IOError)?;
+        emu.set_debug_info(DebugInfo::parse(&text)?);
+    }
     emu.set_program(&code)?;
+    emu.set_check_uninit(args.check_uninit);
+    emu.set_arithmetic_mode(args.arithmetic.into());
+    emu.set_garbage_collection(args.gc);
+    emu.set_check_heap(args.check_heap);
+    if args.verify {
+        verify(&emu);
+    }
+
+    let mut profiler = (args.profile || args.profile_json.is_some()).then(Profiler::new);
+    let mut coverage = args.coverage.as_ref().map(|_| Coverage::new());
+    let mut folded = args.folded.as_ref().map(|_| FoldedStacks::new());
+    let mut chrome_trace = args.chrome_trace.as_ref().map(|_| ChromeTrace::new());
+    let mut observers: Vec<&mut dyn Observer> = Vec::new();
+    if let Some(profiler) = profiler.as_mut() {
+        observers.push(profiler);
+    }
+    if let Some(coverage) = coverage.as_mut() {
+        observers.push(coverage);
+    }
+    if let Some(folded) = folded.as_mut() {
+        observers.push(folded);
+    }
+    if let Some(chrome_trace) = chrome_trace.as_mut() {
+        observers.push(chrome_trace);
+    }
 
     // CPU cycle
-    let mut running = true;
-    while running {
-        let instr = emu.fetch_decode()?;
-        running = emu.execute(instr)?;
+    let program = args.predecode.then(|| emu.predecode());
+    let mut result = Ok(());
+    loop {
+        let addr = emu.registers[CP];
+        let step = match &program {
+            Some(program) => emu.step_predecoded(program),
+            None => emu.step(&mut observers),
+        };
+        match step {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                report_fault(&emu, addr, e);
+                if args.frames_on_fault {
+                    eprint!("{}", emu.stack_diagram().to_text());
+                }
+                result = Err(e);
+                break;
+            }
+        }
     }
 
-    Ok(())
+    if args.heap_report {
+        eprint!("{}", emu.heap().report(emu.debug_info()));
+        if result.is_ok() {
+            eprint!("{}", emu.heap().leak_report(emu.debug_info()));
+        }
+    }
+    if let Some(filename) = &args.frames_dot {
+        fs::write(filename, emu.stack_diagram().to_dot()).map_err(|_| TamError::IOError)?;
+    }
+    if (args.dump_on_exit && result.is_ok()) || (args.dump_on_fault && result.is_err()) {
+        eprint!("{}", emu.dump_data(..));
+        if let Some(filename) = &args.dump_image {
+            fs::write(filename, emu.data_image(..)).map_err(|_| TamError::IOError)?;
+        }
+    }
+
+    if let Some(profiler) = &profiler {
+        if args.profile {
+            eprint!("{}", profiler.report(emu.debug_info()));
+        }
+        if let Some(filename) = &args.profile_json {
+            fs::write(filename, profiler.to_json(emu.debug_info()))
+                .map_err(|_| TamError::IOError)?;
+        }
+    }
+    if let (Some(folded), Some(filename)) = (&folded, &args.folded) {
+        fs::write(filename, folded.to_folded(emu.debug_info())).map_err(|_| TamError::IOError)?;
+    }
+    if let (Some(chrome_trace), Some(filename)) = (&chrome_trace, &args.chrome_trace) {
+        fs::write(filename, chrome_trace.to_json(emu.debug_info()))
+            .map_err(|_| TamError::IOError)?;
+    }
+    if let (Some(coverage), Some(filename)) = (&mut coverage, &args.coverage) {
+        // accumulate with the results of earlier runs
+        if let Ok(text) = fs::read_to_string(filename) {
+            coverage.merge(&Coverage::parse(&text)?);
+        }
+        fs::write(filename, coverage.to_string()).map_err(|_| TamError::IOError)?;
+    }
+    result
+}
+
+/// Prints every problem found in the loaded program and exits if there are any.
+fn verify(emu: &TamEmulator) {
+    if let Err(problems) = emu.verify() {
+        for problem in problems {
+            eprintln!("{problem}");
+        }
+        process::exit(1);
+    }
+}
+
+/// Describes a code address, in source terms where the debug info allows.
+fn describe_code(emu: &TamEmulator, addr: u16) -> String {
+    match emu.debug_info().and_then(|info| info.describe(addr)) {
+        Some(loc) => format!("{addr:#06x}, {loc}"),
+        None => format!("{addr:#06x}"),
+    }
 }
 
-fn read_code_from_file(filename: &str) -> std::io::Result<Vec<u8>> {
-    let f = File::open(filename)?;
-    let mut code = Vec::new();
-    for b in f.bytes() {
-        code.push(b?);
+fn report_fault(emu: &TamEmulator, addr: u16, err: TamError) {
+    if addr < emu.registers[CT] {
+        let instr = TamInstruction::from(emu.code_store[addr as usize]);
+        match emu.debug_info().and_then(|info| info.describe(addr)) {
+            Some(loc) => eprintln!("fault at {addr:#06x}: {instr}, {loc}"),
+            None => eprintln!("fault at {addr:#06x}: {instr}"),
+        }
+    } else {
+        eprintln!("fault at {addr:#06x}, outside the code store");
     }
 
-    Ok(code)
+    match err {
+        TamError::UseAfterDispose {
+            allocated_at,
+            disposed_at,
+            ..
+        } => eprintln!(
+            "  {err}\n  allocated at {}\n  disposed at {}",
+            describe_code(emu, allocated_at),
+            describe_code(emu, disposed_at)
+        ),
+        TamError::DoubleDispose {
+            allocated_at,
+            disposed_at,
+            ..
+        } => eprintln!(
+            "  {err}\n  allocated at {}\n  first disposed at {}",
+            describe_code(emu, allocated_at),
+            describe_code(emu, disposed_at)
+        ),
+        TamError::UninitialisedRead(data_addr) if data_addr > emu.registers[HT] => {
+            eprintln!("  {err} on the heap")
+        }
+        TamError::UninitialisedRead(data_addr) => {
+            let (base, name) = if emu.call_depth() > 0 && data_addr >= emu.registers[LB] {
+                (emu.registers[LB], "LB")
+            } else {
+                (emu.registers[SB], "SB")
+            };
+            eprintln!("  {err} ({name}+{})", data_addr.wrapping_sub(base))
+        }
+        _ => eprintln!("  {err}"),
+    }
+
+    for (var, value) in emu.locals(addr) {
+        eprintln!("  {}: {} = {:?}", var.name, var.ty, value);
+    }
+
+    eprintln!("backtrace:");
+    let frames = emu.backtrace();
+    let (inner, elided, outer) = backtrace::elide(&frames, BACKTRACE_ENDS);
+    for (i, frame) in inner.iter().enumerate() {
+        report_frame(emu, i, frame);
+    }
+    if elided > 0 {
+        eprintln!("  ... {elided} frames elided");
+    }
+    for (i, frame) in outer.iter().enumerate() {
+        report_frame(emu, frames.len() - outer.len() + i, frame);
+    }
+}
+
+/// Prints one line of a backtrace.
+fn report_frame(emu: &TamEmulator, i: usize, frame: &Frame) {
+    let name = emu
+        .debug_info()
+        .and_then(|info| info.procedure(frame.code_address))
+        .map_or("?", |p| p.name.as_str());
+    match frame.static_link {
+        Some(link) => eprintln!(
+            "  #{i} {:#06x} in {name} (LB={:#06x}, static link={link:#06x})",
+            frame.code_address, frame.lb
+        ),
+        None => eprintln!("  #{i} {:#06x} in main program", frame.code_address),
+    }
 }
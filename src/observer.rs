@@ -0,0 +1,113 @@
+//! Hooks for tools that watch a program as it runs.
+
+use crate::{CP, TamEmulator, TamInstruction, errors::TamResult};
+
+/// Receives an event for every instruction an emulator executes.
+pub trait Observer {
+    /// Called when `instr` has been fetched from `addr` and is about to execute.
+    ///
+    /// `CP` already holds the address of the next instruction, but nothing else has
+    /// changed, so this is where to read operands the instruction will pop.
+    fn before_instruction(&mut self, _emu: &TamEmulator, _addr: u16, _instr: TamInstruction) {}
+
+    /// Called after `instr`, fetched from `addr`, has executed successfully.
+    ///
+    /// The emulator's registers and memory reflect the effect of the instruction, so
+    /// after a `CALL` `CP` holds the address of the routine called.
+    fn after_instruction(&mut self, emu: &TamEmulator, addr: u16, instr: TamInstruction);
+}
+
+/// A change to the active routines made by an instruction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CallEvent {
+    /// A routine was entered at the given address
+    Enter(u16),
+    /// The routine entered at the given address returned
+    Leave(u16),
+}
+
+/// Follows the routine activations of a running program, for use by observers.
+///
+/// Activations are identified by the entry address of their routine, with the main
+/// program at address 0. Calls to primitives do not create activations.
+#[derive(Clone, Debug)]
+pub struct CallTracker {
+    stack: Vec<u16>,
+}
+
+impl Default for CallTracker {
+    fn default() -> CallTracker {
+        CallTracker { stack: vec![0] }
+    }
+}
+
+impl CallTracker {
+    pub fn new() -> CallTracker {
+        CallTracker::default()
+    }
+
+    /// Updates the activations after an instruction has executed, by comparing them
+    /// with the emulator's call depth.
+    pub fn update(&mut self, emu: &TamEmulator) -> Option<CallEvent> {
+        let depth = self.stack.len() - 1;
+        if emu.call_depth() > depth {
+            let entry = emu.registers[CP];
+            self.stack.push(entry);
+            Some(CallEvent::Enter(entry))
+        } else if emu.call_depth() < depth {
+            self.stack.pop().map(CallEvent::Leave)
+        } else {
+            None
+        }
+    }
+
+    /// Gets the entry addresses of the active routines, outermost first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    /// Gets the entry address of the innermost active routine.
+    pub fn current(&self) -> u16 {
+        *self.stack.last().expect("main program is always active")
+    }
+}
+
+impl TamEmulator {
+    /// Executes the next instruction and notifies each observer of it.
+    ///
+    /// Returns `false` once the program halts. Observers are not notified of an
+    /// instruction that fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tam_rs::{TamEmulator, TamInstruction, observer::Observer};
+    ///
+    /// struct Counter(usize);
+    ///
+    /// impl Observer for Counter {
+    ///     fn after_instruction(&mut self, _: &TamEmulator, _: u16, _: TamInstruction) {
+    ///         self.0 += 1;
+    ///     }
+    /// }
+    ///
+    /// let mut emu = TamEmulator::new(false);
+    /// emu.set_program(&[0x30, 0, 0, 1, 0xf0, 0, 0, 0]).unwrap();
+    /// let mut counter = Counter(0);
+    /// while emu.step(&mut [&mut counter]).unwrap() {}
+    /// assert_eq!(2, counter.0);
+    /// ```
+    pub fn step(&mut self, observers: &mut [&mut dyn Observer]) -> TamResult<bool> {
+        let addr = self.registers[CP];
+        let instr = self.fetch_decode()?;
+        for observer in observers.iter_mut() {
+            observer.before_instruction(self, addr, instr);
+        }
+        let running = self.execute(instr)?;
+
+        for observer in observers.iter_mut() {
+            observer.after_instruction(self, addr, instr);
+        }
+        Ok(running)
+    }
+}
@@ -0,0 +1,664 @@
+//! Peephole optimisation of TAM bytecode.
+//!
+//! [`optimise`] repeatedly applies a set of local rewrites until none applies:
+//!
+//! - `LOADL a; LOADL b; CALL add[PB]`, and likewise for `sub`, `mult`, `div` and
+//!   `mod`, is folded into `LOADL a+b`, as is `LOADL a; CALL succ[PB]` and the other
+//!   unary arithmetic primitives. Folding is skipped when the result would overflow or
+//!   divide by zero, so the program fails in the same way in every arithmetic mode.
+//! - `PUSH 0`, `POP(0) 0`, and a `JUMP` to the instruction after it are removed.
+//! - A jump to an unconditional `JUMP` is redirected to that jump's target.
+//! - Instructions that cannot be reached from address 0 are deleted.
+//!
+//! Instructions are then moved down over the gaps left behind, and the targets of
+//! every `JUMP`, `JUMPIF`, `CALL` and `LOADA` relative to `CB` are relocated. Code
+//! addresses must therefore only reach the program through those instructions: a
+//! program with a `JUMPI`, or a jump or call through another register, is refused.
+
+use crate::{
+    CALL, CB, HALT, JUMP, JUMPI, JUMPIF, LOADA, LOADL, PB, POP, PRIMITIVE_NAMES, PUSH, RETURN,
+    TamEmulator, TamInstruction, errors::TamResult, io::MemoryIo,
+};
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display},
+    mem,
+};
+
+/// The number of times each rewrite was applied.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Rewrites {
+    /// Arithmetic primitives applied to constants that were folded
+    pub folded: usize,
+    /// `PUSH 0`, `POP(0) 0` and jumps to the next instruction that were removed
+    pub no_ops: usize,
+    /// Jumps redirected past another jump
+    pub threaded: usize,
+    /// Unreachable instructions that were deleted
+    pub unreachable: usize,
+}
+
+impl Display for Rewrites {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "folded {} constant operations, removed {} no-ops, threaded {} jumps, \
+             deleted {} unreachable instructions",
+            self.folded, self.no_ops, self.threaded, self.unreachable
+        )
+    }
+}
+
+/// An optimised program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Optimised {
+    pub code: Vec<TamInstruction>,
+    /// The address in the optimised program of each instruction of the original, or
+    /// `None` for instructions that were removed
+    pub addresses: Vec<Option<u16>>,
+    pub rewrites: Rewrites,
+}
+
+impl Optimised {
+    /// Gets the code in the big-endian byte format read by `TamEmulator::set_program`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.code
+            .iter()
+            .flat_map(|&i| u32::from(i).to_be_bytes())
+            .collect()
+    }
+}
+
+/// An instruction whose target is only known at runtime, which stops a program from
+/// being optimised.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ComputedTarget {
+    /// Code address of the instruction
+    pub address: u16,
+}
+
+impl Display for ComputedTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#06x}: target is computed at runtime, so code cannot be moved",
+            self.address
+        )
+    }
+}
+
+/// Optimises a program, which occupies the whole of `code`.
+///
+/// # Example
+///
+/// ```
+/// use tam_rs::{TamInstruction, optimise::optimise};
+///
+/// // LOADL 2; LOADL 3; CALL(SB) add[PB]; HALT
+/// let code: Vec<TamInstruction> = [0x3000_0002, 0x3000_0003, 0x6204_0008, 0xf000_0000]
+///     .into_iter()
+///     .map(TamInstruction::from)
+///     .collect();
+///
+/// let optimised = optimise(&code).unwrap();
+/// assert_eq!(TamInstruction::from(0x3000_0005), optimised.code[0]);
+/// assert_eq!(2, optimised.code.len());
+/// ```
+pub fn optimise(code: &[TamInstruction]) -> Result<Optimised, ComputedTarget> {
+    for (address, instr) in code.iter().enumerate() {
+        let computed = match instr.op {
+            JUMPI => true,
+            JUMP | JUMPIF => instr.r as usize != CB,
+            CALL => instr.r as usize != CB && instr.r as usize != PB,
+            _ => false,
+        };
+        if computed {
+            return Err(ComputedTarget {
+                address: address as u16,
+            });
+        }
+    }
+
+    let mut optimiser = Optimiser {
+        slots: code.iter().copied().map(Some).collect(),
+        rewrites: Rewrites::default(),
+    };
+    // every pass must run, as each can expose work for the others
+    while optimiser.fold_constants()
+        | optimiser.remove_no_ops()
+        | optimiser.thread_jumps()
+        | optimiser.delete_unreachable()
+    {}
+    Ok(optimiser.finish())
+}
+
+/// The program being optimised, with removed instructions left as gaps so that
+/// targets keep their original addresses until the end.
+struct Optimiser {
+    slots: Vec<Option<TamInstruction>>,
+    rewrites: Rewrites,
+}
+
+impl Optimiser {
+    /// Finds the first instruction at or after `addr` that has not been removed.
+    fn next_live(&self, addr: usize) -> usize {
+        (addr..self.slots.len())
+            .find(|&a| self.slots[a].is_some())
+            .unwrap_or(self.slots.len())
+    }
+
+    /// Gets the code address targeted by an instruction, if it is inside the code.
+    fn target(&self, instr: &TamInstruction) -> Option<usize> {
+        let in_code = instr.r as usize == CB && (instr.d as u16 as usize) < self.slots.len();
+        (matches!(instr.op, JUMP | JUMPIF | CALL | LOADA) && in_code)
+            .then_some(instr.d as u16 as usize)
+    }
+
+    /// Gets the addresses of the live instructions that control can arrive at other
+    /// than from the instruction before.
+    fn leaders(&self) -> BTreeSet<usize> {
+        let mut leaders = BTreeSet::from([self.next_live(0)]);
+        for instr in self.slots.iter().flatten() {
+            if let Some(target) = self.target(instr) {
+                leaders.insert(self.next_live(target));
+            }
+        }
+        leaders
+    }
+
+    fn fold_constants(&mut self) -> bool {
+        let leaders = self.leaders();
+        let mut changed = false;
+        let mut addr = self.next_live(0);
+        while addr < self.slots.len() {
+            let next = self.next_live(addr + 1);
+            let after = self.next_live(next + 1);
+            let instr = |a: usize| self.slots.get(a).copied().flatten();
+
+            let folded = match (instr(addr), instr(next), instr(after)) {
+                (Some(a), Some(b), Some(call)) if a.op == LOADL && b.op == LOADL => {
+                    fold_binary(primitive(&call), a.d, b.d)
+                        .filter(|_| !leaders.contains(&next) && !leaders.contains(&after))
+                        .map(|value| (value, vec![next, after]))
+                }
+                (Some(a), Some(call), _) if a.op == LOADL => fold_unary(primitive(&call), a.d)
+                    .filter(|_| !leaders.contains(&next))
+                    .map(|value| (value, vec![next])),
+                _ => None,
+            };
+
+            match folded {
+                Some((value, removed)) => {
+                    self.slots[addr] = Some(TamInstruction {
+                        op: LOADL,
+                        r: 0,
+                        n: 0,
+                        d: value,
+                    });
+                    for a in removed {
+                        self.slots[a] = None;
+                    }
+                    self.rewrites.folded += 1;
+                    changed = true;
+                    // the result may itself be an operand of the next operation
+                }
+                None => addr = next,
+            }
+        }
+        changed
+    }
+
+    fn remove_no_ops(&mut self) -> bool {
+        let mut changed = false;
+        for addr in 0..self.slots.len() {
+            let Some(instr) = self.slots[addr] else {
+                continue;
+            };
+            let no_op = match instr.op {
+                PUSH => instr.d == 0,
+                POP => instr.n == 0 && instr.d == 0,
+                JUMP => self
+                    .target(&instr)
+                    .is_some_and(|target| self.next_live(target) == self.next_live(addr + 1)),
+                _ => false,
+            };
+            if no_op {
+                self.slots[addr] = None;
+                self.rewrites.no_ops += 1;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for addr in 0..self.slots.len() {
+            let Some(mut instr) = self.slots[addr].filter(|i| matches!(i.op, JUMP | JUMPIF)) else {
+                continue;
+            };
+            let Some(target) = self.target(&instr) else {
+                continue;
+            };
+
+            let first = self.next_live(target);
+            let mut dest = first;
+            let mut seen = BTreeSet::new();
+            while let Some(jump) = self.slots.get(dest).copied().flatten()
+                && jump.op == JUMP
+                && let Some(next) = self.target(&jump)
+                && seen.insert(dest)
+            {
+                dest = self.next_live(next);
+            }
+
+            if dest != first && dest < self.slots.len() {
+                instr.d = dest as i16;
+                self.slots[addr] = Some(instr);
+                self.rewrites.threaded += 1;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn delete_unreachable(&mut self) -> bool {
+        let mut reached = vec![false; self.slots.len()];
+        let mut pending = vec![self.next_live(0)];
+        while let Some(addr) = pending.pop() {
+            let Some(instr) = self.slots.get(addr).copied().flatten() else {
+                continue;
+            };
+            if mem::replace(&mut reached[addr], true) {
+                continue;
+            }
+
+            if let Some(target) = self.target(&instr) {
+                pending.push(self.next_live(target));
+            }
+            if !matches!(instr.op, JUMP | RETURN | HALT) {
+                pending.push(self.next_live(addr + 1));
+            }
+        }
+
+        let mut changed = false;
+        for (slot, reached) in self.slots.iter_mut().zip(reached) {
+            if slot.is_some() && !reached {
+                *slot = None;
+                self.rewrites.unreachable += 1;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Closes up the gaps and relocates every target.
+    fn finish(self) -> Optimised {
+        let mut addresses = Vec::with_capacity(self.slots.len());
+        let mut count = 0;
+        for slot in &self.slots {
+            addresses.push(slot.map(|_| count));
+            count += slot.is_some() as u16;
+        }
+
+        let relocate = |target: usize| {
+            let live = self.next_live(target);
+            addresses.get(live).copied().flatten().unwrap_or(count)
+        };
+        let code = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|&instr| match self.target(&instr) {
+                Some(target) => TamInstruction {
+                    d: relocate(target) as i16,
+                    ..instr
+                },
+                None => instr,
+            })
+            .collect();
+
+        Optimised {
+            code,
+            addresses,
+            rewrites: self.rewrites,
+        }
+    }
+}
+
+/// Gets the name of the primitive called by an instruction, if it calls one.
+fn primitive(instr: &TamInstruction) -> Option<&'static str> {
+    let is_primitive = instr.op == CALL && instr.r as usize == PB;
+    PRIMITIVE_NAMES
+        .get(instr.d as usize)
+        .filter(|_| is_primitive && instr.d > 0)
+        .copied()
+}
+
+fn fold_binary(primitive: Option<&str>, lhs: i16, rhs: i16) -> Option<i16> {
+    match primitive? {
+        "add" => lhs.checked_add(rhs),
+        "sub" => lhs.checked_sub(rhs),
+        "mult" => lhs.checked_mul(rhs),
+        "div" => lhs.checked_div(rhs),
+        "mod" => lhs.checked_rem(rhs),
+        _ => None,
+    }
+}
+
+fn fold_unary(primitive: Option<&str>, op: i16) -> Option<i16> {
+    match primitive? {
+        "succ" => op.checked_add(1),
+        "pred" => op.checked_sub(1),
+        "neg" => op.checked_neg(),
+        _ => None,
+    }
+}
+
+/// How a run of a program ended.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunResult {
+    /// The result of the run, or `None` if the program had not stopped by the step limit
+    pub result: Option<TamResult<()>>,
+    pub output: Vec<u8>,
+}
+
+/// Runs a program on the given input for at most `max_steps` instructions.
+pub fn run_with_input(code: &[u8], input: &[u8], max_steps: u64) -> RunResult {
+    let io = MemoryIo::new(input);
+    let mut emu = Box::new(TamEmulator::new(false));
+    emu.set_io(io.clone());
+
+    let mut result = None;
+    if let Err(e) = emu.set_program(code) {
+        result = Some(Err(e));
+    }
+    for _ in 0..max_steps {
+        if result.is_some() {
+            break;
+        }
+        match emu.fetch_decode().and_then(|instr| emu.execute(instr)) {
+            Ok(true) => {}
+            Ok(false) => result = Some(Ok(())),
+            Err(e) => result = Some(Err(e)),
+        }
+    }
+
+    RunResult {
+        result,
+        output: io.output(),
+    }
+}
+
+/// A difference between runs of a program and its optimised form.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mismatch {
+    /// A program had not stopped by the step limit, so the runs cannot be compared
+    Unfinished,
+    /// The programs stopped differently, with the original's result first
+    Result(TamResult<()>, TamResult<()>),
+    /// The programs wrote different output, with the original's first
+    Output(Vec<u8>, Vec<u8>),
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Unfinished => write!(f, "a program did not stop within the step limit"),
+            Mismatch::Result(original, optimised) => write!(
+                f,
+                "original stopped with {original:?}, optimised with {optimised:?}"
+            ),
+            Mismatch::Output(original, optimised) => write!(
+                f,
+                "original wrote {:?}, optimised wrote {:?}",
+                String::from_utf8_lossy(original),
+                String::from_utf8_lossy(optimised)
+            ),
+        }
+    }
+}
+
+/// Runs a program and its optimised form on the same input and checks that they stop
+/// in the same way and write the same output.
+///
+/// Errors only have to be of the same kind, as the code addresses some of them carry
+/// differ between the two programs.
+pub fn check_equivalent(
+    original: &[u8],
+    optimised: &[u8],
+    input: &[u8],
+    max_steps: u64,
+) -> Result<(), Mismatch> {
+    let before = run_with_input(original, input, max_steps);
+    let after = run_with_input(optimised, input, max_steps);
+
+    let (Some(before_result), Some(after_result)) = (before.result, after.result) else {
+        return Err(Mismatch::Unfinished);
+    };
+    let same_result = match (&before_result, &after_result) {
+        (Ok(()), Ok(())) => true,
+        (Err(e1), Err(e2)) => mem::discriminant(e1) == mem::discriminant(e2),
+        _ => false,
+    };
+    if !same_result {
+        return Err(Mismatch::Result(before_result, after_result));
+    }
+    if before.output != after.output {
+        return Err(Mismatch::Output(before.output, after.output));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::triangle;
+    use rstest::*;
+
+    /// Decodes a program written as for `tam!`.
+    macro_rules! program {
+        ($($code:tt)*) => {
+            crate::tam!($($code)*)
+                .into_iter()
+                .map(TamInstruction::from)
+                .collect::<Vec<_>>()
+        };
+    }
+
+    #[rstest]
+    #[case::add(program! { LOADL 2; LOADL 3; CALL(SB) add[PB]; HALT }, 5)]
+    #[case::sub(program! { LOADL 2; LOADL 3; CALL(SB) sub[PB]; HALT }, -1)]
+    #[case::mult(program! { LOADL -4; LOADL 3; CALL(SB) mult[PB]; HALT }, -12)]
+    #[case::div(program! { LOADL -7; LOADL 2; CALL(SB) div[PB]; HALT }, -3)]
+    #[case::modulo(program! { LOADL -7; LOADL 2; CALL(SB) mod[PB]; HALT }, -1)]
+    #[case::neg(program! { LOADL 7; CALL(SB) neg[PB]; HALT }, -7)]
+    #[case::chain(
+        program! {
+            LOADL 1;
+            LOADL 2;
+            CALL(SB) add[PB];
+            CALL(SB) succ[PB];
+            LOADL 4;
+            CALL(SB) mult[PB];
+            HALT;
+        },
+        16
+    )]
+    fn test_optimise_folds_constants(#[case] code: Vec<TamInstruction>, #[case] value: i16) {
+        let optimised = optimise(&code).unwrap();
+        let mut expected = program! { LOADL 0; HALT };
+        expected[0].d = value;
+        assert_eq!(expected, optimised.code);
+    }
+
+    #[rstest]
+    #[case::overflow(program! { LOADL 32767; LOADL 1; CALL(SB) add[PB]; HALT })]
+    #[case::divide_by_zero(program! { LOADL 1; LOADL 0; CALL(SB) div[PB]; HALT })]
+    #[case::not_arithmetic(program! { LOADL 1; LOADL 2; CALL(SB) lt[PB]; HALT })]
+    #[case::operand_jumped_to(program! {
+        LOADL 1;
+        JUMPIF(0) 3[CB];
+        LOADL 1;
+        LOADL 2;
+        CALL(SB) add[PB];
+        HALT;
+    })]
+    fn test_optimise_does_not_fold(#[case] code: Vec<TamInstruction>) {
+        assert_eq!(code, optimise(&code).unwrap().code);
+    }
+
+    #[rstest]
+    fn test_optimise_removes_no_ops_and_relocates() {
+        let code = program! {
+            PUSH 0;
+            JUMPIF(0) 3[CB];
+            POP(0) 0;
+            PUSH 0;
+            HALT;
+        };
+        let optimised = optimise(&code).unwrap();
+
+        assert_eq!(program! { JUMPIF(0) 1[CB]; HALT }, optimised.code);
+        assert_eq!(
+            vec![None, Some(0), None, None, Some(1)],
+            optimised.addresses
+        );
+        assert_eq!(3, optimised.rewrites.no_ops);
+    }
+
+    #[rstest]
+    fn test_optimise_threads_jumps() {
+        let code = program! {
+            LOADL 0;
+            JUMPIF(0) 4[CB];
+            JUMP 6[CB];
+            HALT;
+            JUMP 5[CB];
+            JUMP 3[CB];
+            JUMP 6[CB];
+        };
+        let optimised = optimise(&code).unwrap();
+
+        // the jump at 4 goes to the next instruction, the one at 5 is left unreachable,
+        // and the loop at 6 stays
+        assert_eq!(
+            program! {
+                LOADL 0;
+                JUMPIF(0) 3[CB];
+                JUMP 4[CB];
+                HALT;
+                JUMP 4[CB];
+            },
+            optimised.code
+        );
+        assert_eq!(
+            Rewrites {
+                folded: 0,
+                no_ops: 1,
+                threaded: 1,
+                unreachable: 1
+            },
+            optimised.rewrites
+        );
+    }
+
+    #[rstest]
+    fn test_optimise_deletes_unreachable_and_relocates_calls() {
+        let code = program! {
+            CALL(SB) 5[CB];
+            LOADA 7[CB];
+            HALT;
+            // never called
+            LOAD(1) -1[LB];
+            RETURN(0) 1;
+            // called
+            LOADL 1;
+            RETURN(0) 0;
+            // address taken
+            STORE(1) 0[SB];
+            RETURN(0) 1;
+        };
+        let optimised = optimise(&code).unwrap();
+
+        assert_eq!(
+            program! {
+                CALL(SB) 3[CB];
+                LOADA 5[CB];
+                HALT;
+                LOADL 1;
+                RETURN(0) 0;
+                STORE(1) 0[SB];
+                RETURN(0) 1;
+            },
+            optimised.code
+        );
+        assert_eq!(2, optimised.rewrites.unreachable);
+    }
+
+    #[rstest]
+    #[case(program! { JUMPI })]
+    #[case(program! { JUMP 0[LB] })]
+    #[case(program! { CALL 0[SB] })]
+    fn test_optimise_computed_target_refused(#[case] first: Vec<TamInstruction>) {
+        assert_eq!(
+            Err(ComputedTarget { address: 0 }),
+            optimise(&[first, program! { HALT }].concat())
+        );
+    }
+
+    #[rstest]
+    fn test_optimised_triangle_program_equivalent() {
+        let source = "
+            let
+              const n ~ 2 * 3 + 1;
+              var i: Integer;
+              var c: Char;
+              func twice(x: Integer): Integer ~ x + x
+            in begin
+              i := 0;
+              while i < n do begin
+                if i // 2 = 0 then putint(twice(i * (4 - 1))) else put('-');
+                i := i + 1
+              end;
+              get(var c);
+              put(c);
+              puteol()
+            end";
+        let compiled = triangle::compile(source, "t.tri").unwrap();
+        let code: Vec<TamInstruction> = compiled.code.iter().map(|&w| w.into()).collect();
+
+        let optimised = optimise(&code).unwrap();
+        assert!(optimised.code.len() < code.len());
+        assert_eq!(
+            Ok(()),
+            check_equivalent(&compiled.to_bytes(), &optimised.to_bytes(), b"z", 100_000)
+        );
+        assert_eq!(
+            b"0-12-24-36z\n".to_vec(),
+            run_with_input(&optimised.to_bytes(), b"z", 100_000).output
+        );
+    }
+
+    #[rstest]
+    fn test_check_equivalent_reports_different_output() {
+        let original = Optimised {
+            code: program! { LOADL 1; CALL(SB) putint[PB]; HALT },
+            addresses: Vec::new(),
+            rewrites: Rewrites::default(),
+        };
+        let changed = Optimised {
+            code: program! { LOADL 2; CALL(SB) putint[PB]; HALT },
+            ..original.clone()
+        };
+
+        assert_eq!(
+            Err(Mismatch::Output(b"1".to_vec(), b"2".to_vec())),
+            check_equivalent(&original.to_bytes(), &changed.to_bytes(), b"", 10)
+        );
+        assert_eq!(
+            Err(Mismatch::Unfinished),
+            check_equivalent(&original.to_bytes(), &changed.to_bytes(), b"", 1)
+        );
+    }
+}
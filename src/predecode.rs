@@ -0,0 +1,190 @@
+//! Execution from a pre-decoded copy of the code store.
+//!
+//! [`TamEmulator::execute`] decodes each instruction as it is fetched, dispatches on
+//! its opcode, and checks every `CALL` for a primitive. A [`DecodedProgram`] does that
+//! work once for the whole code store, resolving each call to a primitive to the
+//! routine implementing it, so the interpreter loop only has to call a handler.
+//! Execution is otherwise identical, including every check the emulator is set up to
+//! make, but instructions are not traced and observers are not notified.
+
+use crate::{
+    CP, CT, PB, TamEmulator, TamInstruction,
+    errors::{TamError, TamResult},
+    execute::{Primitive, primitive_handler},
+};
+
+type Handler = fn(&mut TamEmulator, TamInstruction) -> TamResult<()>;
+
+/// An instruction with its dispatch already resolved.
+#[derive(Copy, Clone)]
+enum DecodedOp {
+    Instruction(Handler, TamInstruction),
+    Primitive(Primitive),
+    Halt,
+    Unknown(u8),
+}
+
+impl From<TamInstruction> for DecodedOp {
+    fn from(instr: TamInstruction) -> DecodedOp {
+        let handler: Handler = match instr.op {
+            0 => TamEmulator::exec_load,
+            1 => TamEmulator::exec_loada,
+            2 => TamEmulator::exec_loadi,
+            3 => TamEmulator::exec_loadl,
+            4 => TamEmulator::exec_store,
+            5 => TamEmulator::exec_storei,
+            6 if instr.r == PB as u8 && instr.d > 0 && instr.d < 29 => {
+                return DecodedOp::Primitive(primitive_handler(instr.d));
+            }
+            6 => TamEmulator::exec_call,
+            7 => |emu, _| emu.exec_calli(),
+            8 => TamEmulator::exec_return,
+            10 => TamEmulator::exec_push,
+            11 => TamEmulator::exec_pop,
+            12 => TamEmulator::exec_jump,
+            13 => |emu, _| emu.exec_jumpi(),
+            14 => TamEmulator::exec_jumpif,
+            15 => return DecodedOp::Halt,
+            op => return DecodedOp::Unknown(op),
+        };
+        DecodedOp::Instruction(handler, instr)
+    }
+}
+
+/// The instructions of a program, decoded ahead of execution.
+#[derive(Clone)]
+pub struct DecodedProgram {
+    ops: Vec<DecodedOp>,
+}
+
+impl TamEmulator {
+    /// Decodes the instructions in the code store, from `CB` up to `CT`.
+    ///
+    /// The result reflects the code store at the time of the call, so should be
+    /// decoded again after [`TamEmulator::set_program`].
+    pub fn predecode(&self) -> DecodedProgram {
+        let ct = self.registers[CT] as usize;
+        DecodedProgram {
+            ops: self.code_store[..ct]
+                .iter()
+                .map(|&code| DecodedOp::from(TamInstruction::from(code)))
+                .collect(),
+        }
+    }
+
+    /// Executes the next instruction from a decoded program.
+    ///
+    /// Returns `false` once the program halts, exactly as
+    /// [`fetch_decode`](TamEmulator::fetch_decode) followed by
+    /// [`execute`](TamEmulator::execute) would.
+    #[inline]
+    pub fn step_predecoded(&mut self, program: &DecodedProgram) -> TamResult<bool> {
+        let addr = self.registers[CP];
+        if addr >= self.registers[CT] {
+            return Err(TamError::CodeAccessViolation);
+        }
+        self.registers[CP] += 1;
+
+        let op = match program.ops.get(addr as usize) {
+            Some(&op) => op,
+            None => DecodedOp::from(TamInstruction::from(self.code_store[addr as usize])),
+        };
+        match op {
+            DecodedOp::Instruction(handler, instr) => handler(self, instr)?,
+            DecodedOp::Primitive(primitive) => primitive(self)?,
+            DecodedOp::Halt => return Ok(false),
+            DecodedOp::Unknown(op) => return Err(TamError::UnknownOpcode(op)),
+        }
+        Ok(true)
+    }
+
+    /// Runs a decoded program until it halts or fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tam_rs::{ST, TamEmulator};
+    ///
+    /// let mut emu = TamEmulator::new(false);
+    /// // LOADL 3; LOADL 4; CALL add; HALT
+    /// emu.set_program(&[
+    ///     0x30, 0, 0, 3, 0x30, 0, 0, 4, 0x62, 0, 0, 8, 0xf0, 0, 0, 0,
+    /// ])
+    /// .unwrap();
+    ///
+    /// let program = emu.predecode();
+    /// emu.run_predecoded(&program).unwrap();
+    /// assert_eq!(1, emu.registers[ST]);
+    /// assert_eq!(7, emu.data_store[0]);
+    /// ```
+    pub fn run_predecoded(&mut self, program: &DecodedProgram) -> TamResult<()> {
+        while self.step_predecoded(program)? {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ST, asm, tam};
+    use rstest::*;
+
+    fn emulator(code: &[u32]) -> TamEmulator {
+        let bytes = asm::to_bytes(code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+        emu
+    }
+
+    /// Runs `code` both ways and checks that the results and final states match.
+    fn assert_equivalent(code: &[u32]) -> TamResult<()> {
+        let mut slow = emulator(code);
+        let slow_result = loop {
+            match slow.fetch_decode().and_then(|instr| slow.execute(instr)) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+
+        let mut fast = emulator(code);
+        let program = fast.predecode();
+        let fast_result = fast.run_predecoded(&program);
+
+        assert_eq!(slow_result, fast_result);
+        assert_eq!(slow.registers, fast.registers);
+        assert_eq!(slow.call_depth(), fast.call_depth());
+        let st = slow.registers[ST] as usize;
+        assert_eq!(slow.data_store[..st], fast.data_store[..st]);
+        fast_result
+    }
+
+    #[rstest]
+    fn test_run_predecoded_countdown_with_calls() {
+        // call a routine that decrements a global until it reaches 0
+        let code = tam! {
+            LOADL 50;
+            CALL(SB) 6[CB];
+            LOAD(1) 0[SB];
+            JUMPIF(0) 5[CB];
+            JUMP 1[CB];
+            HALT;
+            LOAD(1) 0[SB];
+            CALL(LB) pred[PB];
+            STORE(1) 0[SB];
+            RETURN(0) 0;
+        };
+        assert_eq!(Ok(()), assert_equivalent(&code));
+    }
+
+    #[rstest]
+    #[case::unknown_opcode(&[asm::encode(9, 0, 0, 0)], TamError::UnknownOpcode(9))]
+    #[case::off_end(&tam! { LOADL 1 }, TamError::CodeAccessViolation)]
+    #[case::bad_return(
+        &tam! { RETURN(0) 0 },
+        TamError::CorruptFrame { lb: 0, dynamic_link: 0, return_addr: 0 }
+    )]
+    fn test_run_predecoded_errors_match_execute(#[case] code: &[u32], #[case] err: TamError) {
+        assert_eq!(Err(err), assert_equivalent(code));
+    }
+}
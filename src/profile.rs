@@ -0,0 +1,501 @@
+//! Counting of executed instructions, calls and primitives.
+
+use crate::{
+    CALL, CALLI, OPCODE_NAMES, PB, PRIMITIVE_NAMES, PT, ST, TamEmulator, TamInstruction,
+    analysis::procedure_name,
+    debug_info::DebugInfo,
+    observer::{CallEvent, CallTracker, Observer},
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write},
+};
+
+/// Execution counts for a single routine, identified by its entry address.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ProcedureProfile {
+    /// Number of times the routine was called
+    pub calls: u64,
+    /// Instructions executed while the routine was active, including in its callees
+    pub inclusive: u64,
+    /// Instructions executed in the routine itself
+    pub exclusive: u64,
+}
+
+/// An [`Observer`] that counts what a program executes.
+///
+/// Routines are identified by the targets of `CALL` and `CALLI`, and the main
+/// program by address 0.
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+    /// Execution count and instruction at each address executed
+    addresses: BTreeMap<u16, (u64, TamInstruction)>,
+    opcodes: [u64; 16],
+    primitives: [u64; PRIMITIVE_NAMES.len()],
+    procedures: BTreeMap<u16, ProcedureProfile>,
+    calls: CallTracker,
+    /// Number of activations of each routine on the call stack
+    active: BTreeMap<u16, usize>,
+    /// Primitive about to be called by a `CALLI`, read before it pops its closure
+    calli_primitive: Option<usize>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Gets the total number of instructions executed.
+    pub fn instructions(&self) -> u64 {
+        self.opcodes.iter().sum()
+    }
+
+    /// Gets the number of times the instruction at `addr` was executed.
+    pub fn address_count(&self, addr: u16) -> u64 {
+        self.addresses.get(&addr).map_or(0, |(count, _)| *count)
+    }
+
+    /// Gets the number of instructions executed with the given opcode.
+    pub fn opcode_count(&self, op: u8) -> u64 {
+        self.opcodes.get(op as usize).copied().unwrap_or(0)
+    }
+
+    /// Gets the number of calls made to the primitive with the given name.
+    pub fn primitive_count(&self, name: &str) -> u64 {
+        PRIMITIVE_NAMES
+            .iter()
+            .position(|&p| p == name && !name.is_empty())
+            .map_or(0, |i| self.primitives[i])
+    }
+
+    /// Gets the counts for the routine entered at `entry`.
+    pub fn procedure(&self, entry: u16) -> Option<&ProcedureProfile> {
+        self.procedures.get(&entry)
+    }
+
+    fn enter(&mut self, entry: u16) {
+        *self.active.entry(entry).or_default() += 1;
+        self.procedures.entry(entry).or_default().calls += 1;
+    }
+
+    fn leave(&mut self, entry: u16) {
+        if let Some(count) = self.active.get_mut(&entry) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(&entry);
+            }
+        }
+    }
+
+    /// Writes the counts as text tables, each sorted with the largest counts first.
+    pub fn report(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut out = String::new();
+        let _ = self.write_report(&mut out, debug_info);
+        out
+    }
+
+    fn write_report(&self, out: &mut String, debug_info: Option<&DebugInfo>) -> fmt::Result {
+        writeln!(out, "{} instructions executed", self.instructions())?;
+
+        writeln!(
+            out,
+            "\n{:<20} {:>10} {:>12} {:>12}",
+            "procedure", "calls", "inclusive", "exclusive"
+        )?;
+        let mut procedures: Vec<_> = self.procedures.iter().collect();
+        procedures.sort_by_key(|(entry, p)| (Reverse(p.inclusive), **entry));
+        for (&entry, p) in procedures {
+            let name = procedure_name(entry, debug_info);
+            writeln!(
+                out,
+                "{name:<20} {:>10} {:>12} {:>12}",
+                p.calls, p.inclusive, p.exclusive
+            )?;
+        }
+
+        writeln!(out, "\n{:<20} {:>10}", "opcode", "count")?;
+        for (op, count) in sorted_counts(&self.opcodes) {
+            writeln!(out, "{:<20} {count:>10}", OPCODE_NAMES[op])?;
+        }
+
+        writeln!(out, "\n{:<20} {:>10}", "primitive", "calls")?;
+        for (d, count) in sorted_counts(&self.primitives) {
+            writeln!(out, "{:<20} {count:>10}", PRIMITIVE_NAMES[d])?;
+        }
+
+        writeln!(out, "\n{:<20} {:>10}  instruction", "address", "count")?;
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(addr, (count, _))| (Reverse(*count), **addr));
+        for (addr, (count, instr)) in addresses {
+            let addr = format!("{addr:#06x}");
+            writeln!(out, "{addr:<20} {count:>10}  {instr}")?;
+        }
+        Ok(())
+    }
+
+    /// Writes the counts as a JSON object.
+    ///
+    /// The object has the total `instructions` executed, and `procedures`,
+    /// `opcodes`, `primitives` and `addresses` arrays in the same order as
+    /// [`Profiler::report`].
+    pub fn to_json(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut out = String::new();
+        let _ = self.write_json(&mut out, debug_info);
+        out
+    }
+
+    fn write_json(&self, out: &mut String, debug_info: Option<&DebugInfo>) -> fmt::Result {
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"instructions\": {},", self.instructions())?;
+
+        let mut procedures: Vec<_> = self.procedures.iter().collect();
+        procedures.sort_by_key(|(entry, p)| (Reverse(p.inclusive), **entry));
+        let procedures: Vec<String> = procedures
+            .into_iter()
+            .map(|(&entry, p)| {
+                format!(
+                    "{{\"entry\": {entry}, \"name\": {}, \"calls\": {}, \"inclusive\": {}, \
+                     \"exclusive\": {}}}",
+                    json_string(&procedure_name(entry, debug_info)),
+                    p.calls,
+                    p.inclusive,
+                    p.exclusive
+                )
+            })
+            .collect();
+        write_json_array(out, "procedures", &procedures)?;
+        writeln!(out, ",")?;
+
+        let opcodes: Vec<String> = sorted_counts(&self.opcodes)
+            .map(|(op, count)| {
+                format!(
+                    "{{\"opcode\": \"{}\", \"count\": {count}}}",
+                    OPCODE_NAMES[op]
+                )
+            })
+            .collect();
+        write_json_array(out, "opcodes", &opcodes)?;
+        writeln!(out, ",")?;
+
+        let primitives: Vec<String> = sorted_counts(&self.primitives)
+            .map(|(d, count)| {
+                format!(
+                    "{{\"primitive\": \"{}\", \"calls\": {count}}}",
+                    PRIMITIVE_NAMES[d]
+                )
+            })
+            .collect();
+        write_json_array(out, "primitives", &primitives)?;
+        writeln!(out, ",")?;
+
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(addr, (count, _))| (Reverse(*count), **addr));
+        let addresses: Vec<String> = addresses
+            .into_iter()
+            .map(|(addr, (count, instr))| {
+                format!(
+                    "{{\"address\": {addr}, \"count\": {count}, \"instruction\": {}}}",
+                    json_string(&instr.to_string())
+                )
+            })
+            .collect();
+        write_json_array(out, "addresses", &addresses)?;
+        writeln!(out, "\n}}")
+    }
+}
+
+impl Observer for Profiler {
+    fn before_instruction(&mut self, emu: &TamEmulator, _addr: u16, instr: TamInstruction) {
+        self.calli_primitive = None;
+        if instr.op != CALLI {
+            return;
+        }
+
+        let Some(top) = emu.registers[ST].checked_sub(1) else {
+            return;
+        };
+        let target = emu.data_store[top as usize] as u16;
+        if target > emu.registers[PB] && target < emu.registers[PT] {
+            self.calli_primitive = Some((target - emu.registers[PB]) as usize);
+        }
+    }
+
+    fn after_instruction(&mut self, emu: &TamEmulator, addr: u16, instr: TamInstruction) {
+        if self.procedures.is_empty() {
+            self.enter(0);
+        }
+
+        self.addresses.entry(addr).or_insert((0, instr)).0 += 1;
+        self.opcodes[instr.op as usize & 0xf] += 1;
+        for entry in self.active.keys() {
+            self.procedures
+                .get_mut(entry)
+                .expect("active routine")
+                .inclusive += 1;
+        }
+        self.procedures
+            .get_mut(&self.calls.current())
+            .expect("active routine")
+            .exclusive += 1;
+
+        let primitive = match instr.op {
+            CALL if instr.r as usize == PB && instr.d > 0 => Some(instr.d as usize),
+            CALLI => self.calli_primitive.take(),
+            _ => None,
+        };
+        if let Some(id) = primitive.filter(|&id| id < PRIMITIVE_NAMES.len()) {
+            self.primitives[id] += 1;
+        }
+        match self.calls.update(emu) {
+            Some(CallEvent::Enter(entry)) => self.enter(entry),
+            Some(CallEvent::Leave(entry)) => self.leave(entry),
+            None => {}
+        }
+    }
+}
+
+/// An [`Observer`] that counts the instructions executed under each distinct call
+/// stack, for rendering as a flamegraph.
+#[derive(Clone, Debug, Default)]
+pub struct FoldedStacks {
+    calls: CallTracker,
+    /// Instructions executed under each stack of routine entry addresses
+    counts: HashMap<Vec<u16>, u64>,
+}
+
+impl FoldedStacks {
+    pub fn new() -> FoldedStacks {
+        FoldedStacks::default()
+    }
+
+    /// Writes the counts in the collapsed stack format read by flamegraph tools.
+    ///
+    /// Each line names the routines on a stack from the outermost, followed by the
+    /// instructions executed in the innermost, as in `main;fact;fact 1234`.
+    pub fn to_folded(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for (stack, count) in &self.counts {
+            let names: Vec<String> = stack
+                .iter()
+                .map(|&entry| procedure_name(entry, debug_info))
+                .collect();
+            *stacks.entry(names.join(";")).or_default() += count;
+        }
+
+        let mut out = String::new();
+        for (stack, count) in stacks {
+            let _ = writeln!(out, "{stack} {count}");
+        }
+        out
+    }
+}
+
+impl Observer for FoldedStacks {
+    fn after_instruction(&mut self, emu: &TamEmulator, _: u16, _: TamInstruction) {
+        match self.counts.get_mut(self.calls.stack()) {
+            Some(count) => *count += 1,
+            None => {
+                self.counts.insert(self.calls.stack().to_vec(), 1);
+            }
+        }
+        self.calls.update(emu);
+    }
+}
+
+/// Gets the non-zero counts with their indices, largest first.
+fn sorted_counts(counts: &[u64]) -> impl Iterator<Item = (usize, u64)> {
+    let mut sorted: Vec<(usize, u64)> = counts
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    sorted.sort_by_key(|&(i, count)| (Reverse(count), i));
+    sorted.into_iter()
+}
+
+fn write_json_array(out: &mut String, name: &str, items: &[String]) -> fmt::Result {
+    if items.is_empty() {
+        return write!(out, "  \"{name}\": []");
+    }
+    writeln!(out, "  \"{name}\": [")?;
+    for (i, item) in items.iter().enumerate() {
+        let sep = if i + 1 < items.len() { "," } else { "" };
+        writeln!(out, "    {item}{sep}")?;
+    }
+    write!(out, "  ]")
+}
+
+/// Quotes a string for use in JSON.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, debug_info::Procedure, io::MemoryIo, tam};
+    use rstest::*;
+
+    /// Calls a routine twice, which calls `not` each time.
+    fn profile() -> Profiler {
+        let code = tam! {
+            CALL(SB) 3[CB];
+            CALL(SB) 3[CB];
+            HALT;
+            LOADL 1;
+            CALL(SB) not[PB];
+            RETURN(0) 0;
+        };
+        let bytes = asm::to_bytes(&code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+
+        let mut profiler = Profiler::new();
+        while emu.step(&mut [&mut profiler]).unwrap() {}
+        profiler
+    }
+
+    #[rstest]
+    fn test_profiler_counts_instructions() {
+        let profiler = profile();
+        assert_eq!(9, profiler.instructions());
+        assert_eq!(1, profiler.address_count(0));
+        assert_eq!(2, profiler.address_count(4));
+        assert_eq!(4, profiler.opcode_count(CALL));
+        assert_eq!(2, profiler.primitive_count("not"));
+        assert_eq!(0, profiler.primitive_count("add"));
+    }
+
+    #[rstest]
+    fn test_profiler_counts_procedures() {
+        let profiler = profile();
+        assert_eq!(
+            Some(&ProcedureProfile {
+                calls: 1,
+                inclusive: 9,
+                exclusive: 3
+            }),
+            profiler.procedure(0)
+        );
+        assert_eq!(
+            Some(&ProcedureProfile {
+                calls: 2,
+                inclusive: 6,
+                exclusive: 6
+            }),
+            profiler.procedure(3)
+        );
+    }
+
+    #[rstest]
+    fn test_profiler_report_sorted() {
+        let report = profile().report(None);
+        let main = report.find("\nmain ").unwrap();
+        let routine = report.find("\nproc_0x0003 ").unwrap();
+        assert!(main < routine);
+        assert!(report.contains("\nnot                           2\n"));
+    }
+
+    #[rstest]
+    fn test_profiler_json() {
+        let json = profile().to_json(None);
+        assert!(json.starts_with("{\n  \"instructions\": 9,\n"));
+        assert!(json.contains(
+            "{\"entry\": 3, \"name\": \"proc_0x0003\", \"calls\": 2, \"inclusive\": 6, \"exclusive\": 6}"
+        ));
+        assert!(json.contains("{\"opcode\": \"CALL\", \"count\": 4}"));
+        assert!(json.contains("{\"primitive\": \"not\", \"calls\": 2}"));
+        assert!(json.contains("{\"address\": 0, \"count\": 1, \"instruction\": \"CALL(4) 3[0]\"}"));
+    }
+
+    #[rstest]
+    fn test_folded_stacks() {
+        // main calls a routine, which calls itself once through a closure
+        let code = tam! {
+            LOADL 0;
+            CALL(SB) 3[CB];
+            HALT;
+            LOAD(1) -1[LB];
+            JUMPIF(1) 9[CB];
+            LOADL 1;
+            LOADA 0[SB];
+            LOADA 3[CB];
+            CALLI;
+            RETURN(0) 1;
+        };
+        let bytes = asm::to_bytes(&code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+
+        let mut folded = FoldedStacks::new();
+        while emu.step(&mut [&mut folded]).unwrap() {}
+
+        let mut info = DebugInfo::default();
+        info.add_procedure(Procedure {
+            name: "f".into(),
+            start: 3,
+            end: 10,
+        });
+        assert_eq!(
+            "main 3\nmain;f 7\nmain;f;f 3\n",
+            folded.to_folded(Some(&info))
+        );
+        assert_eq!(
+            "main 3\nmain;proc_0x0003 7\nmain;proc_0x0003;proc_0x0003 3\n",
+            folded.to_folded(None)
+        );
+    }
+
+    #[rstest]
+    fn test_profiler_counts_primitives_called_through_closures() {
+        // putint called directly, then twice through a closure as Triangle passes it
+        let code = tam! {
+            LOADL 1;
+            CALL(SB) putint[PB];
+            LOADL 2;
+            LOADA 0[SB];
+            LOADA putint[PB];
+            CALLI;
+            LOADL 3;
+            LOADA 0[SB];
+            LOADA putint[PB];
+            CALLI;
+            LOADA 0[SB];
+            LOADA routine[CB];
+            CALLI;
+            HALT;
+        routine:
+            RETURN(0) 0;
+        };
+        let mut emu = TamEmulator::new(false);
+        emu.set_io(MemoryIo::new(""));
+        emu.set_program(&asm::to_bytes(&code)).unwrap();
+
+        let mut profiler = Profiler::new();
+        while emu.step(&mut [&mut profiler]).unwrap() {}
+
+        assert_eq!(3, profiler.primitive_count("putint"));
+        assert_eq!(1, profiler.procedure(14).unwrap().calls);
+    }
+
+    #[rstest]
+    #[case("plain", "\"plain\"")]
+    #[case("a\"b\\c\n", "\"a\\\"b\\\\c\\u000a\"")]
+    fn test_json_string(#[case] s: &str, #[case] expected: &str) {
+        assert_eq!(expected, json_string(s));
+    }
+}
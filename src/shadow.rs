@@ -0,0 +1,148 @@
+//! Detection of reads from data words that were never written.
+//!
+//! The data store is zero-filled, so without checking a program that reads a
+//! variable before assigning to it silently sees 0. When checking is enabled a shadow
+//! bitmap records which words hold a value written by the program: words pushed onto
+//! the stack or stored to are marked, and words popped off the stack are unmarked, so
+//! the stale contents of old frames count as unwritten. Space reserved by `PUSH` is
+//! not marked.
+
+use crate::{
+    MEMORY_SIZE, TamEmulator,
+    errors::{TamError, TamResult},
+};
+
+/// One bit per word of the data store, set when the word has been written.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ShadowMemory {
+    bits: Vec<u64>,
+}
+
+impl ShadowMemory {
+    fn new() -> ShadowMemory {
+        ShadowMemory {
+            bits: vec![0; MEMORY_SIZE / 64],
+        }
+    }
+
+    fn set(&mut self, addr: u16, written: bool) {
+        let (word, bit) = (addr as usize / 64, addr % 64);
+        if written {
+            self.bits[word] |= 1 << bit;
+        } else {
+            self.bits[word] &= !(1 << bit);
+        }
+    }
+
+    fn is_written(&self, addr: u16) -> bool {
+        self.bits[addr as usize / 64] & (1 << (addr % 64)) != 0
+    }
+}
+
+impl TamEmulator {
+    /// Enables or disables checking for reads of data words that were never written.
+    ///
+    /// While enabled, a `LOAD` or `LOADI` of an unwritten word fails with
+    /// [`TamError::UninitialisedRead`]. Only writes made by executing instructions
+    /// are recorded, so this should be enabled before the program starts.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tam_rs::{TamEmulator, errors::TamError};
+    ///
+    /// let mut emu = TamEmulator::new(false);
+    /// // PUSH 1; LOAD(1) 0[SB]
+    /// emu.set_program(&[0xa0, 0, 0, 1, 0x04, 1, 0, 0]).unwrap();
+    /// emu.set_check_uninit(true);
+    ///
+    /// let instr = emu.fetch_decode().unwrap();
+    /// emu.execute(instr).unwrap();
+    /// let instr = emu.fetch_decode().unwrap();
+    /// assert_eq!(Err(TamError::UninitialisedRead(0)), emu.execute(instr));
+    /// ```
+    pub fn set_check_uninit(&mut self, enabled: bool) {
+        self.shadow = enabled.then(ShadowMemory::new);
+    }
+
+    /// Records whether the data word at `addr` holds a value written by the program.
+    pub(crate) fn mark_written(&mut self, addr: u16, written: bool) {
+        if let Some(shadow) = &mut self.shadow {
+            shadow.set(addr, written);
+        }
+    }
+
+    /// Checks that the data word at `addr` may be read.
+    pub(crate) fn check_initialised(&self, addr: u16) -> TamResult<()> {
+        match &self.shadow {
+            Some(shadow) if !shadow.is_written(addr) => Err(TamError::UninitialisedRead(addr)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, tam};
+    use rstest::*;
+
+    fn run(code: &[u32], check: bool) -> TamResult<()> {
+        let bytes = asm::to_bytes(code);
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&bytes).unwrap();
+        emu.set_check_uninit(check);
+
+        while emu.fetch_decode().and_then(|instr| emu.execute(instr))? {}
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_check_uninit_reserved_global_err() {
+        let code = tam! {
+            PUSH 2;
+            LOAD(2) 0[SB];
+            HALT;
+        };
+        assert_eq!(Err(TamError::UninitialisedRead(0)), run(&code, true));
+        assert_eq!(Ok(()), run(&code, false));
+    }
+
+    #[rstest]
+    fn test_check_uninit_stored_global_ok() {
+        let code = tam! {
+            PUSH 1;
+            LOADL 5;
+            STORE(1) 0[SB];
+            LOAD(1) 0[SB];
+            HALT;
+        };
+        assert_eq!(Ok(()), run(&code, true));
+    }
+
+    #[rstest]
+    fn test_check_uninit_stale_stack_word_err() {
+        // the word at 1 is written, popped and then reserved again
+        let code = tam! {
+            PUSH 1;
+            LOADL 5;
+            POP(0) 1;
+            PUSH 1;
+            LOADL 1;
+            LOADI(1);
+            HALT;
+        };
+        assert_eq!(Err(TamError::UninitialisedRead(1)), run(&code, true));
+    }
+
+    #[rstest]
+    fn test_check_uninit_partial_read_reports_first_unwritten() {
+        let code = tam! {
+            LOADL 5;
+            PUSH 1;
+            LOAD(2) 0[LB];
+            HALT;
+        };
+        assert_eq!(Err(TamError::UninitialisedRead(1)), run(&code, true));
+    }
+}
@@ -0,0 +1,277 @@
+//! A library of routines written in TAM code, for linking with programs that use them.
+//!
+//! The library extends the primitives with routines for strings, held as arrays of
+//! `Char` with one character to a word, and for arrays of integers:
+//!
+//! | Routine                  | Effect                                                   |
+//! |--------------------------|----------------------------------------------------------|
+//! | `putstr(s, n)`           | writes the `n` characters from `s`                       |
+//! | `getline(s, max) -> len` | reads up to `max` characters of a line into `s`          |
+//! | `strcmp(s, t, n) -> ord` | compares `n` characters, giving -1, 0 or 1               |
+//! | `reverse(a, n)`          | reverses `n` words in place                              |
+//! | `itoa(i, s) -> len`      | writes `i` in decimal to `s`, which needs room for six   |
+//! | `copy(src, dst, n)`      | copies `n` words, which may overlap                      |
+//! | `fill(a, n, x)`          | sets `n` words to `x`                                    |
+//! | `sort(a, n)`             | sorts `n` words into ascending order                     |
+//!
+//! Arrays are passed by address. A program calls a routine as compiled Triangle code
+//! calls a procedure, pushing its arguments in order and importing its name at a
+//! `CALL(SB) 0[CB]`. The code is kept in the object file format, with the assembly it
+//! was written in alongside each instruction.
+
+use crate::link::ObjectFile;
+
+/// The object file of the library.
+pub const OBJECT_FILE: &str = include_str!("stdlib.obj");
+
+/// The names of the routines the library exports.
+pub const ROUTINES: [&str; 8] = [
+    "putstr", "getline", "strcmp", "reverse", "itoa", "copy", "fill", "sort",
+];
+
+/// Gets the library as a module to link after a program's own.
+///
+/// # Example
+///
+/// ```
+/// use tam_rs::{link, stdlib};
+///
+/// let main = link::ObjectFile::parse(
+///     "import putstr 3
+///      code 0x30000048  # LOADL 'H'
+///      code 0x14000000  # LOADA 0[SB]
+///      code 0x30000001  # LOADL 1
+///      code 0x60040000  # CALL(SB) putstr[CB]
+///      code 0xf0000000  # HALT",
+/// )
+/// .unwrap();
+///
+/// let linked = link::link(&[main, stdlib::module()]).unwrap();
+/// assert_eq!(Some(&5), linked.symbols.get("putstr"));
+/// ```
+pub fn module() -> ObjectFile {
+    ObjectFile::parse(OBJECT_FILE).expect("library object file is well formed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LOADA, LOADL, SB, TamEmulator, asm, io::MemoryIo, link::link, tam};
+    use rstest::*;
+
+    /// `LOADL value`, for values that are only known at run time.
+    fn load_l(value: i16) -> u32 {
+        asm::encode(LOADL, 0, 0, value)
+    }
+
+    /// `LOADA addr[SB]`, for addresses that are only known at run time.
+    fn load_a(addr: i16) -> u32 {
+        asm::encode(LOADA, SB as u8, 0, addr)
+    }
+
+    /// Marks a call to the library routine named by the following import.
+    fn call() -> Vec<u32> {
+        tam! { CALL(SB) 0[CB] }
+    }
+
+    fn chars(text: &str) -> Vec<i16> {
+        text.bytes().map(i16::from).collect()
+    }
+
+    /// Runs a program that pushes `data` to address 0 upwards and then runs `body`,
+    /// with the calls in it linked to `routines` in order, returning its output and
+    /// any input left unread.
+    fn run(data: &[i16], body: &[u32], routines: &[&str], input: &str) -> (String, Vec<u8>) {
+        let mut code: Vec<u32> = data.iter().map(|&value| load_l(value)).collect();
+        let marker = call()[0];
+        let calls = (code.len()..).zip(body).filter(|&(_, &i)| i == marker);
+        let imports = calls
+            .map(|(addr, _)| addr as u16)
+            .zip(routines.iter().map(|name| name.to_string()))
+            .collect();
+        code.extend(body);
+        code.extend(tam! { HALT });
+        let main = ObjectFile {
+            code,
+            imports,
+            ..ObjectFile::default()
+        };
+        let linked = link(&[main, module()]).unwrap();
+
+        let io = MemoryIo::new(input);
+        let mut emu = TamEmulator::new(false);
+        emu.set_io(io.clone());
+        emu.set_program(&linked.to_bytes()).unwrap();
+        while emu.fetch_decode().and_then(|i| emu.execute(i)).unwrap() {}
+        (io.output_string(), io.remaining_input())
+    }
+
+    /// Writes each of the `n` words from address 0 as an integer followed by a space.
+    fn put_words(n: i16) -> Vec<u32> {
+        (0..n)
+            .flat_map(|i| {
+                let mut code = vec![load_a(i)];
+                code.extend(tam! {
+                    LOADI(1);
+                    CALL(SB) putint[PB];
+                    LOADL ' ';
+                    CALL(SB) put[PB];
+                });
+                code
+            })
+            .collect()
+    }
+
+    #[rstest]
+    fn test_module_exports_routines() {
+        let module = module();
+        let mut exports: Vec<&str> = module.exports.keys().map(String::as_str).collect();
+        let mut routines = ROUTINES.to_vec();
+        exports.sort();
+        routines.sort();
+        assert_eq!(routines, exports);
+        assert_eq!(0, module.globals);
+    }
+
+    #[rstest]
+    fn test_putstr() {
+        let body = tam! { LOADA 0[SB]; LOADL 5; CALL(SB) 0[CB] };
+        assert_eq!("hello", run(&chars("hello"), &body, &["putstr"], "").0);
+    }
+
+    #[rstest]
+    #[case("abc\ndef", 10, "abc|3", "def")]
+    #[case("abcdef", 4, "abcd|4", "ef")]
+    #[case("ab", 10, "ab|2", "")]
+    #[case("\nx", 10, "|0", "x")]
+    #[case("", 10, "|0", "")]
+    fn test_getline(
+        #[case] input: &str,
+        #[case] max: i16,
+        #[case] output: &str,
+        #[case] remaining: &str,
+    ) {
+        let body = [
+            vec![load_a(0), load_l(max)],
+            tam! {
+                CALL(SB) 0[CB];
+                LOADA 0[SB];
+                LOAD(1) -2[ST];
+                CALL(SB) 0[CB];
+                LOADL '|';
+                CALL(SB) put[PB];
+                CALL(SB) putint[PB];
+            },
+        ]
+        .concat();
+        let (out, rest) = run(&[0; 10], &body, &["getline", "putstr"], input);
+        assert_eq!(output, out);
+        assert_eq!(remaining.as_bytes(), rest);
+    }
+
+    #[rstest]
+    #[case("abc", "abd", "-1")]
+    #[case("abc", "abc", "0")]
+    #[case("b", "a", "1")]
+    #[case("", "", "0")]
+    fn test_strcmp(#[case] s: &str, #[case] t: &str, #[case] order: &str) {
+        let n = s.len() as i16;
+        let data = [chars(s), chars(t)].concat();
+        let body = [
+            vec![load_a(0), load_a(n), load_l(n)],
+            tam! { CALL(SB) 0[CB]; CALL(SB) putint[PB] },
+        ]
+        .concat();
+        assert_eq!(order, run(&data, &body, &["strcmp"], "").0);
+    }
+
+    #[rstest]
+    #[case("", "")]
+    #[case("a", "a")]
+    #[case("ab", "ba")]
+    #[case("abcde", "edcba")]
+    fn test_reverse(#[case] text: &str, #[case] reversed: &str) {
+        let n = text.len() as i16;
+        let body = [
+            vec![load_a(0), load_l(n)],
+            call(),
+            vec![load_a(0), load_l(n)],
+            call(),
+        ]
+        .concat();
+        assert_eq!(
+            reversed,
+            run(&chars(text), &body, &["reverse", "putstr"], "").0
+        );
+    }
+
+    #[rstest]
+    #[case(0)]
+    #[case(7)]
+    #[case(-42)]
+    #[case(1000)]
+    #[case(i16::MAX)]
+    #[case(i16::MIN)]
+    fn test_itoa(#[case] value: i16) {
+        let body = [
+            vec![load_l(value)],
+            tam! {
+                LOADA 0[SB];
+                CALL(SB) 0[CB];
+                LOADA 0[SB];
+                LOAD(1) -2[ST];
+                CALL(SB) 0[CB];
+            },
+        ]
+        .concat();
+        assert_eq!(
+            value.to_string(),
+            run(&[0; 6], &body, &["itoa", "putstr"], "").0
+        );
+    }
+
+    #[rstest]
+    #[case(0, 3, 3, "abcabc")]
+    #[case(0, 2, 3, "ababcf")]
+    #[case(2, 0, 3, "cdedef")]
+    #[case(1, 4, 0, "abcdef")]
+    fn test_copy(#[case] src: i16, #[case] dst: i16, #[case] n: i16, #[case] result: &str) {
+        let body = [
+            vec![load_a(src), load_a(dst), load_l(n)],
+            tam! { CALL(SB) 0[CB]; LOADA 0[SB]; LOADL 6; CALL(SB) 0[CB] },
+        ]
+        .concat();
+        assert_eq!(
+            result,
+            run(&chars("abcdef"), &body, &["copy", "putstr"], "").0
+        );
+    }
+
+    #[rstest]
+    #[case(1, 3, "axxxef")]
+    #[case(0, 6, "xxxxxx")]
+    #[case(2, 0, "abcdef")]
+    fn test_fill(#[case] start: i16, #[case] n: i16, #[case] result: &str) {
+        let body = [
+            vec![load_a(start), load_l(n)],
+            tam! { LOADL 'x'; CALL(SB) 0[CB]; LOADA 0[SB]; LOADL 6; CALL(SB) 0[CB] },
+        ]
+        .concat();
+        assert_eq!(
+            result,
+            run(&chars("abcdef"), &body, &["fill", "putstr"], "").0
+        );
+    }
+
+    #[rstest]
+    #[case(&[], "")]
+    #[case(&[4], "4 ")]
+    #[case(&[5, -3, 9, 0, -3], "-3 -3 0 5 9 ")]
+    #[case(&[3, 2, 1], "1 2 3 ")]
+    #[case(&[i16::MAX, i16::MIN], "-32768 32767 ")]
+    fn test_sort(#[case] data: &[i16], #[case] result: &str) {
+        let n = data.len() as i16;
+        let body = [vec![load_a(0), load_l(n)], call(), put_words(n)].concat();
+        assert_eq!(result, run(data, &body, &["sort"], "").0);
+    }
+}
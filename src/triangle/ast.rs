@@ -0,0 +1,203 @@
+use super::Position;
+use std::fmt::{self, Display};
+
+/// Index of a declaration in the table built by the checker.
+pub(super) type DeclId = usize;
+
+/// A Triangle type, after type denoters have been resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Type {
+    /// The type of an ill-typed phrase, compatible with every other type
+    Error,
+    Boolean,
+    Char,
+    Integer,
+    Array(u16, Box<Type>),
+    Record(Vec<(String, Type)>),
+}
+
+impl Type {
+    /// Gets the number of words taken by a value of this type.
+    pub fn size(&self) -> u16 {
+        match self {
+            Type::Error => 0,
+            Type::Boolean | Type::Char | Type::Integer => 1,
+            Type::Array(count, elem) => count.saturating_mul(elem.size()),
+            Type::Record(fields) => fields
+                .iter()
+                .fold(0, |size, (_, ty)| size.saturating_add(ty.size())),
+        }
+    }
+
+    /// Checks structural equivalence, treating `Error` as equal to anything.
+    pub fn equals(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::Error, _) | (_, Type::Error) => true,
+            (Type::Array(n1, e1), Type::Array(n2, e2)) => n1 == n2 && e1.equals(e2),
+            (Type::Record(f1), Type::Record(f2)) => {
+                f1.len() == f2.len()
+                    && f1
+                        .iter()
+                        .zip(f2)
+                        .all(|((n1, t1), (n2, t2))| n1 == n2 && t1.equals(t2))
+            }
+            (t1, t2) => t1 == t2,
+        }
+    }
+}
+
+impl Display for Type {
+    /// Writes the type without whitespace, so that it fits in a debug info record.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Error => write!(f, "<error>"),
+            Type::Boolean => write!(f, "Boolean"),
+            Type::Char => write!(f, "Char"),
+            Type::Integer => write!(f, "Integer"),
+            Type::Array(count, elem) => write!(f, "{elem}[{count}]"),
+            Type::Record(fields) => {
+                write!(f, "record{{")?;
+                for (i, (name, ty)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{name}:{ty}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// The signature of a formal parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum ParamSig {
+    Value(Type),
+    Var(Type),
+    Proc(Vec<ParamSig>),
+    Func(Vec<ParamSig>, Type),
+}
+
+impl ParamSig {
+    pub fn equals(&self, other: &ParamSig) -> bool {
+        match (self, other) {
+            (ParamSig::Value(t1), ParamSig::Value(t2)) | (ParamSig::Var(t1), ParamSig::Var(t2)) => {
+                t1.equals(t2)
+            }
+            (ParamSig::Proc(p1), ParamSig::Proc(p2)) => sigs_equal(p1, p2),
+            (ParamSig::Func(p1, r1), ParamSig::Func(p2, r2)) => sigs_equal(p1, p2) && r1.equals(r2),
+            _ => false,
+        }
+    }
+}
+
+pub(super) fn sigs_equal(s1: &[ParamSig], s2: &[ParamSig]) -> bool {
+    s1.len() == s2.len() && s1.iter().zip(s2).all(|(p1, p2)| p1.equals(p2))
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Identifier {
+    pub name: String,
+    pub position: Position,
+    /// Declaration the identifier refers to, filled in by the checker
+    pub decl: Option<DeclId>,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Command {
+    pub kind: CommandKind,
+    pub position: Position,
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum CommandKind {
+    Empty,
+    Assign(Vname, Expression),
+    Call(Identifier, Vec<ActualParam>),
+    Sequence(Vec<Command>),
+    Let(Vec<Declaration>, Box<Command>),
+    If(Expression, Box<Command>, Box<Command>),
+    While(Expression, Box<Command>),
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Expression {
+    pub kind: ExpressionKind,
+    pub position: Position,
+    /// Type of the expression, filled in by the checker
+    pub ty: Type,
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum ExpressionKind {
+    IntLiteral(i16),
+    CharLiteral(u8),
+    Vname(Vname),
+    Call(Identifier, Vec<ActualParam>),
+    Unary(Identifier, Box<Expression>),
+    Binary(Box<Expression>, Identifier, Box<Expression>),
+    Let(Vec<Declaration>, Box<Expression>),
+    If(Box<Expression>, Box<Expression>, Box<Expression>),
+    Record(Vec<(Identifier, Expression)>),
+    Array(Vec<Expression>),
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Vname {
+    pub kind: VnameKind,
+    pub position: Position,
+    /// Type of the value named, filled in by the checker
+    pub ty: Type,
+    /// Whether the name can be assigned to, filled in by the checker
+    pub variable: bool,
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum VnameKind {
+    Simple(Identifier),
+    Field(Box<Vname>, Identifier),
+    Index(Box<Vname>, Box<Expression>),
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum Declaration {
+    Const(Identifier, Expression),
+    Var(Identifier, TypeDenoter),
+    Proc(Identifier, Vec<FormalParam>, Command),
+    Func(Identifier, Vec<FormalParam>, TypeDenoter, Expression),
+    Type(Identifier, TypeDenoter),
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum FormalParam {
+    Value(Identifier, TypeDenoter),
+    Var(Identifier, TypeDenoter),
+    Proc(Identifier, Vec<FormalParam>),
+    Func(Identifier, Vec<FormalParam>, TypeDenoter),
+}
+
+impl FormalParam {
+    pub fn identifier(&self) -> &Identifier {
+        match self {
+            FormalParam::Value(id, _)
+            | FormalParam::Var(id, _)
+            | FormalParam::Proc(id, _)
+            | FormalParam::Func(id, ..) => id,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum ActualParam {
+    Value(Expression),
+    Var(Vname),
+    Proc(Identifier),
+    Func(Identifier),
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum TypeDenoter {
+    Named(Identifier),
+    Array(Position, i16, Box<TypeDenoter>),
+    Record(Vec<(Identifier, TypeDenoter)>),
+}
@@ -0,0 +1,539 @@
+use super::{CompileError, Position, ast::*, stdenv};
+use std::collections::HashMap;
+
+/// What an identifier has been declared as.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum Binding {
+    Const(Type),
+    /// A variable or value parameter
+    Var(Type),
+    VarParam(Type),
+    Routine {
+        params: Vec<ParamSig>,
+        /// Result type if the routine is a function
+        result: Option<Type>,
+    },
+    Type(Type),
+}
+
+/// Checks the scope and type rules of a program, decorating its tree.
+///
+/// Returns the bindings of every declaration, indexed by the `DeclId`s stored in the
+/// tree's identifiers. The standard environment comes first, in the order given by
+/// [`stdenv::declarations`].
+pub(super) fn check(program: &mut Command) -> Result<Vec<Binding>, Vec<CompileError>> {
+    let mut checker = Checker {
+        bindings: Vec::new(),
+        scopes: vec![HashMap::new()],
+        errors: Vec::new(),
+    };
+
+    for (name, decl) in stdenv::declarations() {
+        let binding = match decl {
+            stdenv::StdDecl::Type(ty) => Binding::Type(ty),
+            stdenv::StdDecl::Const(ty, _) => Binding::Const(ty),
+            stdenv::StdDecl::Routine(params, result, _) => Binding::Routine { params, result },
+        };
+        checker.bindings.push(binding);
+        checker.scopes[0].insert(name.to_string(), checker.bindings.len() - 1);
+    }
+
+    checker.command(program);
+    if checker.errors.is_empty() {
+        Ok(checker.bindings)
+    } else {
+        Err(checker.errors)
+    }
+}
+
+struct Checker {
+    bindings: Vec<Binding>,
+    scopes: Vec<HashMap<String, DeclId>>,
+    errors: Vec<CompileError>,
+}
+
+impl Checker {
+    fn error(&mut self, position: Position, message: impl Into<String>) {
+        self.errors.push(CompileError::new(position, message));
+    }
+
+    /// Declares an identifier in the scope `depth` levels out from the innermost.
+    fn declare_at(&mut self, depth: usize, id: &mut Identifier, binding: Binding) {
+        let scope = self.scopes.len() - 1 - depth;
+        if self.scopes[scope].contains_key(&id.name) {
+            self.error(id.position, format!("`{}` is already declared", id.name));
+        }
+
+        self.bindings.push(binding);
+        id.decl = Some(self.bindings.len() - 1);
+        self.scopes[scope].insert(id.name.clone(), self.bindings.len() - 1);
+    }
+
+    fn declare(&mut self, id: &mut Identifier, binding: Binding) {
+        self.declare_at(0, id, binding)
+    }
+
+    fn lookup(&mut self, id: &mut Identifier) -> Option<Binding> {
+        let decl = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&id.name).copied());
+        match decl {
+            Some(decl) => {
+                id.decl = Some(decl);
+                Some(self.bindings[decl].clone())
+            }
+            None => {
+                self.error(id.position, format!("`{}` is not declared", id.name));
+                None
+            }
+        }
+    }
+
+    fn expect_type(&mut self, position: Position, expected: &Type, actual: &Type) {
+        if !expected.equals(actual) {
+            self.error(position, format!("expected {expected}, found {actual}"));
+        }
+    }
+
+    fn command(&mut self, command: &mut Command) {
+        match &mut command.kind {
+            CommandKind::Empty => {}
+            CommandKind::Assign(vname, expr) => {
+                let target = self.vname(vname);
+                let value = self.expression(expr);
+                if !vname.variable {
+                    self.error(vname.position, "cannot assign to a constant");
+                }
+                self.expect_type(expr.position, &target, &value);
+            }
+            CommandKind::Call(id, args) => match self.lookup(id) {
+                Some(Binding::Routine {
+                    params,
+                    result: None,
+                }) => self.actual_params(id.position, &params, args),
+                binding => {
+                    if binding.is_some() {
+                        self.error(id.position, format!("`{}` is not a procedure", id.name));
+                    }
+                    self.unchecked_params(args);
+                }
+            },
+            CommandKind::Sequence(commands) => commands.iter_mut().for_each(|c| self.command(c)),
+            CommandKind::Let(decls, body) => {
+                self.scopes.push(HashMap::new());
+                self.declarations(decls);
+                self.command(body);
+                self.scopes.pop();
+            }
+            CommandKind::If(cond, then_branch, else_branch) => {
+                let ty = self.expression(cond);
+                self.expect_type(cond.position, &Type::Boolean, &ty);
+                self.command(then_branch);
+                self.command(else_branch);
+            }
+            CommandKind::While(cond, body) => {
+                let ty = self.expression(cond);
+                self.expect_type(cond.position, &Type::Boolean, &ty);
+                self.command(body);
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &mut Expression) -> Type {
+        let ty = match &mut expr.kind {
+            ExpressionKind::IntLiteral(_) => Type::Integer,
+            ExpressionKind::CharLiteral(_) => Type::Char,
+            ExpressionKind::Vname(vname) => self.vname(vname),
+            ExpressionKind::Call(id, args) => match self.lookup(id) {
+                Some(Binding::Routine {
+                    params,
+                    result: Some(result),
+                }) => {
+                    self.actual_params(id.position, &params, args);
+                    result
+                }
+                binding => {
+                    if binding.is_some() {
+                        self.error(id.position, format!("`{}` is not a function", id.name));
+                    }
+                    self.unchecked_params(args);
+                    Type::Error
+                }
+            },
+            ExpressionKind::Unary(op, operand) => {
+                let actual = self.expression(operand);
+                match stdenv::unary_operator(&op.name) {
+                    Some((expected, result, _)) => {
+                        self.expect_type(operand.position, &expected, &actual);
+                        result
+                    }
+                    None => {
+                        self.error(op.position, format!("unknown unary operator `{}`", op.name));
+                        Type::Error
+                    }
+                }
+            }
+            ExpressionKind::Binary(lhs, op, rhs) => {
+                let lhs_ty = self.expression(lhs);
+                let rhs_ty = self.expression(rhs);
+                match stdenv::binary_operator(&op.name) {
+                    Some((Some(expected), result, _)) => {
+                        self.expect_type(lhs.position, &expected, &lhs_ty);
+                        self.expect_type(rhs.position, &expected, &rhs_ty);
+                        result
+                    }
+                    Some((None, result, _)) => {
+                        self.expect_type(rhs.position, &lhs_ty, &rhs_ty);
+                        result
+                    }
+                    None => {
+                        self.error(
+                            op.position,
+                            format!("unknown binary operator `{}`", op.name),
+                        );
+                        Type::Error
+                    }
+                }
+            }
+            ExpressionKind::Let(decls, body) => {
+                self.scopes.push(HashMap::new());
+                self.declarations(decls);
+                let ty = self.expression(body);
+                self.scopes.pop();
+                ty
+            }
+            ExpressionKind::If(cond, then_branch, else_branch) => {
+                let cond_ty = self.expression(cond);
+                self.expect_type(cond.position, &Type::Boolean, &cond_ty);
+                let ty = self.expression(then_branch);
+                let else_ty = self.expression(else_branch);
+                self.expect_type(else_branch.position, &ty, &else_ty);
+                ty
+            }
+            ExpressionKind::Record(fields) => {
+                let mut types: Vec<(String, Type)> = Vec::new();
+                for (id, value) in fields {
+                    if types.iter().any(|(name, _)| *name == id.name) {
+                        self.error(id.position, format!("duplicate field `{}`", id.name));
+                    }
+                    let ty = self.expression(value);
+                    types.push((id.name.clone(), ty));
+                }
+                Type::Record(types)
+            }
+            ExpressionKind::Array(elems) => {
+                let ty = self.expression(&mut elems[0]);
+                for elem in &mut elems[1..] {
+                    let elem_ty = self.expression(elem);
+                    self.expect_type(elem.position, &ty, &elem_ty);
+                }
+                Type::Array(elems.len() as u16, Box::new(ty))
+            }
+        };
+
+        expr.ty = ty.clone();
+        ty
+    }
+
+    fn vname(&mut self, vname: &mut Vname) -> Type {
+        let (ty, variable) = match &mut vname.kind {
+            VnameKind::Simple(id) => match self.lookup(id) {
+                Some(Binding::Const(ty)) => (ty, false),
+                Some(Binding::Var(ty) | Binding::VarParam(ty)) => (ty, true),
+                // treat bad names as variables, so they cause no further errors
+                Some(_) => {
+                    self.error(id.position, format!("`{}` is not a value", id.name));
+                    (Type::Error, true)
+                }
+                None => (Type::Error, true),
+            },
+            VnameKind::Field(record, id) => {
+                let ty = match self.vname(record) {
+                    Type::Record(fields) => match fields.into_iter().find(|(n, _)| *n == id.name) {
+                        Some((_, ty)) => ty,
+                        None => {
+                            self.error(id.position, format!("no field named `{}`", id.name));
+                            Type::Error
+                        }
+                    },
+                    Type::Error => Type::Error,
+                    ty => {
+                        self.error(record.position, format!("expected record, found {ty}"));
+                        Type::Error
+                    }
+                };
+                (ty, record.variable)
+            }
+            VnameKind::Index(array, index) => {
+                let ty = match self.vname(array) {
+                    Type::Array(_, elem) => *elem,
+                    Type::Error => Type::Error,
+                    ty => {
+                        self.error(array.position, format!("expected array, found {ty}"));
+                        Type::Error
+                    }
+                };
+                let index_ty = self.expression(index);
+                self.expect_type(index.position, &Type::Integer, &index_ty);
+                (ty, array.variable)
+            }
+        };
+
+        vname.ty = ty.clone();
+        vname.variable = variable;
+        ty
+    }
+
+    fn declarations(&mut self, decls: &mut [Declaration]) {
+        for decl in decls {
+            match decl {
+                Declaration::Const(id, value) => {
+                    let ty = self.expression(value);
+                    self.declare(id, Binding::Const(ty));
+                }
+                Declaration::Var(id, ty) => {
+                    let ty = self.type_denoter(ty);
+                    self.declare(id, Binding::Var(ty));
+                }
+                Declaration::Proc(id, params, body) => {
+                    self.scopes.push(HashMap::new());
+                    let params = self.formal_params(params);
+                    self.declare_at(
+                        1,
+                        id,
+                        Binding::Routine {
+                            params,
+                            result: None,
+                        },
+                    );
+                    self.command(body);
+                    self.scopes.pop();
+                }
+                Declaration::Func(id, params, result, body) => {
+                    let result = self.type_denoter(result);
+                    self.scopes.push(HashMap::new());
+                    let params = self.formal_params(params);
+                    self.declare_at(
+                        1,
+                        id,
+                        Binding::Routine {
+                            params,
+                            result: Some(result.clone()),
+                        },
+                    );
+                    let ty = self.expression(body);
+                    self.expect_type(body.position, &result, &ty);
+                    self.scopes.pop();
+                }
+                Declaration::Type(id, ty) => {
+                    let ty = self.type_denoter(ty);
+                    self.declare(id, Binding::Type(ty));
+                }
+            }
+        }
+    }
+
+    /// Declares formal parameters in the innermost scope and gets their signatures.
+    fn formal_params(&mut self, params: &mut [FormalParam]) -> Vec<ParamSig> {
+        params
+            .iter_mut()
+            .map(|param| {
+                let (sig, binding) = match param {
+                    FormalParam::Value(_, ty) => {
+                        let ty = self.type_denoter(ty);
+                        (ParamSig::Value(ty.clone()), Binding::Var(ty))
+                    }
+                    FormalParam::Var(_, ty) => {
+                        let ty = self.type_denoter(ty);
+                        (ParamSig::Var(ty.clone()), Binding::VarParam(ty))
+                    }
+                    FormalParam::Proc(_, params) => {
+                        self.scopes.push(HashMap::new());
+                        let params = self.formal_params(params);
+                        self.scopes.pop();
+                        let binding = Binding::Routine {
+                            params: params.clone(),
+                            result: None,
+                        };
+                        (ParamSig::Proc(params), binding)
+                    }
+                    FormalParam::Func(_, params, result) => {
+                        let result = self.type_denoter(result);
+                        self.scopes.push(HashMap::new());
+                        let params = self.formal_params(params);
+                        self.scopes.pop();
+                        let binding = Binding::Routine {
+                            params: params.clone(),
+                            result: Some(result.clone()),
+                        };
+                        (ParamSig::Func(params, result), binding)
+                    }
+                };
+
+                let (FormalParam::Value(id, _)
+                | FormalParam::Var(id, _)
+                | FormalParam::Proc(id, _)
+                | FormalParam::Func(id, ..)) = param;
+                self.declare(id, binding);
+                sig
+            })
+            .collect()
+    }
+
+    /// Checks the arguments of a call against the routine's parameters.
+    fn actual_params(&mut self, position: Position, params: &[ParamSig], args: &mut [ActualParam]) {
+        if params.len() != args.len() {
+            self.error(
+                position,
+                format!("expected {} arguments, found {}", params.len(), args.len()),
+            );
+            self.unchecked_params(args);
+            return;
+        }
+
+        for (param, arg) in params.iter().zip(args) {
+            match (param, arg) {
+                (ParamSig::Value(expected), ActualParam::Value(expr)) => {
+                    let ty = self.expression(expr);
+                    self.expect_type(expr.position, expected, &ty);
+                }
+                (ParamSig::Var(expected), ActualParam::Var(vname)) => {
+                    let ty = self.vname(vname);
+                    if !vname.variable {
+                        self.error(vname.position, "cannot pass a constant as a var argument");
+                    }
+                    self.expect_type(vname.position, expected, &ty);
+                }
+                (ParamSig::Proc(expected), ActualParam::Proc(id)) => match self.lookup(id) {
+                    Some(Binding::Routine {
+                        params,
+                        result: None,
+                    }) if sigs_equal(expected, &params) => {}
+                    Some(_) => self.error(id.position, "procedure argument has wrong parameters"),
+                    None => {}
+                },
+                (ParamSig::Func(expected, expected_result), ActualParam::Func(id)) => {
+                    match self.lookup(id) {
+                        Some(Binding::Routine {
+                            params,
+                            result: Some(result),
+                        }) if sigs_equal(expected, &params) && expected_result.equals(&result) => {}
+                        Some(_) => self.error(id.position, "function argument has wrong type"),
+                        None => {}
+                    }
+                }
+                (_, arg) => {
+                    self.error(position, "argument is the wrong kind of parameter");
+                    self.unchecked_params(std::slice::from_mut(arg));
+                }
+            }
+        }
+    }
+
+    /// Decorates arguments that cannot be matched against a routine's parameters.
+    fn unchecked_params(&mut self, args: &mut [ActualParam]) {
+        for arg in args {
+            match arg {
+                ActualParam::Value(expr) => {
+                    self.expression(expr);
+                }
+                ActualParam::Var(vname) => {
+                    self.vname(vname);
+                }
+                ActualParam::Proc(id) | ActualParam::Func(id) => {
+                    self.lookup(id);
+                }
+            }
+        }
+    }
+
+    fn type_denoter(&mut self, ty: &mut TypeDenoter) -> Type {
+        match ty {
+            TypeDenoter::Named(id) => match self.lookup(id) {
+                Some(Binding::Type(ty)) => ty,
+                Some(_) => {
+                    self.error(id.position, format!("`{}` is not a type", id.name));
+                    Type::Error
+                }
+                None => Type::Error,
+            },
+            TypeDenoter::Array(position, count, elem) => {
+                if *count <= 0 {
+                    self.error(*position, "array must have at least one element");
+                }
+                let elem = self.type_denoter(elem);
+                Type::Array((*count).max(0) as u16, Box::new(elem))
+            }
+            TypeDenoter::Record(fields) => {
+                let mut types: Vec<(String, Type)> = Vec::new();
+                for (id, ty) in fields {
+                    if types.iter().any(|(name, _)| *name == id.name) {
+                        self.error(id.position, format!("duplicate field `{}`", id.name));
+                    }
+                    let ty = self.type_denoter(ty);
+                    types.push((id.name.clone(), ty));
+                }
+                Type::Record(types)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::triangle::{lexer::tokenize, parser::parse};
+    use rstest::*;
+
+    fn check_source(source: &str) -> Result<Vec<Binding>, Vec<CompileError>> {
+        let mut program = parse(tokenize(source).unwrap()).unwrap();
+        check(&mut program)
+    }
+
+    #[rstest]
+    #[case("let var x: Integer in x := x + 1")]
+    #[case("let type P ~ record a: Integer, b: Char end; var p: P in p := {a ~ 1, b ~ 'b'}")]
+    #[case("let var a: array 3 of Boolean in a[1] := a[0] \\/ (1 = 2)")]
+    #[case("let func f(n: Integer): Integer ~ if n <= 1 then 1 else n * f(n - 1) in putint(f(5))")]
+    #[case("let proc p(var x: Integer) ~ x := 1; var y: Integer in p(var y)")]
+    #[case("let proc ap(proc q(c: Char)) ~ q('x') in ap(proc put)")]
+    fn test_check_valid_program_ok(#[case] source: &str) {
+        if let Err(errors) = check_source(source) {
+            panic!("unexpected errors: {errors:?}");
+        }
+    }
+
+    #[rstest]
+    #[case("x := 1", 1)]
+    #[case("let const c ~ 1 in c := 2", 1)]
+    #[case("let var x: Integer in x := 'a'", 1)]
+    #[case("let var x: Integer in if x then x := 1 else x := true", 2)]
+    #[case("let var x: Integer; var x: Char in putint(0)", 1)]
+    #[case("putint(1, 2)", 1)]
+    #[case("let var b: Boolean in getint(var b)", 1)]
+    #[case("let proc ap(proc q(c: Char)) ~ q('x') in ap(proc putint)", 1)]
+    fn test_check_invalid_program_err(#[case] source: &str, #[case] count: usize) {
+        let errors = check_source(source).unwrap_err();
+        assert_eq!(count, errors.len(), "wrong errors: {errors:?}");
+    }
+
+    #[rstest]
+    fn test_check_decorates_identifiers() {
+        let mut program = parse(tokenize("let var x: Integer in x := maxint").unwrap()).unwrap();
+        let bindings = check(&mut program).unwrap();
+
+        let CommandKind::Let(_, body) = program.kind else {
+            panic!("expected let command");
+        };
+        let CommandKind::Assign(vname, value) = body.kind else {
+            panic!("expected assignment");
+        };
+        let VnameKind::Simple(id) = vname.kind else {
+            panic!("expected simple vname");
+        };
+        assert_eq!(Binding::Var(Type::Integer), bindings[id.decl.unwrap()]);
+        assert_eq!(Type::Integer, value.ty);
+    }
+}
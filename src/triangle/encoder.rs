@@ -0,0 +1,738 @@
+use super::{
+    CompileError, Compiled, Position,
+    ast::*,
+    checker::Binding,
+    stdenv::{self, StdDecl},
+};
+use crate::{
+    CALL, CALLI, CB, HALT, JUMP, JUMPIF, LB, LOAD, LOADA, LOADI, LOADL, MEMORY_SIZE, PB, POP,
+    PRIMITIVE_NAMES, PUSH, RETURN, SB, STORE, STOREI, TamInstruction,
+    debug_info::{DebugInfo, Procedure, SourceLocation, Variable},
+};
+
+type EncodeResult<T> = Result<T, CompileError>;
+
+/// Size of the static link, dynamic link and return address at the base of a frame.
+const LINK_DATA_SIZE: i16 = 3;
+
+/// Deepest static nesting reachable through the display registers `LB` and `L1`-`L6`.
+const MAX_NESTING: u8 = 6;
+
+#[derive(Copy, Clone, Debug)]
+struct Address {
+    level: u8,
+    offset: i16,
+}
+
+/// Where the value of a declared identifier lives at run-time.
+#[derive(Copy, Clone, Debug)]
+enum Entity {
+    KnownValue(i16),
+    /// A constant, variable or value parameter stored in a frame
+    Stored(Address),
+    /// A var parameter, whose frame slot holds the variable's address
+    VarParam(Address),
+    KnownRoutine {
+        level: u8,
+        address: u16,
+    },
+    Primitive(i16),
+    /// A procedure or function parameter, whose frame slots hold a static link and
+    /// code address
+    RoutineParam(Address),
+}
+
+/// The activation record that code is generated for.
+#[derive(Copy, Clone, Debug)]
+struct Frame {
+    level: u8,
+    size: i16,
+}
+
+impl Frame {
+    fn extend(self, size: u16) -> Frame {
+        Frame {
+            size: self.size + size as i16,
+            ..self
+        }
+    }
+}
+
+/// How to reach the value named by a vname.
+enum Location {
+    Value(i16),
+    Direct {
+        register: u8,
+        offset: i16,
+    },
+    /// The address has been pushed onto the stack
+    Indirect,
+}
+
+/// Generates TAM code for a checked program.
+pub(super) fn encode(
+    program: &Command,
+    bindings: &[Binding],
+    filename: &str,
+) -> EncodeResult<Compiled> {
+    let mut encoder = Encoder {
+        code: Vec::new(),
+        bindings,
+        entities: vec![None; bindings.len()],
+        filename,
+        debug_info: DebugInfo::default(),
+        procedures: Vec::new(),
+        last_position: None,
+    };
+
+    for (i, (_, decl)) in stdenv::declarations().into_iter().enumerate() {
+        encoder.entities[i] = match decl {
+            StdDecl::Type(_) => None,
+            StdDecl::Const(_, value) => Some(Entity::KnownValue(value)),
+            StdDecl::Routine(_, _, name) => Some(Entity::Primitive(primitive(name))),
+        };
+    }
+
+    encoder.command(program, Frame { level: 0, size: 0 })?;
+    encoder.emit(HALT, 0, 0, 0);
+    if encoder.code.len() > MEMORY_SIZE {
+        return Err(CompileError::new(
+            program.position,
+            "program is too large for the code store",
+        ));
+    }
+
+    Ok(Compiled {
+        code: encoder.code,
+        debug_info: encoder.debug_info,
+    })
+}
+
+fn primitive(name: &str) -> i16 {
+    PRIMITIVE_NAMES
+        .iter()
+        .position(|p| *p == name)
+        .unwrap_or_else(|| panic!("no primitive named {name}")) as i16
+}
+
+struct Encoder<'a> {
+    code: Vec<u32>,
+    bindings: &'a [Binding],
+    entities: Vec<Option<Entity>>,
+    filename: &'a str,
+    debug_info: DebugInfo,
+    /// Names of the routines enclosing the code being generated
+    procedures: Vec<String>,
+    last_position: Option<Position>,
+}
+
+impl Encoder<'_> {
+    fn next_address(&self) -> u16 {
+        self.code.len() as u16
+    }
+
+    fn emit(&mut self, op: u8, n: u8, r: usize, d: i16) -> u16 {
+        let addr = self.next_address();
+        let instr = TamInstruction {
+            op,
+            r: r as u8,
+            n,
+            d,
+        };
+        self.code.push(instr.into());
+        addr
+    }
+
+    /// Points the jump at `addr` to the next instruction to be emitted.
+    fn patch(&mut self, addr: u16) {
+        let target = self.next_address();
+        let code = &mut self.code[addr as usize];
+        *code = (*code & 0xffff0000) | target as u32;
+    }
+
+    fn call_primitive(&mut self, name: &str) {
+        self.emit(CALL, SB as u8, PB, primitive(name));
+    }
+
+    /// Records that the next instruction begins the code for `position`.
+    fn mark_line(&mut self, position: Position) {
+        if self.last_position == Some(position) {
+            return;
+        }
+
+        let location = SourceLocation {
+            file: self.filename.to_string(),
+            line: position.line,
+            column: position.column,
+        };
+        self.debug_info.add_line(self.next_address(), location);
+        self.last_position = Some(position);
+    }
+
+    fn add_variable(&mut self, name: &str, frame: Frame, size: u16, ty: String) {
+        let variable = Variable {
+            scope: self.procedures.last().cloned(),
+            name: name.to_string(),
+            register: if frame.level == 0 { SB } else { LB } as u8,
+            offset: frame.size,
+            size: size.min(u8::MAX as u16) as u8,
+            ty,
+        };
+        self.debug_info.add_variable(variable);
+    }
+
+    fn entity(&self, id: &Identifier) -> Entity {
+        let decl = id.decl.expect("identifier was not checked");
+        self.entities[decl].expect("identifier used before its declaration was encoded")
+    }
+
+    fn set_entity(&mut self, id: &Identifier, entity: Entity) {
+        let decl = id.decl.expect("identifier was not checked");
+        self.entities[decl] = Some(entity);
+    }
+
+    /// Gets the register through which a frame at `level` is reached from `frame`.
+    fn display_register(&self, frame: Frame, level: u8, position: Position) -> EncodeResult<usize> {
+        if level == 0 {
+            return Ok(SB);
+        }
+
+        let depth = frame.level - level;
+        if depth > MAX_NESTING {
+            return Err(CompileError::new(
+                position,
+                "routines are nested too deeply",
+            ));
+        }
+        Ok(LB + depth as usize)
+    }
+
+    fn command(&mut self, command: &Command, frame: Frame) -> EncodeResult<()> {
+        self.mark_line(command.position);
+        match &command.kind {
+            CommandKind::Empty => {}
+            CommandKind::Assign(vname, expr) => {
+                let size = self.expression(expr, frame)?;
+                let location = self.location(vname, frame.extend(size))?;
+                let size = word_count(size, vname.position)?;
+                match location {
+                    Location::Direct { register, offset } => {
+                        self.emit(STORE, size, register as usize, offset);
+                    }
+                    Location::Indirect => {
+                        self.emit(STOREI, size, 0, 0);
+                    }
+                    Location::Value(_) => unreachable!("assignment to a constant"),
+                }
+            }
+            CommandKind::Call(id, args) => self.call(id, args, frame)?,
+            CommandKind::Sequence(commands) => {
+                for command in commands {
+                    self.command(command, frame)?;
+                }
+            }
+            CommandKind::Let(decls, body) => {
+                let size = self.declarations(decls, frame)?;
+                self.command(body, frame.extend(size))?;
+                if size > 0 {
+                    self.emit(POP, 0, 0, size as i16);
+                }
+            }
+            CommandKind::If(cond, then_branch, else_branch) => {
+                self.expression(cond, frame)?;
+                let jump_to_else = self.emit(JUMPIF, 0, CB, 0);
+                self.command(then_branch, frame)?;
+                let jump_to_end = self.emit(JUMP, 0, CB, 0);
+                self.patch(jump_to_else);
+                self.command(else_branch, frame)?;
+                self.patch(jump_to_end);
+            }
+            CommandKind::While(cond, body) => {
+                let jump_to_test = self.emit(JUMP, 0, CB, 0);
+                let loop_start = self.next_address();
+                self.command(body, frame)?;
+                self.patch(jump_to_test);
+                self.mark_line(cond.position);
+                self.expression(cond, frame)?;
+                self.emit(JUMPIF, 1, CB, loop_start as i16);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates code to push the value of an expression, and gets its size.
+    fn expression(&mut self, expr: &Expression, frame: Frame) -> EncodeResult<u16> {
+        match &expr.kind {
+            ExpressionKind::IntLiteral(value) => {
+                self.emit(LOADL, 0, 0, *value);
+            }
+            ExpressionKind::CharLiteral(value) => {
+                self.emit(LOADL, 0, 0, *value as i16);
+            }
+            ExpressionKind::Vname(vname) => {
+                let size = word_count(vname.ty.size(), vname.position)?;
+                match self.location(vname, frame)? {
+                    Location::Value(value) => self.emit(LOADL, 0, 0, value),
+                    Location::Direct { register, offset } => {
+                        self.emit(LOAD, size, register as usize, offset)
+                    }
+                    Location::Indirect => self.emit(LOADI, size, 0, 0),
+                };
+            }
+            ExpressionKind::Call(id, args) => self.call(id, args, frame)?,
+            ExpressionKind::Unary(op, operand) => {
+                self.expression(operand, frame)?;
+                let (_, _, name) = stdenv::unary_operator(&op.name).expect("operator was checked");
+                self.call_primitive(name);
+            }
+            ExpressionKind::Binary(lhs, op, rhs) => {
+                let size = self.expression(lhs, frame)?;
+                self.expression(rhs, frame.extend(size))?;
+                let (operand, _, name) =
+                    stdenv::binary_operator(&op.name).expect("operator was checked");
+                if operand.is_none() {
+                    self.emit(LOADL, 0, 0, size as i16);
+                }
+                self.call_primitive(name);
+            }
+            ExpressionKind::Let(decls, body) => {
+                let decl_size = self.declarations(decls, frame)?;
+                let size = self.expression(body, frame.extend(decl_size))?;
+                if decl_size > 0 {
+                    let size = word_count(size, body.position)?;
+                    self.emit(POP, size, 0, decl_size as i16);
+                }
+            }
+            ExpressionKind::If(cond, then_branch, else_branch) => {
+                self.expression(cond, frame)?;
+                let jump_to_else = self.emit(JUMPIF, 0, CB, 0);
+                self.expression(then_branch, frame)?;
+                let jump_to_end = self.emit(JUMP, 0, CB, 0);
+                self.patch(jump_to_else);
+                self.expression(else_branch, frame)?;
+                self.patch(jump_to_end);
+            }
+            ExpressionKind::Record(fields) => {
+                let mut size = 0;
+                for (_, value) in fields {
+                    size += self.expression(value, frame.extend(size))?;
+                }
+            }
+            ExpressionKind::Array(elems) => {
+                let mut size = 0;
+                for elem in elems {
+                    size += self.expression(elem, frame.extend(size))?;
+                }
+            }
+        }
+        Ok(expr.ty.size())
+    }
+
+    /// Generates any code needed to locate the value named by a vname.
+    fn location(&mut self, vname: &Vname, frame: Frame) -> EncodeResult<Location> {
+        Ok(match &vname.kind {
+            VnameKind::Simple(id) => match self.entity(id) {
+                Entity::KnownValue(value) => Location::Value(value),
+                Entity::Stored(addr) => Location::Direct {
+                    register: self.display_register(frame, addr.level, id.position)? as u8,
+                    offset: addr.offset,
+                },
+                Entity::VarParam(addr) => {
+                    let register = self.display_register(frame, addr.level, id.position)?;
+                    self.emit(LOAD, 1, register, addr.offset);
+                    Location::Indirect
+                }
+                entity => unreachable!("{} names a routine: {entity:?}", id.name),
+            },
+            VnameKind::Field(record, id) => {
+                let location = self.location(record, frame)?;
+                let Type::Record(fields) = &record.ty else {
+                    unreachable!("field of non-record was checked");
+                };
+                let field_offset: u16 = fields
+                    .iter()
+                    .take_while(|(name, _)| *name != id.name)
+                    .map(|(_, ty)| ty.size())
+                    .sum();
+
+                match location {
+                    Location::Direct { register, offset } => Location::Direct {
+                        register,
+                        offset: offset + field_offset as i16,
+                    },
+                    Location::Indirect => {
+                        if field_offset != 0 {
+                            self.emit(LOADL, 0, 0, field_offset as i16);
+                            self.call_primitive("add");
+                        }
+                        Location::Indirect
+                    }
+                    Location::Value(_) => unreachable!("record constants are stored"),
+                }
+            }
+            VnameKind::Index(array, index) => {
+                let location = self.location(array, frame)?;
+                let elem_size = vname.ty.size();
+
+                match (&index.kind, location) {
+                    (ExpressionKind::IntLiteral(i), Location::Direct { register, offset }) => {
+                        Location::Direct {
+                            register,
+                            offset: offset + i * elem_size as i16,
+                        }
+                    }
+                    (_, location) => {
+                        if let Location::Direct { register, offset } = location {
+                            self.emit(LOADA, 0, register as usize, offset);
+                        }
+                        self.expression(index, frame.extend(1))?;
+                        if elem_size != 1 {
+                            self.emit(LOADL, 0, 0, elem_size as i16);
+                            self.call_primitive("mult");
+                        }
+                        self.call_primitive("add");
+                        Location::Indirect
+                    }
+                }
+            }
+        })
+    }
+
+    fn call(&mut self, id: &Identifier, args: &[ActualParam], frame: Frame) -> EncodeResult<()> {
+        let mut size = 0;
+        for arg in args {
+            size += self.actual_param(arg, frame.extend(size))?;
+        }
+
+        match self.entity(id) {
+            Entity::KnownRoutine { level, address } => {
+                let register = self.display_register(frame, level, id.position)?;
+                self.emit(CALL, register as u8, CB, address as i16);
+            }
+            Entity::Primitive(displacement) => {
+                self.emit(CALL, SB as u8, PB, displacement);
+            }
+            Entity::RoutineParam(addr) => {
+                let register = self.display_register(frame, addr.level, id.position)?;
+                self.emit(LOAD, 2, register, addr.offset);
+                self.emit(CALLI, 0, 0, 0);
+            }
+            entity => unreachable!("{} does not name a routine: {entity:?}", id.name),
+        }
+        Ok(())
+    }
+
+    /// Generates code to push an argument, and gets its size.
+    fn actual_param(&mut self, arg: &ActualParam, frame: Frame) -> EncodeResult<u16> {
+        match arg {
+            ActualParam::Value(expr) => self.expression(expr, frame),
+            ActualParam::Var(vname) => {
+                match self.location(vname, frame)? {
+                    Location::Direct { register, offset } => {
+                        self.emit(LOADA, 0, register as usize, offset);
+                    }
+                    Location::Indirect => {}
+                    Location::Value(_) => unreachable!("var argument was checked"),
+                }
+                Ok(1)
+            }
+            ActualParam::Proc(id) | ActualParam::Func(id) => {
+                match self.entity(id) {
+                    Entity::KnownRoutine { level, address } => {
+                        let register = self.display_register(frame, level, id.position)?;
+                        self.emit(LOADA, 0, register, 0);
+                        self.emit(LOADA, 0, CB, address as i16);
+                    }
+                    Entity::Primitive(displacement) => {
+                        self.emit(LOADA, 0, SB, 0);
+                        self.emit(LOADA, 0, PB, displacement);
+                    }
+                    Entity::RoutineParam(addr) => {
+                        let register = self.display_register(frame, addr.level, id.position)?;
+                        self.emit(LOAD, 2, register, addr.offset);
+                    }
+                    entity => unreachable!("{} does not name a routine: {entity:?}", id.name),
+                }
+                Ok(2)
+            }
+        }
+    }
+
+    /// Generates code for a sequence of declarations, and gets the size of the
+    /// storage they allocate.
+    fn declarations(&mut self, decls: &[Declaration], frame: Frame) -> EncodeResult<u16> {
+        let mut size = 0;
+        for decl in decls {
+            size += self.declaration(decl, frame.extend(size))?;
+        }
+        Ok(size)
+    }
+
+    fn declaration(&mut self, decl: &Declaration, frame: Frame) -> EncodeResult<u16> {
+        let address = Address {
+            level: frame.level,
+            offset: frame.size,
+        };
+
+        match decl {
+            Declaration::Const(id, value) => match value.kind {
+                ExpressionKind::IntLiteral(v) => {
+                    self.set_entity(id, Entity::KnownValue(v));
+                    Ok(0)
+                }
+                ExpressionKind::CharLiteral(c) => {
+                    self.set_entity(id, Entity::KnownValue(c as i16));
+                    Ok(0)
+                }
+                _ => {
+                    let size = self.expression(value, frame)?;
+                    self.set_entity(id, Entity::Stored(address));
+                    Ok(size)
+                }
+            },
+            Declaration::Var(id, _) => {
+                let Binding::Var(ty) = &self.bindings[id.decl.expect("identifier was not checked")]
+                else {
+                    unreachable!("variable has a non-variable binding");
+                };
+                let size = ty.size();
+                let ty = ty.to_string();
+                self.emit(PUSH, 0, 0, size as i16);
+                self.set_entity(id, Entity::Stored(address));
+                self.add_variable(&id.name, frame, size, ty);
+                Ok(size)
+            }
+            Declaration::Proc(id, params, body) => {
+                self.routine(id, params, frame, |encoder, frame| {
+                    encoder.command(body, frame)?;
+                    Ok(0)
+                })?;
+                Ok(0)
+            }
+            Declaration::Func(id, params, _, body) => {
+                self.routine(id, params, frame, |encoder, frame| {
+                    encoder.mark_line(body.position);
+                    let size = encoder.expression(body, frame)?;
+                    word_count(size, body.position)
+                })?;
+                Ok(0)
+            }
+            Declaration::Type(..) => Ok(0),
+        }
+    }
+
+    /// Generates a routine, jumping around its body, where `body` generates the
+    /// code that leaves the routine's result on the stack and returns its size.
+    fn routine(
+        &mut self,
+        id: &Identifier,
+        params: &[FormalParam],
+        frame: Frame,
+        body: impl FnOnce(&mut Self, Frame) -> EncodeResult<u8>,
+    ) -> EncodeResult<()> {
+        let jump_over_body = self.emit(JUMP, 0, CB, 0);
+        let start = self.next_address();
+        self.set_entity(
+            id,
+            Entity::KnownRoutine {
+                level: frame.level,
+                address: start,
+            },
+        );
+
+        self.procedures.push(id.name.clone());
+        let level = frame.level + 1;
+        let params_size = self.formal_params(params, level)?;
+        let result_size = body(
+            self,
+            Frame {
+                level,
+                size: LINK_DATA_SIZE,
+            },
+        )?;
+        self.emit(RETURN, result_size, 0, params_size as i16);
+        self.procedures.pop();
+
+        self.debug_info.add_procedure(Procedure {
+            name: id.name.clone(),
+            start,
+            end: self.next_address(),
+        });
+        self.patch(jump_over_body);
+        Ok(())
+    }
+
+    /// Assigns addresses below the frame base to the parameters of a routine whose
+    /// body is at `level`, and gets their total size.
+    fn formal_params(&mut self, params: &[FormalParam], level: u8) -> EncodeResult<u16> {
+        let sizes: Vec<u16> = params
+            .iter()
+            .map(|param| match param {
+                FormalParam::Value(id, _) => match &self.bindings[id.decl.expect("unchecked")] {
+                    Binding::Var(ty) => ty.size(),
+                    binding => unreachable!("value parameter bound to {binding:?}"),
+                },
+                FormalParam::Var(..) => 1,
+                FormalParam::Proc(..) | FormalParam::Func(..) => 2,
+            })
+            .collect();
+        let total: u16 = sizes.iter().sum();
+
+        let mut offset = -(total as i16);
+        for (param, size) in params.iter().zip(sizes) {
+            let id = param.identifier();
+            let address = Address { level, offset };
+            let frame = Frame {
+                level,
+                size: offset,
+            };
+            match param {
+                FormalParam::Value(_, _) => {
+                    self.set_entity(id, Entity::Stored(address));
+                    let ty = self.binding_type(id);
+                    self.add_variable(&id.name, frame, size, ty);
+                }
+                FormalParam::Var(_, _) => {
+                    self.set_entity(id, Entity::VarParam(address));
+                    let ty = format!("var:{}", self.binding_type(id));
+                    self.add_variable(&id.name, frame, size, ty);
+                }
+                FormalParam::Proc(..) | FormalParam::Func(..) => {
+                    self.set_entity(id, Entity::RoutineParam(address))
+                }
+            }
+            offset += size as i16;
+        }
+        Ok(total)
+    }
+
+    fn binding_type(&self, id: &Identifier) -> String {
+        match &self.bindings[id.decl.expect("identifier was not checked")] {
+            Binding::Var(ty) | Binding::VarParam(ty) | Binding::Const(ty) => ty.to_string(),
+            binding => unreachable!("{} has no type: {binding:?}", id.name),
+        }
+    }
+}
+
+/// Checks that a value is small enough to be moved by a single instruction.
+fn word_count(size: u16, position: Position) -> EncodeResult<u8> {
+    u8::try_from(size).map_err(|_| {
+        CompileError::new(
+            position,
+            format!("value of {size} words is too large to load or store"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{TamInstruction, triangle::compile};
+    use rstest::*;
+
+    fn disassemble(source: &str) -> Vec<String> {
+        compile(source, "test.tri")
+            .unwrap()
+            .code
+            .into_iter()
+            .map(|i| TamInstruction::from(i).to_string())
+            .collect()
+    }
+
+    #[rstest]
+    fn test_encode_global_variable() {
+        assert_eq!(
+            vec![
+                "PUSH 1",
+                "LOAD(1) 0[4]",
+                "LOADL 1",
+                "CALL(4) 8[2]",
+                "STORE(1) 0[4]",
+                "POP(0), 1",
+                "HALT"
+            ],
+            disassemble("let var x: Integer in x := x + 1")
+        );
+    }
+
+    #[rstest]
+    fn test_encode_while_loop() {
+        assert_eq!(
+            vec![
+                "PUSH 1",
+                "JUMP 4[0]",
+                "LOAD(1) 0[4]",
+                "CALL(4) 26[2]",
+                "LOAD(1) 0[4]",
+                "LOADL 10",
+                "CALL(4) 13[2]",
+                "JUMPIF(1) 2[0]",
+                "POP(0), 1",
+                "HALT"
+            ],
+            disassemble("let var i: Integer in while i < 10 do putint(i)")
+        );
+    }
+
+    #[rstest]
+    fn test_encode_function_with_params() {
+        assert_eq!(
+            vec![
+                "JUMP 5[0]",
+                "LOAD(1) -2[8]",
+                "LOAD(1) -1[8]",
+                "CALL(4) 8[2]",
+                "RETURN(1) 2",
+                "LOADL 1",
+                "LOADL 2",
+                "CALL(4) 1[0]",
+                "CALL(4) 26[2]",
+                "HALT"
+            ],
+            disassemble(
+                "let func add(a: Integer, b: Integer): Integer ~ a + b in putint(add(1, 2))"
+            )
+        );
+    }
+
+    #[rstest]
+    fn test_encode_var_param_and_array_index() {
+        assert_eq!(
+            vec![
+                "PUSH 3",
+                "JUMP 6[0]",
+                "LOADL 9",
+                "LOAD(1) -1[8]",
+                "STOREI(1)",
+                "RETURN(0) 1",
+                "LOADA 0[4]",
+                "LOAD(1) 1[4]",
+                "CALL(4) 8[2]",
+                "CALL(4) 2[0]",
+                "POP(0), 3",
+                "HALT"
+            ],
+            disassemble(
+                "let
+                    var a: array 3 of Integer;
+                    proc set(var x: Integer) ~ x := 9
+                in set(var a[a[1]])"
+            )
+        );
+    }
+
+    #[rstest]
+    fn test_encode_records_debug_info() {
+        let compiled = compile(
+            "let\n  proc p(n: Integer) ~\n    putint(n)\nin\n  p(4)",
+            "p.tri",
+        )
+        .unwrap();
+        let info = compiled.debug_info;
+
+        assert_eq!(Some("in procedure `p`, line 3".into()), info.describe(1));
+        assert_eq!(Some("line 5".into()), info.describe(5));
+        let params: Vec<_> = info.variables(Some("p")).collect();
+        assert_eq!(1, params.len());
+        assert_eq!(-1, params[0].offset);
+    }
+}
@@ -0,0 +1,242 @@
+use super::{CompileError, Position};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum TokenKind {
+    IntLiteral(i16),
+    CharLiteral(u8),
+    Identifier(String),
+    Operator(String),
+    // keywords
+    Array,
+    Begin,
+    Const,
+    Do,
+    Else,
+    End,
+    Func,
+    If,
+    In,
+    Let,
+    Of,
+    Proc,
+    Record,
+    Then,
+    Type,
+    Var,
+    While,
+    // punctuation
+    Dot,
+    Colon,
+    Semicolon,
+    Comma,
+    Becomes,
+    Is,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    LCurly,
+    RCurly,
+    Eof,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct Token {
+    pub kind: TokenKind,
+    pub position: Position,
+}
+
+const OPERATOR_CHARS: &str = "+-*/=<>\\&@%^?";
+
+/// Splits Triangle source code into tokens, ending with a single `Eof` token.
+pub(super) fn tokenize(source: &str) -> Result<Vec<Token>, CompileError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut position = Position { line: 1, column: 1 };
+
+    macro_rules! advance {
+        () => {{
+            let c = chars.next();
+            if c == Some('\n') {
+                position.line += 1;
+                position.column = 1;
+            } else if c.is_some() {
+                position.column += 1;
+            }
+            c
+        }};
+    }
+
+    while let Some(&c) = chars.peek() {
+        let start = position;
+        let kind = match c {
+            c if c.is_whitespace() => {
+                advance!();
+                continue;
+            }
+            '!' => {
+                while !matches!(advance!(), Some('\n') | None) {}
+                continue;
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()) {
+                    word.push(c);
+                    advance!();
+                }
+                keyword(&word).unwrap_or(TokenKind::Identifier(word))
+            }
+            c if c.is_ascii_digit() => {
+                let mut digits = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                    digits.push(c);
+                    advance!();
+                }
+                let value = digits.parse().map_err(|_| {
+                    CompileError::new(start, format!("integer literal {digits} is too large"))
+                })?;
+                TokenKind::IntLiteral(value)
+            }
+            '\'' => {
+                advance!();
+                let value = advance!().filter(|c| c.is_ascii());
+                match (value, advance!()) {
+                    (Some(c), Some('\'')) => TokenKind::CharLiteral(c as u8),
+                    _ => return Err(CompileError::new(start, "malformed character literal")),
+                }
+            }
+            c if OPERATOR_CHARS.contains(c) => {
+                let mut op = String::new();
+                while let Some(&c) = chars.peek().filter(|c| OPERATOR_CHARS.contains(**c)) {
+                    op.push(c);
+                    advance!();
+                }
+                TokenKind::Operator(op)
+            }
+            ':' => {
+                advance!();
+                if chars.peek() == Some(&'=') {
+                    advance!();
+                    TokenKind::Becomes
+                } else {
+                    TokenKind::Colon
+                }
+            }
+            c => {
+                advance!();
+                match c {
+                    '.' => TokenKind::Dot,
+                    ';' => TokenKind::Semicolon,
+                    ',' => TokenKind::Comma,
+                    '~' => TokenKind::Is,
+                    '(' => TokenKind::LParen,
+                    ')' => TokenKind::RParen,
+                    '[' => TokenKind::LBracket,
+                    ']' => TokenKind::RBracket,
+                    '{' => TokenKind::LCurly,
+                    '}' => TokenKind::RCurly,
+                    c => {
+                        return Err(CompileError::new(
+                            start,
+                            format!("unexpected character {c:?}"),
+                        ));
+                    }
+                }
+            }
+        };
+        tokens.push(Token {
+            kind,
+            position: start,
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        position,
+    });
+    Ok(tokens)
+}
+
+fn keyword(word: &str) -> Option<TokenKind> {
+    Some(match word {
+        "array" => TokenKind::Array,
+        "begin" => TokenKind::Begin,
+        "const" => TokenKind::Const,
+        "do" => TokenKind::Do,
+        "else" => TokenKind::Else,
+        "end" => TokenKind::End,
+        "func" => TokenKind::Func,
+        "if" => TokenKind::If,
+        "in" => TokenKind::In,
+        "let" => TokenKind::Let,
+        "of" => TokenKind::Of,
+        "proc" => TokenKind::Proc,
+        "record" => TokenKind::Record,
+        "then" => TokenKind::Then,
+        "type" => TokenKind::Type,
+        "var" => TokenKind::Var,
+        "while" => TokenKind::While,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.kind)
+            .collect()
+    }
+
+    #[rstest]
+    fn test_tokenize_assignment() {
+        assert_eq!(
+            vec![
+                TokenKind::Identifier("x".into()),
+                TokenKind::Becomes,
+                TokenKind::Identifier("x".into()),
+                TokenKind::Operator("+".into()),
+                TokenKind::IntLiteral(12),
+                TokenKind::Eof,
+            ],
+            kinds("x := x + 12 ! increment")
+        );
+    }
+
+    #[rstest]
+    fn test_tokenize_keywords_and_chars() {
+        assert_eq!(
+            vec![
+                TokenKind::Let,
+                TokenKind::Const,
+                TokenKind::Identifier("c".into()),
+                TokenKind::Is,
+                TokenKind::CharLiteral(b'a'),
+                TokenKind::In,
+                TokenKind::Operator("\\=".into()),
+                TokenKind::Eof,
+            ],
+            kinds("let const c ~ 'a' in \\=")
+        );
+    }
+
+    #[rstest]
+    fn test_tokenize_tracks_positions() {
+        let tokens = tokenize("a\n  b").unwrap();
+        assert_eq!(Position { line: 1, column: 1 }, tokens[0].position);
+        assert_eq!(Position { line: 2, column: 3 }, tokens[1].position);
+    }
+
+    #[rstest]
+    #[case("40000")]
+    #[case("'ab'")]
+    #[case("x # y")]
+    fn test_tokenize_invalid_err(#[case] source: &str) {
+        assert!(tokenize(source).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! A compiler from the Triangle language to TAM bytecode.
+//!
+//! Compilation runs in four passes: [`lexer`] splits the source into tokens,
+//! [`parser`] builds an abstract syntax tree, [`checker`] resolves identifiers and
+//! decorates the tree with types, and [`encoder`] generates TAM instructions together
+//! with the debug info that describes them.
+
+mod ast;
+mod checker;
+mod encoder;
+mod lexer;
+mod parser;
+mod stdenv;
+
+use crate::debug_info::DebugInfo;
+use std::fmt::{self, Display};
+
+/// A position in Triangle source code.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+
+/// A problem found while compiling a Triangle program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub position: Position,
+    pub message: String,
+}
+
+impl CompileError {
+    fn new(position: Position, message: impl Into<String>) -> CompileError {
+        CompileError {
+            position,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.position.line, self.position.column, self.message
+        )
+    }
+}
+
+/// The output of a successful compilation.
+#[derive(Clone, Debug)]
+pub struct Compiled {
+    /// Encoded TAM instructions
+    pub code: Vec<u32>,
+    /// Line, procedure and variable tables for the generated code
+    pub debug_info: DebugInfo,
+}
+
+impl Compiled {
+    /// Gets the code in the big-endian byte format read by `TamEmulator::set_program`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.code.iter().flat_map(|i| i.to_be_bytes()).collect()
+    }
+}
+
+/// Compiles a Triangle program.
+///
+/// The file name is only used to label locations in the generated debug info.
+/// Syntax errors stop compilation immediately, but all contextual errors in the
+/// program are reported together.
+///
+/// # Example
+///
+/// ```
+/// let compiled = tam_rs::triangle::compile("let var x: Integer in x := 2", "x.tri").unwrap();
+/// let mut emu = tam_rs::TamEmulator::new(false);
+/// emu.set_program(&compiled.to_bytes()).unwrap();
+/// ```
+pub fn compile(source: &str, filename: &str) -> Result<Compiled, Vec<CompileError>> {
+    let tokens = lexer::tokenize(source).map_err(|e| vec![e])?;
+    let mut program = parser::parse(tokens).map_err(|e| vec![e])?;
+    let bindings = checker::check(&mut program)?;
+    encoder::encode(&program, &bindings, filename).map_err(|e| vec![e])
+}
@@ -0,0 +1,499 @@
+use super::{
+    CompileError, Position,
+    ast::*,
+    lexer::{Token, TokenKind},
+};
+
+type ParseResult<T> = Result<T, CompileError>;
+
+/// Parses a complete Triangle program, which is a single command.
+pub(super) fn parse(tokens: Vec<Token>) -> ParseResult<Command> {
+    let mut parser = Parser { tokens, next: 0 };
+    let program = parser.command()?;
+    if parser.peek() != &TokenKind::Eof {
+        return Err(parser.unexpected("end of program"));
+    }
+    Ok(program)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &TokenKind {
+        &self.tokens[self.next].kind
+    }
+
+    fn position(&self) -> Position {
+        self.tokens[self.next].position
+    }
+
+    fn advance(&mut self) -> TokenKind {
+        let kind = self.tokens[self.next].kind.clone();
+        if kind != TokenKind::Eof {
+            self.next += 1;
+        }
+        kind
+    }
+
+    fn unexpected(&self, expected: &str) -> CompileError {
+        CompileError::new(
+            self.position(),
+            format!("expected {expected}, found {:?}", self.peek()),
+        )
+    }
+
+    fn accept(&mut self, kind: TokenKind) -> bool {
+        if *self.peek() == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> ParseResult<()> {
+        if self.accept(kind.clone()) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("{kind:?}")))
+        }
+    }
+
+    fn identifier(&mut self) -> ParseResult<Identifier> {
+        let position = self.position();
+        match self.peek().clone() {
+            TokenKind::Identifier(name) => {
+                self.advance();
+                Ok(Identifier {
+                    name,
+                    position,
+                    decl: None,
+                })
+            }
+            _ => Err(self.unexpected("identifier")),
+        }
+    }
+
+    fn operator(&mut self) -> ParseResult<Identifier> {
+        let position = self.position();
+        match self.peek().clone() {
+            TokenKind::Operator(name) => {
+                self.advance();
+                Ok(Identifier {
+                    name,
+                    position,
+                    decl: None,
+                })
+            }
+            _ => Err(self.unexpected("operator")),
+        }
+    }
+
+    fn int_literal(&mut self) -> ParseResult<i16> {
+        match *self.peek() {
+            TokenKind::IntLiteral(value) => {
+                self.advance();
+                Ok(value)
+            }
+            _ => Err(self.unexpected("integer literal")),
+        }
+    }
+
+    /// Parses items separated by `separator` until `parse_item` has run at least once.
+    fn list<T>(
+        &mut self,
+        separator: TokenKind,
+        mut parse_item: impl FnMut(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<Vec<T>> {
+        let mut items = vec![parse_item(self)?];
+        while self.accept(separator.clone()) {
+            items.push(parse_item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn command(&mut self) -> ParseResult<Command> {
+        let position = self.position();
+        let mut commands = self.list(TokenKind::Semicolon, Self::single_command)?;
+        Ok(if commands.len() == 1 {
+            commands.remove(0)
+        } else {
+            Command {
+                kind: CommandKind::Sequence(commands),
+                position,
+            }
+        })
+    }
+
+    fn single_command(&mut self) -> ParseResult<Command> {
+        let position = self.position();
+        let kind = match self.peek() {
+            TokenKind::Identifier(_) => {
+                let id = self.identifier()?;
+                if self.accept(TokenKind::LParen) {
+                    let args = self.actual_params()?;
+                    CommandKind::Call(id, args)
+                } else {
+                    let vname = self.rest_of_vname(id)?;
+                    self.expect(TokenKind::Becomes)?;
+                    CommandKind::Assign(vname, self.expression()?)
+                }
+            }
+            TokenKind::Begin => {
+                self.advance();
+                let command = self.command()?;
+                self.expect(TokenKind::End)?;
+                return Ok(command);
+            }
+            TokenKind::Let => {
+                self.advance();
+                let decls = self.declaration()?;
+                self.expect(TokenKind::In)?;
+                CommandKind::Let(decls, Box::new(self.single_command()?))
+            }
+            TokenKind::If => {
+                self.advance();
+                let cond = self.expression()?;
+                self.expect(TokenKind::Then)?;
+                let then_branch = self.single_command()?;
+                self.expect(TokenKind::Else)?;
+                let else_branch = self.single_command()?;
+                CommandKind::If(cond, Box::new(then_branch), Box::new(else_branch))
+            }
+            TokenKind::While => {
+                self.advance();
+                let cond = self.expression()?;
+                self.expect(TokenKind::Do)?;
+                CommandKind::While(cond, Box::new(self.single_command()?))
+            }
+            TokenKind::Semicolon
+            | TokenKind::End
+            | TokenKind::Else
+            | TokenKind::In
+            | TokenKind::Eof => CommandKind::Empty,
+            _ => return Err(self.unexpected("command")),
+        };
+        Ok(Command { kind, position })
+    }
+
+    fn expression(&mut self) -> ParseResult<Expression> {
+        let position = self.position();
+        let kind = match self.peek() {
+            TokenKind::Let => {
+                self.advance();
+                let decls = self.declaration()?;
+                self.expect(TokenKind::In)?;
+                ExpressionKind::Let(decls, Box::new(self.expression()?))
+            }
+            TokenKind::If => {
+                self.advance();
+                let cond = self.expression()?;
+                self.expect(TokenKind::Then)?;
+                let then_branch = self.expression()?;
+                self.expect(TokenKind::Else)?;
+                let else_branch = self.expression()?;
+                ExpressionKind::If(Box::new(cond), Box::new(then_branch), Box::new(else_branch))
+            }
+            _ => return self.secondary_expression(),
+        };
+        Ok(expression(kind, position))
+    }
+
+    /// Parses a chain of binary operators, which all share one precedence and
+    /// associate to the left.
+    fn secondary_expression(&mut self) -> ParseResult<Expression> {
+        let position = self.position();
+        let mut expr = self.primary_expression()?;
+        while let TokenKind::Operator(_) = self.peek() {
+            let op = self.operator()?;
+            let rhs = self.primary_expression()?;
+            expr = expression(
+                ExpressionKind::Binary(Box::new(expr), op, Box::new(rhs)),
+                position,
+            );
+        }
+        Ok(expr)
+    }
+
+    fn primary_expression(&mut self) -> ParseResult<Expression> {
+        let position = self.position();
+        let kind = match self.peek() {
+            TokenKind::IntLiteral(value) => {
+                let value = *value;
+                self.advance();
+                ExpressionKind::IntLiteral(value)
+            }
+            TokenKind::CharLiteral(value) => {
+                let value = *value;
+                self.advance();
+                ExpressionKind::CharLiteral(value)
+            }
+            TokenKind::Identifier(_) => {
+                let id = self.identifier()?;
+                if self.accept(TokenKind::LParen) {
+                    ExpressionKind::Call(id, self.actual_params()?)
+                } else {
+                    ExpressionKind::Vname(self.rest_of_vname(id)?)
+                }
+            }
+            TokenKind::Operator(_) => {
+                let op = self.operator()?;
+                ExpressionKind::Unary(op, Box::new(self.primary_expression()?))
+            }
+            TokenKind::LParen => {
+                self.advance();
+                let expr = self.expression()?;
+                self.expect(TokenKind::RParen)?;
+                return Ok(expr);
+            }
+            TokenKind::LCurly => {
+                self.advance();
+                let fields = self.list(TokenKind::Comma, |p| {
+                    let id = p.identifier()?;
+                    p.expect(TokenKind::Is)?;
+                    Ok((id, p.expression()?))
+                })?;
+                self.expect(TokenKind::RCurly)?;
+                ExpressionKind::Record(fields)
+            }
+            TokenKind::LBracket => {
+                self.advance();
+                let elems = self.list(TokenKind::Comma, Self::expression)?;
+                self.expect(TokenKind::RBracket)?;
+                ExpressionKind::Array(elems)
+            }
+            _ => return Err(self.unexpected("expression")),
+        };
+        Ok(expression(kind, position))
+    }
+
+    fn rest_of_vname(&mut self, id: Identifier) -> ParseResult<Vname> {
+        let position = id.position;
+        let mut vname = vname(VnameKind::Simple(id), position);
+        loop {
+            if self.accept(TokenKind::Dot) {
+                let field = self.identifier()?;
+                vname = self::vname(VnameKind::Field(Box::new(vname), field), position);
+            } else if self.accept(TokenKind::LBracket) {
+                let index = self.expression()?;
+                self.expect(TokenKind::RBracket)?;
+                vname = self::vname(VnameKind::Index(Box::new(vname), Box::new(index)), position);
+            } else {
+                return Ok(vname);
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> ParseResult<Vec<Declaration>> {
+        self.list(TokenKind::Semicolon, Self::single_declaration)
+    }
+
+    fn single_declaration(&mut self) -> ParseResult<Declaration> {
+        if !matches!(
+            self.peek(),
+            TokenKind::Const | TokenKind::Var | TokenKind::Proc | TokenKind::Func | TokenKind::Type
+        ) {
+            return Err(self.unexpected("declaration"));
+        }
+
+        match self.advance() {
+            TokenKind::Const => {
+                let id = self.identifier()?;
+                self.expect(TokenKind::Is)?;
+                Ok(Declaration::Const(id, self.expression()?))
+            }
+            TokenKind::Var => {
+                let id = self.identifier()?;
+                self.expect(TokenKind::Colon)?;
+                Ok(Declaration::Var(id, self.type_denoter()?))
+            }
+            TokenKind::Proc => {
+                let id = self.identifier()?;
+                let params = self.formal_params()?;
+                self.expect(TokenKind::Is)?;
+                Ok(Declaration::Proc(id, params, self.single_command()?))
+            }
+            TokenKind::Func => {
+                let id = self.identifier()?;
+                let params = self.formal_params()?;
+                self.expect(TokenKind::Colon)?;
+                let result = self.type_denoter()?;
+                self.expect(TokenKind::Is)?;
+                Ok(Declaration::Func(id, params, result, self.expression()?))
+            }
+            TokenKind::Type => {
+                let id = self.identifier()?;
+                self.expect(TokenKind::Is)?;
+                Ok(Declaration::Type(id, self.type_denoter()?))
+            }
+            _ => unreachable!("declaration keyword already checked"),
+        }
+    }
+
+    /// Parses a parenthesised, possibly empty, list of formal parameters.
+    fn formal_params(&mut self) -> ParseResult<Vec<FormalParam>> {
+        self.expect(TokenKind::LParen)?;
+        if self.accept(TokenKind::RParen) {
+            return Ok(Vec::new());
+        }
+
+        let params = self.list(TokenKind::Comma, Self::formal_param)?;
+        self.expect(TokenKind::RParen)?;
+        Ok(params)
+    }
+
+    fn formal_param(&mut self) -> ParseResult<FormalParam> {
+        match self.peek() {
+            TokenKind::Identifier(_) => {
+                let id = self.identifier()?;
+                self.expect(TokenKind::Colon)?;
+                Ok(FormalParam::Value(id, self.type_denoter()?))
+            }
+            TokenKind::Var => {
+                self.advance();
+                let id = self.identifier()?;
+                self.expect(TokenKind::Colon)?;
+                Ok(FormalParam::Var(id, self.type_denoter()?))
+            }
+            TokenKind::Proc => {
+                self.advance();
+                let id = self.identifier()?;
+                Ok(FormalParam::Proc(id, self.formal_params()?))
+            }
+            TokenKind::Func => {
+                self.advance();
+                let id = self.identifier()?;
+                let params = self.formal_params()?;
+                self.expect(TokenKind::Colon)?;
+                Ok(FormalParam::Func(id, params, self.type_denoter()?))
+            }
+            _ => Err(self.unexpected("formal parameter")),
+        }
+    }
+
+    /// Parses a possibly empty list of actual parameters, after the opening bracket.
+    fn actual_params(&mut self) -> ParseResult<Vec<ActualParam>> {
+        if self.accept(TokenKind::RParen) {
+            return Ok(Vec::new());
+        }
+
+        let params = self.list(TokenKind::Comma, Self::actual_param)?;
+        self.expect(TokenKind::RParen)?;
+        Ok(params)
+    }
+
+    fn actual_param(&mut self) -> ParseResult<ActualParam> {
+        if self.accept(TokenKind::Var) {
+            let id = self.identifier()?;
+            Ok(ActualParam::Var(self.rest_of_vname(id)?))
+        } else if self.accept(TokenKind::Proc) {
+            Ok(ActualParam::Proc(self.identifier()?))
+        } else if self.accept(TokenKind::Func) {
+            Ok(ActualParam::Func(self.identifier()?))
+        } else {
+            Ok(ActualParam::Value(self.expression()?))
+        }
+    }
+
+    fn type_denoter(&mut self) -> ParseResult<TypeDenoter> {
+        let position = self.position();
+        if self.accept(TokenKind::Array) {
+            let count = self.int_literal()?;
+            self.expect(TokenKind::Of)?;
+            let elem = self.type_denoter()?;
+            Ok(TypeDenoter::Array(position, count, Box::new(elem)))
+        } else if self.accept(TokenKind::Record) {
+            let fields = self.list(TokenKind::Comma, |p| {
+                let id = p.identifier()?;
+                p.expect(TokenKind::Colon)?;
+                Ok((id, p.type_denoter()?))
+            })?;
+            self.expect(TokenKind::End)?;
+            Ok(TypeDenoter::Record(fields))
+        } else {
+            Ok(TypeDenoter::Named(self.identifier()?))
+        }
+    }
+}
+
+fn expression(kind: ExpressionKind, position: Position) -> Expression {
+    Expression {
+        kind,
+        position,
+        ty: Type::Error,
+    }
+}
+
+fn vname(kind: VnameKind, position: Position) -> Vname {
+    Vname {
+        kind,
+        position,
+        ty: Type::Error,
+        variable: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::triangle::lexer::tokenize;
+    use rstest::*;
+
+    fn parse_source(source: &str) -> ParseResult<Command> {
+        parse(tokenize(source).unwrap())
+    }
+
+    #[rstest]
+    fn test_parse_binary_operators_associate_left() {
+        let program = parse_source("x := 1 + 2 * 3").unwrap();
+        let CommandKind::Assign(_, expr) = program.kind else {
+            panic!("expected assignment, got {program:?}");
+        };
+        let ExpressionKind::Binary(lhs, op, _) = expr.kind else {
+            panic!("expected binary expression, got {expr:?}");
+        };
+        assert_eq!("*", op.name);
+        assert!(matches!(lhs.kind, ExpressionKind::Binary(..)));
+    }
+
+    #[rstest]
+    fn test_parse_sequence_with_empty_commands() {
+        let program = parse_source("begin putint(1); ; puteol() end").unwrap();
+        let CommandKind::Sequence(commands) = program.kind else {
+            panic!("expected sequence, got {program:?}");
+        };
+        assert_eq!(3, commands.len());
+        assert!(matches!(commands[1].kind, CommandKind::Empty));
+    }
+
+    #[rstest]
+    fn test_parse_declarations() {
+        let program = parse_source(
+            "let
+                type Point ~ record x: Integer, y: Integer end;
+                var ps: array 3 of Point;
+                proc p(var a: Integer, func f(b: Integer): Boolean) ~ a := 0;
+                const c ~ 'c'
+            in ps[1].x := 2",
+        )
+        .unwrap();
+        let CommandKind::Let(decls, _) = program.kind else {
+            panic!("expected let command, got {program:?}");
+        };
+        assert_eq!(4, decls.len());
+        assert!(matches!(&decls[2], Declaration::Proc(_, params, _) if params.len() == 2));
+    }
+
+    #[rstest]
+    #[case("x := ")]
+    #[case("let var x: Integer x := 1")]
+    #[case("if b then x := 1")]
+    #[case("p(var 1)")]
+    #[case("begin x := 1")]
+    fn test_parse_syntax_error_err(#[case] source: &str) {
+        assert!(parse_source(source).is_err());
+    }
+}
@@ -0,0 +1,77 @@
+//! The standard environment that every Triangle program is compiled in.
+
+use super::ast::{ParamSig, Type};
+
+pub(super) enum StdDecl {
+    Type(Type),
+    Const(Type, i16),
+    /// A routine implemented by a TAM primitive, with its parameters, its result type
+    /// if it is a function, and the name of the primitive
+    Routine(Vec<ParamSig>, Option<Type>, &'static str),
+}
+
+/// Gets the identifiers of the standard environment, in declaration order.
+pub(super) fn declarations() -> Vec<(&'static str, StdDecl)> {
+    use ParamSig::{Value, Var};
+    use StdDecl::{Const, Routine};
+
+    vec![
+        ("Boolean", StdDecl::Type(Type::Boolean)),
+        ("Char", StdDecl::Type(Type::Char)),
+        ("Integer", StdDecl::Type(Type::Integer)),
+        ("false", Const(Type::Boolean, 0)),
+        ("true", Const(Type::Boolean, 1)),
+        ("maxint", Const(Type::Integer, i16::MAX)),
+        (
+            "chr",
+            Routine(vec![Value(Type::Integer)], Some(Type::Char), "id"),
+        ),
+        (
+            "ord",
+            Routine(vec![Value(Type::Char)], Some(Type::Integer), "id"),
+        ),
+        ("eol", Routine(vec![], Some(Type::Boolean), "eol")),
+        ("eof", Routine(vec![], Some(Type::Boolean), "eof")),
+        ("get", Routine(vec![Var(Type::Char)], None, "get")),
+        ("put", Routine(vec![Value(Type::Char)], None, "put")),
+        ("getint", Routine(vec![Var(Type::Integer)], None, "getint")),
+        (
+            "putint",
+            Routine(vec![Value(Type::Integer)], None, "putint"),
+        ),
+        ("geteol", Routine(vec![], None, "geteol")),
+        ("puteol", Routine(vec![], None, "puteol")),
+    ]
+}
+
+/// Gets the operand type and result type of a unary operator, with its primitive.
+pub(super) fn unary_operator(op: &str) -> Option<(Type, Type, &'static str)> {
+    match op {
+        "\\" => Some((Type::Boolean, Type::Boolean, "not")),
+        _ => None,
+    }
+}
+
+/// Gets the operand type and result type of a binary operator, with its primitive.
+///
+/// The operand type of `=` and `\=` is `None`, as they accept any two operands of
+/// the same type.
+pub(super) fn binary_operator(op: &str) -> Option<(Option<Type>, Type, &'static str)> {
+    let (operand, result, primitive) = match op {
+        "/\\" => (Some(Type::Boolean), Type::Boolean, "and"),
+        "\\/" => (Some(Type::Boolean), Type::Boolean, "or"),
+        "+" => (Some(Type::Integer), Type::Integer, "add"),
+        "-" => (Some(Type::Integer), Type::Integer, "sub"),
+        "*" => (Some(Type::Integer), Type::Integer, "mult"),
+        "/" => (Some(Type::Integer), Type::Integer, "div"),
+        "//" => (Some(Type::Integer), Type::Integer, "mod"),
+        "<" => (Some(Type::Integer), Type::Boolean, "lt"),
+        "<=" => (Some(Type::Integer), Type::Boolean, "le"),
+        ">=" => (Some(Type::Integer), Type::Boolean, "ge"),
+        ">" => (Some(Type::Integer), Type::Boolean, "gt"),
+        "=" => (None, Type::Boolean, "eq"),
+        "\\=" => (None, Type::Boolean, "ne"),
+        _ => return None,
+    };
+    Some((operand, result, primitive))
+}
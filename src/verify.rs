@@ -0,0 +1,246 @@
+//! Static checks on TAM bytecode, run before a program is executed.
+
+use crate::{
+    CALL, CB, CT, JUMP, JUMPIF, L6, LB, LOAD, LOADA, PB, PRIMITIVE_NAMES, SB, STORE, TamEmulator,
+    TamInstruction, analysis::cfg::falls_through,
+};
+use std::fmt::{self, Display};
+
+/// A problem found in a program by [`verify`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Problem {
+    /// Code address of the offending instruction
+    pub address: u16,
+    pub kind: ProblemKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProblemKind {
+    UnknownOpcode(u8),
+    /// The instruction's `r` field is not a register it can address through
+    InvalidRegister(u8),
+    /// A jump or call targets an address outside the code store
+    TargetOutOfRange(u16),
+    /// A call into the primitive segment does not name a primitive
+    InvalidPrimitive(i16),
+    /// A call's `n` field is not a register that can hold a static link
+    InvalidStaticLink(u8),
+    /// Execution can continue past the last instruction
+    FallsOffEnd,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}: ", self.address)?;
+        match self.kind {
+            ProblemKind::UnknownOpcode(op) => write!(f, "unknown opcode {op}"),
+            ProblemKind::InvalidRegister(r) => write!(f, "invalid register {r}"),
+            ProblemKind::TargetOutOfRange(addr) => {
+                write!(f, "target {addr:#06x} is outside the code store")
+            }
+            ProblemKind::InvalidPrimitive(d) => write!(f, "no primitive at offset {d}"),
+            ProblemKind::InvalidStaticLink(n) => write!(f, "invalid static link register {n}"),
+            ProblemKind::FallsOffEnd => write!(f, "execution can run past the end of the code"),
+        }
+    }
+}
+
+/// Checks every instruction of a program, which occupies the whole of `code`.
+///
+/// All problems found are reported, in address order.
+///
+/// # Example
+///
+/// ```
+/// use tam_rs::verify::{verify, Problem, ProblemKind};
+///
+/// // LOADL 1; JUMP 5[CB]
+/// let problems = verify(&[0x30000001, 0xc0000005]).unwrap_err();
+/// assert_eq!(
+///     vec![Problem { address: 1, kind: ProblemKind::TargetOutOfRange(5) }],
+///     problems
+/// );
+/// ```
+pub fn verify(code: &[u32]) -> Result<(), Vec<Problem>> {
+    let mut problems = Vec::new();
+    let code_top = code.len();
+
+    for (address, &word) in code.iter().enumerate() {
+        let address = address as u16;
+        let instr = TamInstruction::from(word);
+        let mut report = |kind| problems.push(Problem { address, kind });
+
+        match instr.op {
+            LOAD | STORE if !is_data_register(instr.r) => {
+                report(ProblemKind::InvalidRegister(instr.r));
+            }
+            LOADA if !is_data_register(instr.r) && !is_code_register(instr.r) => {
+                report(ProblemKind::InvalidRegister(instr.r));
+            }
+            CALL | JUMP | JUMPIF => {
+                if instr.op == CALL && !is_frame_register(instr.n) {
+                    report(ProblemKind::InvalidStaticLink(instr.n));
+                }
+
+                match instr.r as usize {
+                    CB if instr.d as u16 as usize >= code_top => {
+                        report(ProblemKind::TargetOutOfRange(instr.d as u16));
+                    }
+                    CB => {}
+                    PB if instr.op == CALL => {
+                        if instr.d <= 0 || instr.d as usize >= PRIMITIVE_NAMES.len() {
+                            report(ProblemKind::InvalidPrimitive(instr.d));
+                        }
+                    }
+                    _ => report(ProblemKind::InvalidRegister(instr.r)),
+                }
+            }
+            9 | 16.. => report(ProblemKind::UnknownOpcode(instr.op)),
+            _ => {}
+        }
+    }
+
+    // the block ending the code must not have a successor past it
+    if code
+        .last()
+        .is_none_or(|&word| falls_through(&TamInstruction::from(word)))
+    {
+        problems.push(Problem {
+            address: code_top.saturating_sub(1) as u16,
+            kind: ProblemKind::FallsOffEnd,
+        });
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+/// Registers that hold the base of a data segment or frame.
+fn is_data_register(r: u8) -> bool {
+    (SB as u8..=L6 as u8).contains(&r)
+}
+
+/// Registers that hold the base of the code or primitive segments.
+fn is_code_register(r: u8) -> bool {
+    r == CB as u8 || r == PB as u8
+}
+
+/// Registers that can hold a static link.
+fn is_frame_register(r: u8) -> bool {
+    r == SB as u8 || (LB as u8..=L6 as u8).contains(&r)
+}
+
+impl TamEmulator {
+    /// Verifies the program loaded into the code store.
+    pub fn verify(&self) -> Result<(), Vec<Problem>> {
+        verify(&self.code_store[..self.registers[CT] as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ST, asm, tam};
+    use rstest::*;
+
+    #[rstest]
+    fn test_verify_valid_program_ok() {
+        let code = tam! {
+            LOADL 3;
+            CALL(SB) putint[PB];
+            JUMPIF(0) 4[CB];
+            CALL(LB) 0[CB];
+            LOADA 8[PB];
+            HALT;
+        };
+        assert_eq!(Ok(()), verify(&code));
+    }
+
+    #[rstest]
+    #[case(vec![asm::encode(9, 0, 0, 0)], ProblemKind::UnknownOpcode(9))]
+    #[case(tam! { LOAD(1) 0[CB] }, ProblemKind::InvalidRegister(CB as u8))]
+    #[case(tam! { STORE(1) 0[CP] }, ProblemKind::InvalidRegister(15))]
+    #[case(tam! { JUMP 1[PB] }, ProblemKind::InvalidRegister(PB as u8))]
+    #[case(tam! { JUMP 2[CB] }, ProblemKind::TargetOutOfRange(2))]
+    #[case(tam! { JUMPIF(0) -1[CB] }, ProblemKind::TargetOutOfRange(0xffff))]
+    #[case(tam! { CALL(SB) 29[PB] }, ProblemKind::InvalidPrimitive(29))]
+    #[case(tam! { CALL(ST) 0[CB] }, ProblemKind::InvalidStaticLink(ST as u8))]
+    fn test_verify_bad_instruction_reported(#[case] instr: Vec<u32>, #[case] kind: ProblemKind) {
+        let code = [instr, tam! { HALT }].concat();
+        assert_eq!(Err(vec![Problem { address: 0, kind }]), verify(&code));
+    }
+
+    #[rstest]
+    fn test_verify_reports_all_problems() {
+        let mut code = vec![asm::encode(9, 0, 0, 0)];
+        code.extend(tam! {
+            HALT;
+            JUMP 30[CB];
+            LOADL 1;
+        });
+        let problems = verify(&code).unwrap_err();
+        assert_eq!(
+            vec![
+                Problem {
+                    address: 0,
+                    kind: ProblemKind::UnknownOpcode(9)
+                },
+                Problem {
+                    address: 2,
+                    kind: ProblemKind::TargetOutOfRange(30)
+                },
+                Problem {
+                    address: 3,
+                    kind: ProblemKind::FallsOffEnd
+                },
+            ],
+            problems
+        );
+    }
+
+    #[rstest]
+    // the condition can fail on the last instruction
+    #[case(tam! { LOADL 0; JUMPIF(0) 0[CB] })]
+    // the routine returns to just past the end
+    #[case(tam! { JUMP 2[CB]; RETURN(0) 0; CALL(SB) 1[CB] })]
+    #[case(tam! { LOADL 1; CALL(SB) putint[PB] })]
+    #[case(tam! { HALT; LOADL 1 })]
+    fn test_verify_falls_off_end(#[case] code: Vec<u32>) {
+        assert_eq!(
+            Err(vec![Problem {
+                address: code.len() as u16 - 1,
+                kind: ProblemKind::FallsOffEnd
+            }]),
+            verify(&code)
+        );
+    }
+
+    #[rstest]
+    #[case(tam! { LOADL 1; JUMP 0[CB] })]
+    #[case(tam! { CALL(SB) 2[CB]; HALT; RETURN(0) 0 })]
+    #[case(tam! { LOADA 2[CB]; JUMPI; HALT })]
+    fn test_verify_ends_without_falling_off(#[case] code: Vec<u32>) {
+        assert_eq!(Ok(()), verify(&code));
+    }
+
+    #[rstest]
+    fn test_verify_empty_program_falls_off_end() {
+        assert_eq!(
+            Err(vec![Problem {
+                address: 0,
+                kind: ProblemKind::FallsOffEnd
+            }]),
+            verify(&[])
+        );
+    }
+
+    #[rstest]
+    fn test_emulator_verify_checks_loaded_program() {
+        let mut emu = TamEmulator::new(false);
+        emu.set_program(&[0xf0, 0, 0, 0]).unwrap();
+        assert_eq!(Ok(()), emu.verify());
+    }
+}
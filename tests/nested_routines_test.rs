@@ -0,0 +1,35 @@
+use tam_rs::{io::MemoryIo, triangle};
+
+mod common;
+use common::cpu_cycle;
+
+#[test]
+fn nested_routines_test() {
+    // innermost reaches x two levels out through L2, and the recursive calls to count
+    // pass outer's frame as the static link rather than the caller's
+    let source = "
+        let
+          var g: Integer;
+          proc outer(n: Integer) ~
+            let
+              var x: Integer;
+              proc count(k: Integer) ~
+                if k > 0 then begin x := x + 1; g := g + n; count(k - 1) end else ;
+              proc inner(m: Integer) ~
+                let
+                  proc innermost() ~ begin x := x + m; count(2) end
+                in innermost()
+            in begin x := n; inner(10); count(3); putint(x); puteol() end
+        in begin g := 0; outer(1); outer(2); putint(g) end";
+    let compiled = triangle::compile(source, "nested.tri").expect("program compiles");
+
+    let io = MemoryIo::new("");
+    let mut emulator = tam_rs::TamEmulator::new(false);
+    emulator.set_io(io.clone());
+    emulator
+        .set_program(&compiled.to_bytes())
+        .expect("failed to set program");
+    while cpu_cycle(&mut emulator).expect("CPU cycle failed") {}
+
+    assert_eq!("16\n17\n15", io.output_string());
+}
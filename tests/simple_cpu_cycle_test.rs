@@ -1,4 +1,4 @@
-use tam_rs::{CP, ST};
+use tam_rs::{CP, ST, asm, tam};
 
 mod common;
 use common::cpu_cycle;
@@ -7,7 +7,7 @@ use common::cpu_cycle;
 fn simple_cpu_cycle_test() {
     let mut emulator = tam_rs::TamEmulator::new(false);
     emulator
-        .set_program(&vec![0x30, 0x00, 0x12, 0x34])
+        .set_program(&asm::to_bytes(&tam! { LOADL 0x1234 }))
         .expect("failed to set program");
 
     let running = cpu_cycle(&mut emulator).expect("CPU cycle failed");
@@ -0,0 +1,94 @@
+use tam_rs::{io::MemoryIo, triangle};
+
+mod common;
+use common::cpu_cycle;
+
+fn run(source: &str) -> String {
+    let compiled = triangle::compile(source, "test.tri").expect("program compiles");
+
+    let io = MemoryIo::new("");
+    let mut emulator = tam_rs::TamEmulator::new(false);
+    emulator.set_io(io.clone());
+    emulator
+        .set_program(&compiled.to_bytes())
+        .expect("failed to set program");
+    while cpu_cycle(&mut emulator).expect("CPU cycle failed") {}
+
+    io.output_string()
+}
+
+#[test]
+fn record_test() {
+    // a record aggregate assigned whole, a field updated in place, and a record
+    // passed by value to a function that reads its fields
+    let source = "
+        let
+          type Point ~ record x: Integer, y: Integer end;
+          var p: Point;
+          func sum(q: Point): Integer ~ q.x + q.y
+        in begin
+          p := {x ~ 3, y ~ 4};
+          p.y := p.y * 10;
+          putint(p.x); puteol();
+          putint(p.y); puteol();
+          putint(sum(p))
+        end";
+
+    assert_eq!("3\n40\n43", run(source));
+}
+
+#[test]
+fn array_test() {
+    // an array aggregate indexed by a variable, elements updated through a loop, and
+    // an array of records
+    let source = "
+        let
+          type Point ~ record x: Integer, y: Integer end;
+          var a: array 4 of Integer;
+          var ps: array 2 of Point;
+          var i: Integer
+        in begin
+          a := [5, 6, 7, 8];
+          i := 0;
+          while i < 4 do begin a[i] := a[i] * a[i]; i := i + 1 end;
+          i := 0;
+          while i < 4 do begin putint(a[i]); puteol(); i := i + 1 end;
+          ps := [{x ~ 1, y ~ 2}, {x ~ 3, y ~ 4}];
+          ps[1].x := ps[0].y + ps[1].y;
+          putint(ps[1].x)
+        end";
+
+    assert_eq!("25\n36\n49\n64\n6", run(source));
+}
+
+#[test]
+fn func_param_test() {
+    // twice calls its parameter through CALLI, and offset reads k from the frame
+    // its closure was taken in
+    let source = "
+        let
+          func twice(func f(n: Integer): Integer, n: Integer): Integer ~ f(f(n));
+          func square(n: Integer): Integer ~ n * n;
+          proc show(k: Integer) ~
+            let
+              func offset(n: Integer): Integer ~ n + k
+            in begin putint(twice(func offset, 1)); puteol() end
+        in begin
+          putint(twice(func square, 3)); puteol();
+          show(10)
+        end";
+
+    assert_eq!("81\n21\n", run(source));
+}
+
+#[test]
+fn primitive_proc_param_test() {
+    // ap passes its parameter on, so the primitive is called through two closures
+    let source = "
+        let
+          proc ap(proc p(c: Char)) ~ begin p('o'); p('k') end;
+          proc ap2(proc p(c: Char)) ~ ap(proc p)
+        in begin ap(proc put); ap2(proc put) end";
+
+    assert_eq!("okok", run(source));
+}